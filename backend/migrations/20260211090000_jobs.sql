@@ -0,0 +1,19 @@
+create table if not exists jobs (
+    id uuid primary key,
+    kind text not null,
+    payload jsonb not null default '{}'::jsonb,
+    status text not null check (status in ('queued','running','succeeded','failed','cancelled')),
+    attempts integer not null default 0,
+    max_attempts integer not null default 5,
+    run_at timestamptz not null default now(),
+    last_error text,
+    locked_by text,
+    locked_at timestamptz,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now(),
+    finished_at timestamptz
+);
+
+create index if not exists idx_jobs_queued_run_at on jobs(run_at) where status = 'queued';
+create index if not exists idx_jobs_status on jobs(status);
+create index if not exists idx_jobs_kind on jobs(kind);
@@ -0,0 +1,11 @@
+alter table download_requests drop constraint if exists download_requests_status_check;
+alter table download_requests add constraint download_requests_status_check
+    check (status in ('pending','approved','rejected','expired','cancelled'));
+
+alter table download_requests add column if not exists expired_at timestamptz;
+alter table download_requests add column if not exists cancelled_at timestamptz;
+
+create index if not exists idx_download_requests_approved_expires_at on download_requests(expires_at) where status = 'approved';
+
+alter table jobs add column if not exists dedupe_key text;
+create unique index if not exists idx_jobs_dedupe_key_active on jobs(dedupe_key) where status in ('queued','running');
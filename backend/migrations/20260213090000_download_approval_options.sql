@@ -0,0 +1,5 @@
+alter table download_requests add column if not exists max_downloads integer;
+alter table download_requests add column if not exists downloads_remaining integer;
+alter table download_requests add column if not exists download_count integer not null default 0;
+alter table download_requests add column if not exists approver_note text not null default '';
+alter table download_requests add column if not exists reject_reason text not null default '';
@@ -0,0 +1,59 @@
+create table if not exists groups (
+    id uuid primary key,
+    name text not null,
+    created_at timestamptz not null default now()
+);
+
+create unique index if not exists idx_groups_name_unique on groups(lower(name));
+
+create table if not exists group_members (
+    group_id uuid not null references groups(id) on delete cascade,
+    user_id uuid not null references users(id) on delete cascade,
+    primary key (group_id, user_id)
+);
+
+create index if not exists idx_group_members_user_id on group_members(user_id);
+
+alter table documents add column if not exists folder text not null default '';
+create index if not exists idx_documents_folder on documents(folder);
+
+create table if not exists approval_policies (
+    id uuid primary key,
+    name text not null,
+    created_by uuid references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create table if not exists approval_policy_stages (
+    policy_id uuid not null references approval_policies(id) on delete cascade,
+    stage_index integer not null,
+    approver_kind text not null check (approver_kind in ('owner','admins','group','users')),
+    group_id uuid references groups(id) on delete restrict,
+    user_ids uuid[] not null default '{}',
+    required_approvals integer not null default 1 check (required_approvals >= 1),
+    primary key (policy_id, stage_index)
+);
+
+alter table documents add column if not exists approval_policy_id uuid references approval_policies(id) on delete set null;
+
+create table if not exists folder_approval_policies (
+    folder text primary key,
+    policy_id uuid not null references approval_policies(id) on delete cascade
+);
+
+alter table download_requests add column if not exists approval_policy_id uuid references approval_policies(id) on delete set null;
+alter table download_requests add column if not exists approval_stage integer not null default 0;
+
+create table if not exists download_request_decisions (
+    id uuid primary key,
+    request_id uuid not null references download_requests(id) on delete cascade,
+    stage_index integer not null,
+    approver_id uuid references users(id) on delete set null,
+    decision text not null check (decision in ('approved','rejected')),
+    note text not null default '',
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_download_request_decisions_request_id on download_request_decisions(request_id);
+create unique index if not exists idx_download_request_decisions_unique on download_request_decisions(request_id, stage_index, approver_id);
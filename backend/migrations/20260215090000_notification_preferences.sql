@@ -0,0 +1,6 @@
+create table if not exists notification_preferences (
+    user_id uuid not null references users(id) on delete cascade,
+    event_type text not null,
+    email_enabled boolean not null default true,
+    primary key (user_id, event_type)
+);
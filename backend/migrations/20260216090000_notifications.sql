@@ -0,0 +1,16 @@
+create table if not exists notifications (
+    id uuid primary key,
+    user_id uuid not null references users(id) on delete cascade,
+    event_type text not null,
+    title text not null,
+    body text not null default '',
+    document_id uuid references documents(id) on delete set null,
+    download_request_id uuid references download_requests(id) on delete set null,
+    read_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_notifications_user_created on notifications(user_id, created_at desc);
+create index if not exists idx_notifications_user_unread on notifications(user_id) where read_at is null;
+
+alter table download_requests add column if not exists expiry_warned_at timestamptz;
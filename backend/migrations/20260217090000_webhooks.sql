@@ -0,0 +1,28 @@
+create table if not exists webhooks (
+    id uuid primary key,
+    name text not null,
+    url text not null,
+    secret text not null,
+    event_types text[] not null default '{}',
+    enabled boolean not null default true,
+    created_by uuid references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create table if not exists webhook_deliveries (
+    id uuid primary key,
+    webhook_id uuid not null references webhooks(id) on delete cascade,
+    event_type text not null,
+    payload jsonb not null,
+    status text not null default 'pending' check (status in ('pending','succeeded','failed')),
+    attempts integer not null default 0,
+    response_status integer,
+    response_body text,
+    last_error text,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now(),
+    delivered_at timestamptz
+);
+
+create index if not exists idx_webhook_deliveries_webhook_created on webhook_deliveries(webhook_id, created_at desc);
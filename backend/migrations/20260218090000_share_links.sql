@@ -0,0 +1,26 @@
+create table if not exists share_links (
+    id uuid primary key,
+    document_id uuid not null references documents(id) on delete cascade,
+    created_by uuid references users(id) on delete set null,
+    expires_at timestamptz not null,
+    password_hash text,
+    max_downloads integer check (max_downloads is null or max_downloads >= 1),
+    download_count integer not null default 0,
+    view_only boolean not null default false,
+    revoked_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_share_links_document_id on share_links(document_id);
+
+create table if not exists share_link_accesses (
+    id uuid primary key,
+    share_link_id uuid not null references share_links(id) on delete cascade,
+    action text not null,
+    outcome text not null,
+    ip text,
+    user_agent text,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_share_link_accesses_link_created on share_link_accesses(share_link_id, created_at desc);
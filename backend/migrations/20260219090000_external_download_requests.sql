@@ -0,0 +1,29 @@
+alter table documents add column if not exists requestable boolean not null default false;
+
+alter table download_requests alter column requester_id drop not null;
+alter table download_requests add column if not exists external_email text;
+alter table download_requests drop constraint if exists download_requests_requester_check;
+alter table download_requests add constraint download_requests_requester_check
+    check (requester_id is not null or external_email is not null);
+
+create unique index if not exists idx_download_requests_external_active_unique
+    on download_requests(document_id, lower(external_email)) where status = 'pending' and requester_id is null;
+
+alter table share_links add column if not exists download_request_id uuid references download_requests(id) on delete cascade;
+
+create table if not exists email_verifications (
+    id uuid primary key,
+    document_id uuid not null references documents(id) on delete cascade,
+    email text not null,
+    code_hash text not null,
+    applicant_name text not null,
+    applicant_company text not null,
+    message text not null default '',
+    attempts integer not null default 0,
+    expires_at timestamptz not null,
+    verified_at timestamptz,
+    download_request_id uuid references download_requests(id) on delete set null,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_email_verifications_email_created on email_verifications(lower(email), created_at desc);
@@ -0,0 +1 @@
+alter table documents add column if not exists watermark boolean not null default false;
@@ -0,0 +1,4 @@
+alter table documents add column if not exists encryption_key_id text;
+alter table documents add column if not exists wrapped_data_key bytea;
+
+create index if not exists idx_documents_encryption_key_id on documents(encryption_key_id);
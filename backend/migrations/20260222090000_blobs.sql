@@ -0,0 +1,15 @@
+create table if not exists blobs (
+    sha256 text primary key,
+    storage_rel_path text not null unique,
+    size bigint not null,
+    ref_count integer not null default 0,
+    encryption_key_id text,
+    wrapped_data_key bytea,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_blobs_encryption_key_id on blobs(encryption_key_id);
+
+alter table documents add column if not exists sha256 text references blobs(sha256);
+
+create index if not exists idx_documents_sha256 on documents(sha256);
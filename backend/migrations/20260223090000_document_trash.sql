@@ -0,0 +1,4 @@
+alter table documents add column if not exists deleted_at timestamptz;
+alter table documents add column if not exists deleted_by uuid references users(id) on delete set null;
+
+create index if not exists idx_documents_deleted_at on documents(deleted_at) where deleted_at is not null;
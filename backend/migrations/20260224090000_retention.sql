@@ -0,0 +1,35 @@
+create table if not exists retention_policies (
+    id uuid primary key,
+    name text not null,
+    min_retention_days integer check (min_retention_days >= 0),
+    max_retention_days integer check (max_retention_days >= 1),
+    created_by uuid references users(id) on delete set null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now(),
+    check (min_retention_days is null or max_retention_days is null or min_retention_days <= max_retention_days)
+);
+
+create unique index if not exists idx_retention_policies_name_unique on retention_policies(lower(name));
+
+create table if not exists folder_retention_policies (
+    folder text primary key,
+    policy_id uuid not null references retention_policies(id) on delete cascade
+);
+
+alter table documents add column if not exists retention_policy_id uuid references retention_policies(id) on delete set null;
+alter table documents add column if not exists legal_hold boolean not null default false;
+alter table documents add column if not exists legal_hold_reason text not null default '';
+alter table documents add column if not exists legal_hold_by uuid references users(id) on delete set null;
+alter table documents add column if not exists legal_hold_at timestamptz;
+
+create table if not exists retention_audit_log (
+    id uuid primary key,
+    document_id uuid,
+    actor_id uuid references users(id) on delete set null,
+    action text not null,
+    details jsonb not null default '{}'::jsonb,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists idx_retention_audit_log_document_id on retention_audit_log(document_id, created_at desc);
+create index if not exists idx_retention_audit_log_created_at on retention_audit_log(created_at desc);
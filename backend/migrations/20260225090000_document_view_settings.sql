@@ -0,0 +1 @@
+alter table documents add column if not exists view_requires_approval boolean not null default false;
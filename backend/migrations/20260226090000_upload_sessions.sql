@@ -0,0 +1,24 @@
+create table if not exists upload_sessions (
+    id uuid primary key,
+    owner_id uuid not null references users(id) on delete cascade,
+    file_name text not null,
+    mime_type text not null,
+    folder text not null default '',
+    notes text not null default '',
+    permission text not null default 'public',
+    allowed_users uuid[] not null default '{}',
+    is_generated boolean not null default false,
+    requestable boolean not null default false,
+    watermark boolean not null default false,
+    view_requires_approval boolean not null default false,
+    upload_length bigint not null check (upload_length >= 0),
+    upload_offset bigint not null default 0 check (upload_offset >= 0 and upload_offset <= upload_length),
+    sha256 text,
+    encryption_key_id text,
+    wrapped_data_key bytea,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz not null
+);
+
+create index if not exists idx_upload_sessions_owner on upload_sessions(owner_id);
+create index if not exists idx_upload_sessions_expires_at on upload_sessions(expires_at);
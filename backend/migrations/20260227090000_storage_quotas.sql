@@ -0,0 +1,12 @@
+alter table users add column if not exists storage_quota_bytes bigint check (storage_quota_bytes >= 0);
+alter table groups add column if not exists storage_quota_bytes bigint check (storage_quota_bytes >= 0);
+
+create table if not exists storage_usage (
+    user_id uuid primary key references users(id) on delete cascade,
+    used_bytes bigint not null default 0 check (used_bytes >= 0),
+    updated_at timestamptz not null default now()
+);
+
+insert into storage_usage (user_id, used_bytes)
+select owner_id, sum(size) from documents group by owner_id
+on conflict (user_id) do update set used_bytes = excluded.used_bytes, updated_at = now();
@@ -0,0 +1,6 @@
+alter table documents add column if not exists scan_status text not null default 'unscanned'
+    check (scan_status in ('unscanned', 'quarantined', 'clean', 'infected'));
+alter table documents add column if not exists scan_signature text;
+alter table documents add column if not exists scanned_at timestamptz;
+
+create index if not exists idx_documents_scan_status on documents(scan_status) where scan_status in ('quarantined', 'infected');
@@ -0,0 +1,36 @@
+create table if not exists tags (
+    id uuid primary key,
+    name text not null,
+    created_at timestamptz not null default now()
+);
+
+create unique index if not exists idx_tags_name_unique on tags(lower(name));
+
+create table if not exists document_tags (
+    document_id uuid not null references documents(id) on delete cascade,
+    tag_id uuid not null references tags(id) on delete cascade,
+    primary key (document_id, tag_id)
+);
+
+create index if not exists idx_document_tags_tag_id on document_tags(tag_id);
+
+create table if not exists metadata_fields (
+    id uuid primary key,
+    key text not null unique,
+    label text not null,
+    field_type text not null check (field_type in ('text', 'number', 'date', 'enum')),
+    options text[] not null default '{}',
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now()
+);
+
+create table if not exists document_metadata (
+    document_id uuid not null references documents(id) on delete cascade,
+    field_id uuid not null references metadata_fields(id) on delete cascade,
+    value_text text,
+    value_number double precision,
+    value_date date,
+    primary key (document_id, field_id)
+);
+
+create index if not exists idx_document_metadata_field on document_metadata(field_id);
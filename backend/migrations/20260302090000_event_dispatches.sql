@@ -0,0 +1,4 @@
+create table if not exists event_dispatches (
+    job_id uuid primary key references jobs(id) on delete cascade,
+    dispatched_at timestamptz not null default now()
+);
@@ -0,0 +1,3 @@
+-- Documents that predate the view setting were only reachable through an approved download request unless
+-- preauthorized; keep them gated for inline viewing too.
+update documents set view_requires_approval = true where not download_preauthorized and not view_requires_approval;
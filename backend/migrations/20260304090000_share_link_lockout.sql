@@ -0,0 +1,3 @@
+create index if not exists idx_share_link_accesses_failed_ip
+    on share_link_accesses(ip, created_at desc)
+    where outcome = 'invalid_password';
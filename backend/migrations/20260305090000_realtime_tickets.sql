@@ -0,0 +1,12 @@
+create table if not exists ws_tickets (
+    ticket_hash text primary key,
+    user_id uuid not null references users(id) on delete cascade,
+    expires_at timestamptz not null
+);
+
+-- Push messages too large for a NOTIFY payload are parked here and announced by id.
+create table if not exists realtime_messages (
+    id uuid primary key,
+    payload jsonb not null,
+    created_at timestamptz not null default now()
+);
@@ -0,0 +1,5 @@
+alter table jobs add column if not exists locked_until timestamptz;
+
+update jobs set locked_until = locked_at + interval '15 minutes' where status = 'running' and locked_until is null;
+
+create index if not exists idx_jobs_running_locked_until on jobs(locked_until) where status = 'running';
@@ -0,0 +1,8 @@
+alter table email_verifications add column if not exists ip text;
+
+create index if not exists idx_email_verifications_ip_created on email_verifications(ip, created_at desc);
+
+-- Download links are now rendered when the email is sent; drop the ones already stored in finished jobs.
+update jobs
+set payload = jsonb_set(payload, '{body}', to_jsonb(regexp_replace(payload->>'body', '\n\nDownload link: \S+', '')))
+where kind = 'email.send' and status <> 'queued' and payload->>'body' like '%Download link: %';
@@ -0,0 +1,4 @@
+-- Re-sniff documents stored before uploads were content-inspected.
+insert into jobs (id, kind, payload, status, max_attempts, run_at, dedupe_key)
+values (gen_random_uuid(), 'documents.backfill_mime_types', '{}'::jsonb, 'queued', 5, now(), 'documents.backfill_mime_types')
+on conflict (dedupe_key) where status in ('queued','running') do nothing;
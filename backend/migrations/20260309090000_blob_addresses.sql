@@ -0,0 +1,4 @@
+-- Move blobs stored under their plaintext SHA-256 to keyed addresses.
+insert into jobs (id, kind, payload, status, max_attempts, run_at, dedupe_key)
+values (gen_random_uuid(), 'storage.readdress_blobs', '{}'::jsonb, 'queued', 5, now(), 'storage.readdress_blobs')
+on conflict (dedupe_key) where status in ('queued','running') do nothing;
@@ -0,0 +1,7 @@
+alter table documents drop constraint if exists documents_scan_status_check;
+alter table documents add constraint documents_scan_status_check
+    check (scan_status in ('unscanned', 'quarantined', 'clean', 'infected', 'too_large'));
+
+drop index if exists idx_documents_scan_status;
+create index if not exists idx_documents_scan_status on documents(scan_status)
+    where scan_status in ('quarantined', 'infected', 'too_large');
@@ -0,0 +1,835 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Path as AxumPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgExecutor};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    events::{self, Event},
+    is_admin, normalize_folder, share_links, AppState, AuthedUser,
+};
+
+// Matches pending requests the user ($1) may act on at their current stage; never their own.
+pub const APPROVER_PENDING_FILTER: &str = r#"
+    (
+        r.requester_id is distinct from $1
+    ) and (
+        (r.approval_policy_id is null and d.owner_id = $1)
+        or exists (
+            select 1
+            from approval_policy_stages s
+            where s.policy_id = r.approval_policy_id
+              and s.stage_index = r.approval_stage
+              and (
+                  (s.approver_kind = 'owner' and d.owner_id = $1)
+                  or (s.approver_kind = 'admins' and exists (select 1 from users au where au.id = $1 and au.role = 'admin'))
+                  or (s.approver_kind = 'users' and $1 = any(s.user_ids))
+                  or (s.approver_kind = 'group' and exists (
+                      select 1 from group_members gm where gm.group_id = s.group_id and gm.user_id = $1
+                  ))
+              )
+              and not exists (
+                  select 1
+                  from download_request_decisions x
+                  where x.request_id = r.id and x.stage_index = r.approval_stage and x.approver_id = $1
+              )
+        )
+    )
+"#;
+
+// The users who may act on request `r` (of document `d`) at its current stage.
+pub const CURRENT_STAGE_APPROVERS: &str = r#"
+    case when r.approval_policy_id is null then array[d.owner_id] else coalesce((
+        select array_agg(distinct a.user_id)
+        from approval_policy_stages s
+        cross join lateral (
+            select d.owner_id as user_id where s.approver_kind = 'owner'
+            union
+            select au.id from users au where s.approver_kind = 'admins' and au.role = 'admin' and au.status = 'active'
+            union
+            select unnest(s.user_ids) where s.approver_kind = 'users'
+            union
+            select gm.user_id from group_members gm where s.approver_kind = 'group' and gm.group_id = s.group_id
+        ) a
+        where s.policy_id = r.approval_policy_id and s.stage_index = r.approval_stage
+    ), '{}') end
+"#;
+
+#[derive(Debug, sqlx::FromRow)]
+struct StageRow {
+    policy_id: Uuid,
+    approver_kind: String,
+    group_id: Option<Uuid>,
+    user_ids: Vec<Uuid>,
+    required_approvals: i32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StageDto {
+    approver: String,
+    group_id: Option<Uuid>,
+    user_ids: Vec<Uuid>,
+    required_approvals: i32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApprovalPolicyDto {
+    id: Uuid,
+    name: String,
+    stages: Vec<StageDto>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StageInput {
+    approver: String,
+    group_id: Option<Uuid>,
+    user_ids: Option<Vec<Uuid>>,
+    required_approvals: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApprovalPolicyRequest {
+    name: String,
+    stages: Vec<StageInput>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssignPolicyRequest {
+    policy_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssignFolderPolicyRequest {
+    folder: String,
+    policy_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderPolicyDto {
+    folder: String,
+    policy_id: Uuid,
+    policy_name: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct DecisionDto {
+    id: Uuid,
+    stage_index: i32,
+    approver_id: Option<Uuid>,
+    approver_name: Option<String>,
+    decision: String,
+    note: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct ApprovalGrant {
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<i32>,
+    pub note: String,
+}
+
+#[derive(Debug)]
+pub enum Decision {
+    Approve(ApprovalGrant),
+    Reject { reason: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecisionOutcome {
+    pub status: &'static str,
+    pub approval_stage: i32,
+}
+
+#[derive(Debug)]
+pub enum DecisionError {
+    NotFound,
+    NotApprover,
+    OwnRequest,
+    AlreadyDecided,
+    FinalStageOnly,
+    Db(sqlx::Error),
+}
+
+impl From<sqlx::Error> for DecisionError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Db(e)
+    }
+}
+
+impl DecisionError {
+    pub fn status_and_message(&self) -> (StatusCode, &'static str) {
+        match self {
+            Self::NotFound => (StatusCode::NOT_FOUND, "not found"),
+            Self::NotApprover => (StatusCode::FORBIDDEN, "not an approver for current stage"),
+            Self::OwnRequest => (StatusCode::FORBIDDEN, "cannot decide own request"),
+            Self::AlreadyDecided => (StatusCode::CONFLICT, "already decided at this stage"),
+            Self::FinalStageOnly => (StatusCode::BAD_REQUEST, "ttl_hours and max_downloads apply only at the final stage"),
+            Self::Db(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error"),
+        }
+    }
+}
+
+impl IntoResponse for DecisionError {
+    fn into_response(self) -> Response {
+        if let Self::Db(e) = &self {
+            error!(?e, "download request decision failed");
+        }
+        self.status_and_message().into_response()
+    }
+}
+
+struct CurrentStage {
+    approver_kind: String,
+    group_id: Option<Uuid>,
+    user_ids: Vec<Uuid>,
+    required_approvals: i32,
+    is_last: bool,
+}
+
+pub async fn decide(
+    conn: &mut PgConnection,
+    authed: &AuthedUser,
+    id: Uuid,
+    decision: &Decision,
+) -> Result<DecisionOutcome, DecisionError> {
+    let row = sqlx::query_as::<_, (String, Option<Uuid>, i32, Uuid, Option<Uuid>)>(
+        r#"
+        select r.status, r.approval_policy_id, r.approval_stage, d.owner_id, r.requester_id
+        from download_requests r
+        join documents d on d.id = r.document_id
+        where r.id = $1 and d.deleted_at is null
+        for update of r
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some((status, policy_id, stage_index, owner_id, requester_id)) = row else {
+        return Err(DecisionError::NotFound);
+    };
+    if status != "pending" {
+        return Err(DecisionError::NotFound);
+    }
+    if requester_id == Some(authed.id) {
+        return Err(DecisionError::OwnRequest);
+    }
+
+    let stage = match policy_id {
+        Some(pid) => load_stage(conn, pid, stage_index).await?,
+        None => None,
+    };
+
+    let eligible = match &stage {
+        None => is_admin(authed) || owner_id == authed.id,
+        Some(s) => match s.approver_kind.as_str() {
+            "owner" => owner_id == authed.id,
+            "admins" => is_admin(authed),
+            "users" => s.user_ids.contains(&authed.id),
+            "group" => {
+                sqlx::query_scalar::<_, bool>(
+                    "select exists(select 1 from group_members where group_id = $1 and user_id = $2)",
+                )
+                .bind(s.group_id)
+                .bind(authed.id)
+                .fetch_one(&mut *conn)
+                .await?
+            }
+            _ => false,
+        },
+    };
+    if !eligible {
+        return Err(if stage.is_some() { DecisionError::NotApprover } else { DecisionError::NotFound });
+    }
+    // Intermediate approvers only pass the request on; their note is kept with their decision.
+    if let (Decision::Approve(g), Some(s)) = (decision, &stage) {
+        if !s.is_last && (g.expires_at.is_some() || g.max_downloads.is_some()) {
+            return Err(DecisionError::FinalStageOnly);
+        }
+    }
+
+    let (decision_str, note) = match decision {
+        Decision::Approve(g) => ("approved", g.note.as_str()),
+        Decision::Reject { reason } => ("rejected", reason.as_str()),
+    };
+
+    let recorded = sqlx::query(
+        r#"
+        insert into download_request_decisions (id, request_id, stage_index, approver_id, decision, note)
+        values ($1,$2,$3,$4,$5,$6)
+        on conflict (request_id, stage_index, approver_id) do nothing
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(id)
+    .bind(stage_index)
+    .bind(authed.id)
+    .bind(decision_str)
+    .bind(note)
+    .execute(&mut *conn)
+    .await?;
+    if recorded.rows_affected() == 0 {
+        return Err(DecisionError::AlreadyDecided);
+    }
+
+    let grant = match decision {
+        Decision::Reject { reason } => {
+            sqlx::query(
+                "update download_requests set status = 'rejected', approver_id = $2, rejected_at = now(), updated_at = now(), reject_reason = $3 where id = $1",
+            )
+            .bind(id)
+            .bind(authed.id)
+            .bind(reason)
+            .execute(&mut *conn)
+            .await?;
+            events::publish(&mut *conn, Event::DownloadRequestRejected { request_id: id }).await?;
+            return Ok(DecisionOutcome { status: "rejected", approval_stage: stage_index });
+        }
+        Decision::Approve(g) => g,
+    };
+
+    let (required, is_last) = stage
+        .as_ref()
+        .map(|s| (s.required_approvals, s.is_last))
+        .unwrap_or((1, true));
+
+    let approvals = sqlx::query_scalar::<_, i64>(
+        "select count(*) from download_request_decisions where request_id = $1 and stage_index = $2 and decision = 'approved'",
+    )
+    .bind(id)
+    .bind(stage_index)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if approvals < required as i64 {
+        sqlx::query("update download_requests set updated_at = now() where id = $1")
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        return Ok(DecisionOutcome { status: "pending", approval_stage: stage_index });
+    }
+
+    if !is_last {
+        sqlx::query("update download_requests set approval_stage = approval_stage + 1, updated_at = now() where id = $1")
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        events::publish(&mut *conn, Event::DownloadRequestStageAdvanced { request_id: id }).await?;
+        return Ok(DecisionOutcome { status: "pending", approval_stage: stage_index + 1 });
+    }
+
+    sqlx::query(
+        r#"
+        update download_requests
+        set status = 'approved', approver_id = $2, approved_at = now(), updated_at = now(), expires_at = $3,
+            max_downloads = $4, downloads_remaining = $4, approver_note = $5
+        where id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(authed.id)
+    .bind(grant.expires_at.unwrap_or_else(crate::default_approval_expiry))
+    .bind(grant.max_downloads)
+    .bind(&grant.note)
+    .execute(&mut *conn)
+    .await?;
+    share_links::create_for_request(&mut *conn, id).await?;
+    events::publish(&mut *conn, Event::DownloadRequestApproved { request_id: id }).await?;
+
+    Ok(DecisionOutcome { status: "approved", approval_stage: stage_index })
+}
+
+async fn load_stage(conn: &mut PgConnection, policy_id: Uuid, stage_index: i32) -> sqlx::Result<Option<CurrentStage>> {
+    let row = sqlx::query_as::<_, (String, Option<Uuid>, Vec<Uuid>, i32, i64)>(
+        r#"
+        select
+            s.approver_kind, s.group_id, s.user_ids, s.required_approvals,
+            (select count(*) from approval_policy_stages x where x.policy_id = s.policy_id)
+        from approval_policy_stages s
+        where s.policy_id = $1 and s.stage_index = $2
+        "#,
+    )
+    .bind(policy_id)
+    .bind(stage_index)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(row.map(|(approver_kind, group_id, user_ids, required_approvals, stage_count)| CurrentStage {
+        approver_kind,
+        group_id,
+        user_ids,
+        required_approvals,
+        is_last: (stage_index as i64) + 1 >= stage_count,
+    }))
+}
+
+pub async fn resolve_policy<'e>(exec: impl PgExecutor<'e>, document_id: Uuid) -> sqlx::Result<Option<Uuid>> {
+    sqlx::query_scalar::<_, Option<Uuid>>(
+        r#"
+        select coalesce(
+            d.approval_policy_id,
+            (
+                select fp.policy_id
+                from folder_approval_policies fp
+                where fp.folder = '' or d.folder = fp.folder or starts_with(d.folder, fp.folder || '/')
+                order by length(fp.folder) desc
+                limit 1
+            )
+        )
+        from documents d
+        where d.id = $1
+        "#,
+    )
+    .bind(document_id)
+    .fetch_one(exec)
+    .await
+}
+
+fn validate_stages(stages: &[StageInput]) -> Result<(), &'static str> {
+    if stages.is_empty() {
+        return Err("at least one stage is required");
+    }
+    for s in stages {
+        let required = s.required_approvals.unwrap_or(1);
+        if required < 1 {
+            return Err("invalid required_approvals");
+        }
+        match s.approver.as_str() {
+            "owner" if required != 1 => return Err("owner stage requires exactly one approval"),
+            "owner" | "admins" => {}
+            "group" if s.group_id.is_none() => return Err("group stage requires group_id"),
+            "group" => {}
+            "users" => {
+                let n = s.user_ids.as_ref().map(|v| v.len()).unwrap_or(0);
+                if n == 0 {
+                    return Err("users stage requires user_ids");
+                }
+                if required as usize > n {
+                    return Err("required_approvals exceeds number of approvers");
+                }
+            }
+            _ => return Err("invalid approver"),
+        }
+    }
+    Ok(())
+}
+
+async fn load_policies(state: &AppState, id: Option<Uuid>) -> sqlx::Result<Vec<ApprovalPolicyDto>> {
+    let policies = sqlx::query_as::<_, (Uuid, String, DateTime<Utc>, DateTime<Utc>)>(
+        "select id, name, created_at, updated_at from approval_policies where ($1::uuid is null or id = $1) order by name",
+    )
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await?;
+
+    let stages = sqlx::query_as::<_, StageRow>(
+        r#"
+        select policy_id, approver_kind, group_id, user_ids, required_approvals
+        from approval_policy_stages
+        where ($1::uuid is null or policy_id = $1)
+        order by policy_id, stage_index
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut by_policy: HashMap<Uuid, Vec<StageDto>> = HashMap::new();
+    for s in stages {
+        by_policy.entry(s.policy_id).or_default().push(StageDto {
+            approver: s.approver_kind,
+            group_id: s.group_id,
+            user_ids: s.user_ids,
+            required_approvals: s.required_approvals,
+        });
+    }
+
+    Ok(policies
+        .into_iter()
+        .map(|(id, name, created_at, updated_at)| ApprovalPolicyDto {
+            stages: by_policy.remove(&id).unwrap_or_default(),
+            id,
+            name,
+            created_at,
+            updated_at,
+        })
+        .collect())
+}
+
+async fn write_stages(conn: &mut PgConnection, policy_id: Uuid, stages: &[StageInput]) -> sqlx::Result<()> {
+    sqlx::query("delete from approval_policy_stages where policy_id = $1")
+        .bind(policy_id)
+        .execute(&mut *conn)
+        .await?;
+
+    for (i, s) in stages.iter().enumerate() {
+        let user_ids = if s.approver == "users" { s.user_ids.clone().unwrap_or_default() } else { vec![] };
+        let group_id = if s.approver == "group" { s.group_id } else { None };
+        sqlx::query(
+            r#"
+            insert into approval_policy_stages (policy_id, stage_index, approver_kind, group_id, user_ids, required_approvals)
+            values ($1,$2,$3,$4,$5,$6)
+            "#,
+        )
+        .bind(policy_id)
+        .bind(i as i32)
+        .bind(&s.approver)
+        .bind(group_id)
+        .bind(&user_ids)
+        .bind(s.required_approvals.unwrap_or(1))
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn check_group_sizes(conn: &mut PgConnection, stages: &[StageInput]) -> sqlx::Result<Result<(), &'static str>> {
+    for s in stages.iter().filter(|s| s.approver == "group") {
+        let members = sqlx::query_scalar::<_, i64>("select count(*) from group_members where group_id = $1")
+            .bind(s.group_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if s.required_approvals.unwrap_or(1) as i64 > members {
+            return Ok(Err("required_approvals exceeds number of approvers"));
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Locks the policy against new requests and says whether requests still pending depend on its stages.
+async fn lock_unused_policy(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<bool>> {
+    let exists = sqlx::query_scalar::<_, Uuid>("select id from approval_policies where id = $1 for update")
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    if exists.is_none() {
+        return Ok(None);
+    }
+    sqlx::query_scalar::<_, bool>(
+        "select not exists(select 1 from download_requests where approval_policy_id = $1 and status = 'pending')",
+    )
+    .bind(id)
+    .fetch_one(&mut *conn)
+    .await
+    .map(Some)
+}
+
+fn stage_write_error(e: sqlx::Error) -> Response {
+    if let Some(db_err) = e.as_database_error() {
+        if db_err.is_foreign_key_violation() {
+            return (StatusCode::BAD_REQUEST, "unknown group").into_response();
+        }
+    }
+    (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+}
+
+pub async fn list_policies(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    match load_policies(&state, None).await {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn create_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<ApprovalPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if body.name.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    if let Err(msg) = validate_stages(&body.stages) {
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let id = Uuid::new_v4();
+    let res = sqlx::query("insert into approval_policies (id, name, created_by) values ($1,$2,$3)")
+        .bind(id)
+        .bind(body.name.trim())
+        .bind(authed.id)
+        .execute(&mut *tx)
+        .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match check_group_sizes(&mut tx, &body.stages).await {
+        Ok(Ok(())) => {}
+        Ok(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+    if let Err(e) = write_stages(&mut tx, id, &body.stages).await {
+        return stage_write_error(e);
+    }
+
+    if tx.commit().await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match load_policies(&state, Some(id)).await {
+        Ok(mut v) if !v.is_empty() => (StatusCode::CREATED, Json(v.remove(0))).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn update_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<ApprovalPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if body.name.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    if let Err(msg) = validate_stages(&body.stages) {
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    match lock_unused_policy(&mut tx, id).await {
+        Ok(Some(true)) => {}
+        Ok(Some(false)) => return (StatusCode::CONFLICT, "policy in use by pending requests").into_response(),
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+    match check_group_sizes(&mut tx, &body.stages).await {
+        Ok(Ok(())) => {}
+        Ok(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let res = sqlx::query("update approval_policies set name = $2, updated_at = now() where id = $1")
+        .bind(id)
+        .bind(body.name.trim())
+        .execute(&mut *tx)
+        .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    if let Err(e) = write_stages(&mut tx, id, &body.stages).await {
+        return stage_write_error(e);
+    }
+
+    if tx.commit().await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match load_policies(&state, Some(id)).await {
+        Ok(mut v) if !v.is_empty() => (StatusCode::OK, Json(v.remove(0))).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn delete_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    match lock_unused_policy(&mut tx, id).await {
+        Ok(Some(true)) => {}
+        Ok(Some(false)) => return (StatusCode::CONFLICT, "policy in use by pending requests").into_response(),
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let res = sqlx::query("delete from approval_policies where id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await;
+
+    match res {
+        Ok(_) => match tx.commit().await {
+            Ok(_) => StatusCode::NO_CONTENT.into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        },
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn assign_document_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<AssignPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let res = sqlx::query("update documents set approval_policy_id = $2, updated_at = now() where id = $1")
+        .bind(id)
+        .bind(body.policy_id)
+        .execute(&state.pool)
+        .await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            if let Some(db_err) = e.as_database_error() {
+                if db_err.is_foreign_key_violation() {
+                    return (StatusCode::BAD_REQUEST, "unknown policy").into_response();
+                }
+            }
+            (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+        }
+    }
+}
+
+pub async fn list_folder_policies(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, FolderPolicyDto>(
+        r#"
+        select fp.folder, fp.policy_id, p.name as policy_name
+        from folder_approval_policies fp
+        join approval_policies p on p.id = fp.policy_id
+        order by fp.folder
+        "#,
+    )
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn assign_folder_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<AssignFolderPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let Some(folder) = normalize_folder(&body.folder) else {
+        return (StatusCode::BAD_REQUEST, "invalid folder").into_response();
+    };
+
+    let res = match body.policy_id {
+        Some(policy_id) => {
+            sqlx::query(
+                "insert into folder_approval_policies (folder, policy_id) values ($1,$2) on conflict (folder) do update set policy_id = excluded.policy_id",
+            )
+            .bind(&folder)
+            .bind(policy_id)
+            .execute(&state.pool)
+            .await
+        }
+        None => {
+            sqlx::query("delete from folder_approval_policies where folder = $1")
+                .bind(&folder)
+                .execute(&state.pool)
+                .await
+        }
+    };
+
+    match res {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            if let Some(db_err) = e.as_database_error() {
+                if db_err.is_foreign_key_violation() {
+                    return (StatusCode::BAD_REQUEST, "unknown policy").into_response();
+                }
+            }
+            (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+        }
+    }
+}
+
+pub async fn list_decisions(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let visible = sqlx::query_scalar::<_, bool>(
+        r#"
+        select $3 or r.requester_id = $2 or d.owner_id = $2
+            or exists(select 1 from download_request_decisions x where x.request_id = r.id and x.approver_id = $2)
+        from download_requests r
+        join documents d on d.id = r.document_id
+        where r.id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(authed.id)
+    .bind(is_admin(&authed))
+    .fetch_optional(&state.pool)
+    .await;
+
+    match visible {
+        Ok(Some(true)) => {}
+        Ok(_) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let rows = sqlx::query_as::<_, DecisionDto>(
+        r#"
+        select x.id, x.stage_index, x.approver_id, u.username as approver_name, x.decision, x.note, x.created_at
+        from download_request_decisions x
+        left join users u on u.id = x.approver_id
+        where x.request_id = $1
+        order by x.created_at asc
+        "#,
+    )
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
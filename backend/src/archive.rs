@@ -0,0 +1,376 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufWriter, Write},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tracing::{error, warn};
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, write::StreamWriter, CompressionMethod, ZipWriter};
+
+use crate::{
+    claim_download, doc_accessible, is_admin, normalize_folder, refund_download, scanner, storage, watermark, AppState,
+    AuthedUser, DocumentRow, DOCUMENT_COLUMNS,
+};
+
+const ARCHIVE_MAX_DOCUMENTS_DEFAULT: usize = 500;
+const STREAM_BUFFER: usize = 64 * 1024;
+const STREAM_CHUNKS: usize = 8;
+const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveRequest {
+    document_ids: Option<Vec<Uuid>>,
+    folder: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IncludedEntry {
+    id: Uuid,
+    name: String,
+    path: String,
+    size: usize,
+    sha256: Option<String>,
+    download_request_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeniedEntry {
+    id: Uuid,
+    name: Option<String>,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    generated_at: DateTime<Utc>,
+    requested_by: Uuid,
+    folder: Option<String>,
+    included: Vec<IncludedEntry>,
+    denied: Vec<DeniedEntry>,
+}
+
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type Archive = ZipWriter<StreamWriter<BufWriter<ChannelWriter>>>;
+
+fn zip_time(t: DateTime<Utc>) -> zip::DateTime {
+    zip::DateTime::from_date_and_time(t.year() as u16, t.month() as u8, t.day() as u8, t.hour() as u8, t.minute() as u8, t.second() as u8)
+        .unwrap_or_default()
+}
+
+fn compressed(mime_type: &str) -> bool {
+    let mime = mime_type.to_ascii_lowercase();
+    mime.starts_with("image/")
+        || mime.starts_with("video/")
+        || mime.starts_with("audio/")
+        || mime.contains("zip")
+        || mime.contains("compressed")
+}
+
+fn entry_path(doc: &DocumentRow, root: &str, taken: &mut HashSet<String>) -> String {
+    let name: String = doc.name.chars().map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c }).collect();
+    let name = match name.trim() {
+        "" | "." | ".." => format!("{}.bin", doc.id),
+        v => v.to_string(),
+    };
+    let folder = doc.folder.strip_prefix(root).unwrap_or(&doc.folder).trim_start_matches('/');
+    let base = if folder.is_empty() { name } else { format!("{folder}/{name}") };
+
+    let (stem, ext) = match base.rsplit_once('.') {
+        Some((s, e)) if !s.is_empty() && !s.ends_with('/') && !e.contains('/') => (s.to_string(), format!(".{e}")),
+        _ => (base.clone(), String::new()),
+    };
+    let mut path = base;
+    let mut n = 2;
+    while path == MANIFEST_NAME || !taken.insert(path.to_lowercase()) {
+        path = format!("{stem} ({n}){ext}");
+        n += 1;
+    }
+    path
+}
+
+async fn load_documents(
+    state: &AppState,
+    authed: &AuthedUser,
+    body: &ArchiveRequest,
+) -> Result<(Vec<DocumentRow>, Vec<DeniedEntry>, Option<String>), Response> {
+    let mut denied = vec![];
+    match (&body.document_ids, &body.folder) {
+        (Some(ids), None) if !ids.is_empty() => {
+            let mut unique = vec![];
+            for id in ids {
+                if !unique.contains(id) {
+                    unique.push(*id);
+                }
+            }
+            let rows = sqlx::query_as::<_, DocumentRow>(&format!(
+                "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = any($1) and d.deleted_at is null"
+            ))
+            .bind(&unique)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response())?;
+
+            let mut rows: HashMap<Uuid, DocumentRow> = rows.into_iter().map(|d| (d.id, d)).collect();
+            let mut docs = vec![];
+            for id in unique {
+                match rows.remove(&id) {
+                    Some(doc) if doc_accessible(&doc, authed) => docs.push(doc),
+                    Some(_) => denied.push(DeniedEntry { id, name: None, reason: "forbidden".to_string() }),
+                    None => denied.push(DeniedEntry { id, name: None, reason: "not found".to_string() }),
+                }
+            }
+            Ok((docs, denied, None))
+        }
+        (None, Some(folder)) => {
+            let Some(folder) = normalize_folder(folder) else {
+                return Err((StatusCode::BAD_REQUEST, "invalid folder").into_response());
+            };
+            let rows = sqlx::query_as::<_, DocumentRow>(&format!(
+                r#"
+                select {DOCUMENT_COLUMNS}
+                from documents d join users u on u.id = d.owner_id
+                where d.deleted_at is null and ($1 = '' or d.folder = $1 or starts_with(d.folder, $1 || '/'))
+                order by d.folder, d.name
+                "#
+            ))
+            .bind(&folder)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response())?;
+            let docs = rows.into_iter().filter(|d| doc_accessible(d, authed)).collect();
+            Ok((docs, denied, Some(folder)))
+        }
+        _ => Err((StatusCode::BAD_REQUEST, "either document_ids or folder is required").into_response()),
+    }
+}
+
+async fn open(state: &AppState, doc: &DocumentRow) -> Result<storage::BoxReader, String> {
+    match storage::open_document(state, doc).await {
+        Ok(reader) => Ok(reader),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err("file missing".to_string()),
+        Err(e) => {
+            error!(?e, document_id = %doc.id, "open document failed");
+            Err("storage error".to_string())
+        }
+    }
+}
+
+async fn watermarked(state: &AppState, doc: &DocumentRow, request_id: Uuid) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(doc.size.max(0) as usize);
+    open(state, doc).await?.read_to_end(&mut data).await.map_err(|e| {
+        error!(?e, document_id = %doc.id, "read document failed");
+        "storage error".to_string()
+    })?;
+    watermark::apply(state, doc, request_id, data).await.map_err(|e| {
+        error!(?e, document_id = %doc.id, %request_id, "watermark failed");
+        "watermark failed".to_string()
+    })
+}
+
+// Returns a reader over the entry, its expected size and the approval it was released under, or why the document
+// was withheld. A claimed download is refunded here if the entry cannot be opened.
+async fn release(
+    state: &AppState,
+    authed: &AuthedUser,
+    doc: &DocumentRow,
+) -> Result<(storage::BoxReader, u64, Option<Uuid>), String> {
+    if let Some((_, reason)) = scanner::blocked(doc) {
+        return Err(reason.to_string());
+    }
+    if is_admin(authed) || doc.owner_id == authed.id || doc.download_preauthorized {
+        return open(state, doc).await.map(|reader| (reader, doc.size.max(0) as u64, None));
+    }
+
+    let request_id = claim_download(&state.pool, doc.id, authed.id)
+        .await
+        .map_err(|e| {
+            error!(?e, document_id = %doc.id, "claim archive download failed");
+            "db error".to_string()
+        })?
+        .map_err(str::to_string)?;
+    let opened = if watermark::applies(doc) {
+        watermarked(state, doc, request_id).await.map(|data| {
+            let size = data.len() as u64;
+            (Box::new(io::Cursor::new(data)) as storage::BoxReader, size)
+        })
+    } else {
+        open(state, doc).await.map(|reader| (reader, doc.size.max(0) as u64))
+    };
+    match opened {
+        Ok((reader, size)) => Ok((reader, size, Some(request_id))),
+        Err(reason) => {
+            refund_download(&state.pool, request_id).await;
+            Err(reason)
+        }
+    }
+}
+
+// Copies `reader` into a new entry in chunks; returns the archive and the number of bytes written.
+async fn add_entry(
+    zip: Archive,
+    path: String,
+    mut reader: storage::BoxReader,
+    size: u64,
+    options: SimpleFileOptions,
+) -> anyhow::Result<(Archive, u64)> {
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let mut zip = zip;
+        zip.start_file(path, options.large_file(size >= u32::MAX as u64))?;
+        let mut buf = vec![0u8; STREAM_BUFFER];
+        let mut written = 0u64;
+        loop {
+            let n = runtime.block_on(reader.read(&mut buf))?;
+            if n == 0 {
+                break;
+            }
+            zip.write_all(&buf[..n])?;
+            written += n as u64;
+        }
+        anyhow::Ok((zip, written))
+    })
+    .await?
+}
+
+async fn write_archive(
+    state: AppState,
+    authed: AuthedUser,
+    docs: Vec<DocumentRow>,
+    mut denied: Vec<DeniedEntry>,
+    folder: Option<String>,
+    tx: mpsc::Sender<io::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let mut zip = ZipWriter::new_stream(BufWriter::with_capacity(STREAM_BUFFER, ChannelWriter(tx)));
+    let root = folder.clone().unwrap_or_default();
+    let mut taken = HashSet::new();
+    let mut included = vec![];
+
+    for doc in docs {
+        let (reader, size, download_request_id) = match release(&state, &authed, &doc).await {
+            Ok(v) => v,
+            Err(reason) => {
+                denied.push(DeniedEntry { id: doc.id, name: Some(doc.name.clone()), reason });
+                continue;
+            }
+        };
+        let path = entry_path(&doc, &root, &mut taken);
+        let method = if compressed(&doc.mime_type) { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .last_modified_time(zip_time(doc.updated_at));
+        let written = match add_entry(zip, path.clone(), reader, size, options).await {
+            Ok((next, written)) => {
+                zip = next;
+                written
+            }
+            Err(e) => {
+                if let Some(request_id) = download_request_id {
+                    refund_download(&state.pool, request_id).await;
+                }
+                return Err(e);
+            }
+        };
+        included.push(IncludedEntry {
+            id: doc.id,
+            name: doc.name.clone(),
+            path,
+            size: written as usize,
+            sha256: doc.sha256.clone(),
+            download_request_id,
+        });
+    }
+
+    let manifest = Manifest {
+        generated_at: Utc::now(),
+        requested_by: authed.id,
+        folder,
+        included,
+        denied,
+    };
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    let size = data.len() as u64;
+    let options = SimpleFileOptions::default().last_modified_time(zip_time(manifest.generated_at));
+    let (zip, _) = add_entry(zip, MANIFEST_NAME.to_string(), Box::new(io::Cursor::new(data)), size, options).await?;
+
+    tokio::task::spawn_blocking(move || {
+        zip.finish()?.into_inner().flush()?;
+        anyhow::Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+pub async fn download_archive(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<ArchiveRequest>,
+) -> impl IntoResponse {
+    let (docs, denied, folder) = match load_documents(&state, &authed, &body).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    let max: usize = std::env::var("ARCHIVE_MAX_DOCUMENTS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(ARCHIVE_MAX_DOCUMENTS_DEFAULT);
+    if docs.len() + denied.len() > max {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "too many documents").into_response();
+    }
+    if docs.is_empty() && denied.is_empty() {
+        return (StatusCode::NOT_FOUND, "no documents").into_response();
+    }
+
+    let file_name = match folder.as_deref().and_then(|f| f.rsplit('/').next()).filter(|f| !f.is_empty()) {
+        Some(last) => format!("{}.zip", last.replace('"', "_")),
+        None => "documents.zip".to_string(),
+    };
+
+    let (tx, mut rx) = mpsc::channel::<io::Result<Bytes>>(STREAM_CHUNKS);
+    tokio::spawn(async move {
+        let abort = tx.clone();
+        if let Err(e) = write_archive(state, authed, docs, denied, folder, tx).await {
+            warn!(?e, "archive stream aborted");
+            let _ = abort.send(Err(io::Error::other("archive failed"))).await;
+        }
+    });
+
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    let mut resp = Response::new(Body::from_stream(stream));
+    let headers = resp.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{file_name}\""))
+            .unwrap_or_else(|_| HeaderValue::from_static("attachment; filename=\"documents.zip\"")),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    resp
+}
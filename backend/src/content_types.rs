@@ -0,0 +1,311 @@
+use axum::http::StatusCode;
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{storage, AppState, DocumentRow, DOCUMENT_COLUMNS};
+
+const SNIFF_LEN: usize = 8 * 1024;
+const BACKFILL_BATCH: i64 = 100;
+pub const INSPECT_LEN: usize = 64 * 1024;
+
+pub const EXECUTABLE: &str = "executable content not allowed";
+pub const MISMATCH: &str = "file content does not match its extension";
+pub const TYPE_NOT_ALLOWED: &str = "file type not allowed";
+pub const EXTENSION_NOT_ALLOWED: &str = "file extension not allowed";
+pub const TOO_LARGE_FOR_TYPE: &str = "file too large for its type";
+
+const EXECUTABLE_TYPES: &[&str] = &[
+    "application/x-executable",
+    "application/x-mach-binary",
+    "application/vnd.microsoft.portable-executable",
+    "application/vnd.android.dex",
+    "application/vnd.android.dey",
+    "application/java",
+    "application/wasm",
+    "application/x-shockwave-flash",
+    "application/x-google-chrome-extension",
+    "application/vnd.debian.binary-package",
+    "application/x-rpm",
+];
+
+const EXECUTABLE_EXTENSIONS: &[&str] = &[
+    "exe", "dll", "com", "scr", "pif", "cpl", "msi", "msp", "msc", "sys", "drv", "bat", "cmd", "ps1", "psm1", "vbs", "vbe", "jse", "wsf",
+    "wsh", "hta", "lnk", "reg", "jar", "apk", "app", "dmg", "pkg", "deb", "rpm", "so", "dylib", "elf",
+];
+
+// Types whose content runs script when rendered by a browser. Sniffed as such, they must also be named as such.
+const ACTIVE_TYPES: &[&str] = &["text/html", "application/xhtml+xml", "image/svg+xml", "text/xml", "application/xml"];
+
+// Formats that are ZIP or OLE containers underneath; the sniffer may only recognise the container.
+const ZIP_CONTAINER_EXTENSIONS: &[&str] = &["docx", "xlsx", "pptx", "docm", "xlsm", "pptm", "odt", "ods", "odp", "odg", "epub", "vsdx"];
+const OLE_CONTAINER_EXTENSIONS: &[&str] = &["doc", "xls", "ppt", "msg", "vsd", "pub"];
+// Text formats the sniffer only recognises by an optional prefix, so a missing one proves nothing.
+const WEAK_SIGNATURE_EXTENSIONS: &[&str] = &["html", "htm", "xml", "sh"];
+
+struct ContentPolicy {
+    block_executables: bool,
+    allowed_types: Vec<String>,
+    denied_types: Vec<String>,
+    allowed_extensions: Vec<String>,
+    denied_extensions: Vec<String>,
+    size_limits: Vec<(String, u64)>,
+}
+
+impl ContentPolicy {
+    fn from_env() -> Self {
+        fn list(name: &str) -> Vec<String> {
+            std::env::var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(|v| v.trim().trim_start_matches('.').to_ascii_lowercase())
+                .filter(|v| !v.is_empty())
+                .collect()
+        }
+        let block_executables = std::env::var("UPLOAD_BLOCK_EXECUTABLES")
+            .map(|v| !matches!(v.trim().to_ascii_lowercase().as_str(), "0" | "false" | "no"))
+            .unwrap_or(true);
+        let size_limits = list("UPLOAD_TYPE_SIZE_LIMITS")
+            .into_iter()
+            .filter_map(|v| {
+                let (pattern, bytes) = v.split_once('=')?;
+                Some((pattern.trim().to_string(), bytes.trim().parse::<u64>().ok()?))
+            })
+            .collect();
+        Self {
+            block_executables,
+            allowed_types: list("UPLOAD_ALLOWED_TYPES"),
+            denied_types: list("UPLOAD_DENIED_TYPES"),
+            allowed_extensions: list("UPLOAD_ALLOWED_EXTENSIONS"),
+            denied_extensions: list("UPLOAD_DENIED_EXTENSIONS"),
+            size_limits,
+        }
+    }
+
+    fn check_extension(&self, ext: &str) -> Result<(), (StatusCode, &'static str)> {
+        if self.block_executables && EXECUTABLE_EXTENSIONS.contains(&ext) {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, EXECUTABLE));
+        }
+        let listed = |l: &Vec<String>| l.iter().any(|v| v == ext || v == "*");
+        if listed(&self.denied_extensions) || (!self.allowed_extensions.is_empty() && !listed(&self.allowed_extensions)) {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, EXTENSION_NOT_ALLOWED));
+        }
+        Ok(())
+    }
+
+    fn check_type(&self, mime: &str, size: u64) -> Result<(), (StatusCode, &'static str)> {
+        let listed = |l: &Vec<String>| l.iter().any(|p| type_specificity(p, mime).is_some());
+        if listed(&self.denied_types) || (!self.allowed_types.is_empty() && !listed(&self.allowed_types)) {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, TYPE_NOT_ALLOWED));
+        }
+        let limit = self
+            .size_limits
+            .iter()
+            .filter_map(|(p, bytes)| type_specificity(p, mime).map(|s| (s, *bytes)))
+            .max_by_key(|(s, _)| *s);
+        match limit {
+            Some((_, max)) if size > max => Err((StatusCode::PAYLOAD_TOO_LARGE, TOO_LARGE_FOR_TYPE)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// How closely `pattern` (`*`, `image/*` or `image/png`) matches `mime`; None if it does not.
+fn type_specificity(pattern: &str, mime: &str) -> Option<u8> {
+    if pattern == "*" {
+        return Some(0);
+    }
+    if pattern == mime {
+        return Some(2);
+    }
+    match pattern.strip_suffix("/*") {
+        Some(top) if mime.split('/').next() == Some(top) => Some(1),
+        _ => None,
+    }
+}
+
+fn extension(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.to_ascii_lowercase(),
+        _ => String::new(),
+    }
+}
+
+fn looks_like_text(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(SNIFF_LEN)];
+    head.starts_with(&[0xFF, 0xFE]) || head.starts_with(&[0xFE, 0xFF]) || !head.contains(&0)
+}
+
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    let mime = infer::get(data).map(|t| t.mime_type())?;
+    if mime == "text/xml" {
+        let head = String::from_utf8_lossy(&data[..data.len().min(SNIFF_LEN)]).to_ascii_lowercase();
+        if head.contains("<svg") {
+            return Some("image/svg+xml");
+        }
+    }
+    Some(mime)
+}
+
+// Checks only what the file name reveals, so resumable uploads can be refused before any data is sent.
+pub fn check_name(name: &str) -> Result<(), (StatusCode, &'static str)> {
+    ContentPolicy::from_env().check_extension(&extension(name))
+}
+
+// Works out the MIME type to store from the file's magic bytes and name, ignoring whatever the client
+// claimed, and applies the configured type, extension and size rules. `data` only needs to hold the first
+// INSPECT_LEN bytes of a `size`-byte file.
+pub fn inspect(name: &str, data: &[u8], size: u64) -> Result<String, (StatusCode, &'static str)> {
+    let policy = ContentPolicy::from_env();
+    let ext = extension(name);
+    policy.check_extension(&ext)?;
+    let mime = detect(&policy, &ext, data)?;
+    policy.check_type(&mime, size)?;
+    Ok(mime)
+}
+
+fn detect(policy: &ContentPolicy, ext: &str, data: &[u8]) -> Result<String, (StatusCode, &'static str)> {
+    let detected = sniff(data);
+    if policy.block_executables && detected.is_some_and(|m| EXECUTABLE_TYPES.contains(&m)) {
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, EXECUTABLE));
+    }
+
+    let guessed = mime_guess::from_ext(ext).first().map(|m| m.essence_str().to_string());
+    let guessed_text = guessed.as_deref().is_some_and(|m| m.starts_with("text/"));
+    let container = (ZIP_CONTAINER_EXTENSIONS.contains(&ext) && detected == Some("application/zip"))
+        || (OLE_CONTAINER_EXTENSIONS.contains(&ext) && detected == Some("application/x-ole-storage"));
+
+    // A sniffable extension whose signature is missing means the name lies about the content.
+    let weak = guessed_text || WEAK_SIGNATURE_EXTENSIONS.contains(&ext);
+    if !ext.is_empty() && !weak && !container && infer::is_supported(ext) && !infer::is(data, ext) {
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, MISMATCH));
+    }
+    let mime = match (detected, guessed) {
+        (Some(d), Some(g)) if ACTIVE_TYPES.contains(&d) && !ACTIVE_TYPES.contains(&g.as_str()) => {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, MISMATCH));
+        }
+        (Some(d), Some(_)) if !d.starts_with("text/") && guessed_text => {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, MISMATCH));
+        }
+        (Some(_), Some(g)) if container => g,
+        (Some(d), _) if !d.starts_with("text/") => d.to_string(),
+        (_, Some(g)) if g.starts_with("text/") && !looks_like_text(data) => {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, MISMATCH));
+        }
+        (_, Some(g)) => g,
+        (Some(d), None) => d.to_string(),
+        (None, None) if looks_like_text(data) => "text/plain".to_string(),
+        (None, None) => "application/octet-stream".to_string(),
+    };
+    Ok(mime)
+}
+
+// Re-sniffs every stored document and corrects its recorded MIME type. Documents uploaded before content
+// inspection whose content contradicts their name are downgraded to application/octet-stream so they are
+// never rendered inline.
+pub async fn backfill(state: &AppState) -> anyhow::Result<()> {
+    let policy = ContentPolicy::from_env();
+    let (mut checked, mut corrected, mut mislabelled) = (0u64, 0u64, 0u64);
+    let mut after = Uuid::nil();
+    loop {
+        let docs = sqlx::query_as::<_, DocumentRow>(&format!(
+            "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id > $1 order by d.id limit $2"
+        ))
+        .bind(after)
+        .bind(BACKFILL_BATCH)
+        .fetch_all(&state.pool)
+        .await?;
+        let Some(last) = docs.last() else {
+            break;
+        };
+        after = last.id;
+
+        for doc in docs {
+            let mut head = Vec::with_capacity(INSPECT_LEN);
+            let read = async {
+                let mut reader = storage::open_document(state, &doc).await?;
+                (&mut reader).take(INSPECT_LEN as u64).read_to_end(&mut head).await
+            };
+            if let Err(e) = read.await {
+                warn!(?e, document_id = %doc.id, "read document for type backfill failed");
+                continue;
+            }
+            checked += 1;
+
+            let mime = match detect(&policy, &extension(&doc.name), &head) {
+                Ok(v) => v,
+                Err((_, reason)) => {
+                    warn!(document_id = %doc.id, name = %doc.name, reason, "stored document content does not match its name");
+                    mislabelled += 1;
+                    "application/octet-stream".to_string()
+                }
+            };
+            if mime != doc.mime_type {
+                sqlx::query("update documents set mime_type = $2 where id = $1")
+                    .bind(doc.id)
+                    .bind(&mime)
+                    .execute(&state.pool)
+                    .await?;
+                corrected += 1;
+            }
+        }
+    }
+
+    info!(checked, corrected, mislabelled, "backfilled document types");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+    fn docx() -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let entries: [(&str, &[u8]); 3] = [
+            (
+                "[Content_Types].xml",
+                br#"<?xml version="1.0" encoding="UTF-8"?><Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Override PartName="/word/document.xml" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml"/></Types>"#,
+            ),
+            ("_rels/.rels", br#"<?xml version="1.0" encoding="UTF-8"?><Relationships/>"#),
+            ("word/document.xml", br#"<?xml version="1.0" encoding="UTF-8"?><w:document/>"#),
+        ];
+        for (name, data) in entries {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn elf() -> Vec<u8> {
+        let mut data = vec![0x7F, b'E', b'L', b'F', 2, 1, 1, 0];
+        data.resize(64, 0);
+        data[16] = 2;
+        data
+    }
+
+    #[test]
+    fn inspect_detects_types_and_rejects_mislabelled_content() {
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D, b'I', b'H', b'D', b'R'];
+        let cases: Vec<(&str, Vec<u8>, Result<&str, &str>)> = vec![
+            ("invoice.pdf", b"<!DOCTYPE html><html><script>alert(1)</script></html>".to_vec(), Err(MISMATCH)),
+            ("logo.png", br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"></svg>"#.to_vec(), Err(MISMATCH)),
+            ("notes.txt", elf(), Err(EXECUTABLE)),
+            ("report.docx", docx(), Ok(DOCX)),
+            ("real.pdf", b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n".to_vec(), Ok("application/pdf")),
+            ("logo.png", png.to_vec(), Ok("image/png")),
+            ("page.html", b"<!DOCTYPE html><html></html>".to_vec(), Ok("text/html")),
+            ("notes.txt", b"plain words".to_vec(), Ok("text/plain")),
+            ("README", b"no extension".to_vec(), Ok("text/plain")),
+        ];
+        for (name, data, expected) in cases {
+            let got = inspect(name, &data, data.len() as u64).map_err(|(_, msg)| msg);
+            assert_eq!(got, expected.map(String::from), "{name}");
+        }
+    }
+}
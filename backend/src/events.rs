@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{jobs, notifications, realtime, webhooks, AppState};
+
+pub const EVENT_TYPES: &[&str] = &[
+    "user.registered",
+    "user.approved",
+    "user.disabled",
+    "document.created",
+    "document.updated",
+    "document.deleted",
+    "document.restored",
+    "document.shared",
+    "document.infected",
+    "document.unscannable",
+    "download_request.created",
+    "download_request.stage_advanced",
+    "download_request.approved",
+    "download_request.rejected",
+    "download_request.cancelled",
+    "download_request.expired",
+    "download_request.expiring_soon",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    #[serde(rename = "user.registered")]
+    UserRegistered { user_id: Uuid },
+    #[serde(rename = "user.approved")]
+    UserApproved { user_id: Uuid },
+    #[serde(rename = "user.disabled")]
+    UserDisabled { user_id: Uuid },
+    #[serde(rename = "download_request.created")]
+    DownloadRequestCreated { request_id: Uuid },
+    #[serde(rename = "download_request.stage_advanced")]
+    DownloadRequestStageAdvanced { request_id: Uuid },
+    #[serde(rename = "download_request.approved")]
+    DownloadRequestApproved { request_id: Uuid },
+    #[serde(rename = "download_request.rejected")]
+    DownloadRequestRejected { request_id: Uuid },
+    #[serde(rename = "download_request.cancelled")]
+    DownloadRequestCancelled { request_id: Uuid },
+    #[serde(rename = "download_request.expired")]
+    DownloadRequestExpired { request_id: Uuid },
+    #[serde(rename = "download_request.expiring_soon")]
+    DownloadRequestExpiringSoon { request_id: Uuid },
+    #[serde(rename = "document.created")]
+    DocumentCreated { document_id: Uuid },
+    #[serde(rename = "document.updated")]
+    DocumentUpdated {
+        document_id: Uuid,
+        previous_permission: String,
+        previous_allowed_users: Vec<Uuid>,
+    },
+    #[serde(rename = "document.deleted")]
+    DocumentDeleted {
+        document_id: Uuid,
+        owner_id: Uuid,
+        permission: String,
+        allowed_users: Vec<Uuid>,
+    },
+    #[serde(rename = "document.restored")]
+    DocumentRestored { document_id: Uuid },
+    #[serde(rename = "document.shared")]
+    DocumentShared { document_id: Uuid, user_ids: Vec<Uuid> },
+    #[serde(rename = "document.infected")]
+    DocumentInfected { document_id: Uuid, signature: String },
+    #[serde(rename = "document.unscannable")]
+    DocumentUnscannable { document_id: Uuid, reason: String },
+}
+
+impl Event {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Self::UserRegistered { .. } => "user.registered",
+            Self::UserApproved { .. } => "user.approved",
+            Self::UserDisabled { .. } => "user.disabled",
+            Self::DownloadRequestCreated { .. } => "download_request.created",
+            Self::DownloadRequestStageAdvanced { .. } => "download_request.stage_advanced",
+            Self::DownloadRequestApproved { .. } => "download_request.approved",
+            Self::DownloadRequestRejected { .. } => "download_request.rejected",
+            Self::DownloadRequestCancelled { .. } => "download_request.cancelled",
+            Self::DownloadRequestExpired { .. } => "download_request.expired",
+            Self::DownloadRequestExpiringSoon { .. } => "download_request.expiring_soon",
+            Self::DocumentCreated { .. } => "document.created",
+            Self::DocumentUpdated { .. } => "document.updated",
+            Self::DocumentDeleted { .. } => "document.deleted",
+            Self::DocumentRestored { .. } => "document.restored",
+            Self::DocumentShared { .. } => "document.shared",
+            Self::DocumentInfected { .. } => "document.infected",
+            Self::DocumentUnscannable { .. } => "document.unscannable",
+        }
+    }
+}
+
+pub async fn publish<'e>(exec: impl PgExecutor<'e>, event: Event) -> sqlx::Result<()> {
+    let payload = serde_json::to_value(&event).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    jobs::enqueue(exec, jobs::DISPATCH_EVENT, payload).await?;
+    Ok(())
+}
+
+// Inbox rows, emails and webhook deliveries are written in one transaction keyed by the dispatch job, so a
+// retried job never fans out twice. Realtime pushes are best effort and never fail the job.
+pub async fn dispatch(state: &AppState, job_id: Uuid, event: Event) -> anyhow::Result<()> {
+    let mut tx = state.pool.begin().await?;
+    let first = sqlx::query("insert into event_dispatches (job_id) values ($1) on conflict do nothing")
+        .bind(job_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+        > 0;
+    if first {
+        notifications::deliver(state, &mut tx, &event).await?;
+        webhooks::enqueue_deliveries(&state.pool, &mut tx, &event).await?;
+        tx.commit().await?;
+    } else {
+        tx.rollback().await?;
+    }
+
+    if let Err(e) = realtime::notify(&state.pool, &event).await {
+        warn!(?e, event = event.event_type(), "realtime notify failed");
+    }
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use axum::{
+    extract::{Extension, Path as AxumPath, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{is_admin, AppState, AuthedUser};
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupDto {
+    id: Uuid,
+    name: String,
+    member_ids: Vec<Uuid>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupRequest {
+    name: String,
+    member_ids: Option<Vec<Uuid>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetGroupMembersRequest {
+    member_ids: Vec<Uuid>,
+}
+
+const GROUP_SELECT: &str = r#"
+    select
+        g.id, g.name,
+        coalesce((select array_agg(gm.user_id) from group_members gm where gm.group_id = g.id), '{}') as member_ids,
+        g.created_at
+    from groups g
+"#;
+
+pub async fn list_groups(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, GroupDto>(&format!("{GROUP_SELECT} order by lower(g.name)"))
+        .fetch_all(&state.pool)
+        .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn create_group(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<CreateGroupRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let id = Uuid::new_v4();
+    let res = sqlx::query("insert into groups (id, name) values ($1,$2)")
+        .bind(id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await;
+
+    if let Err(e) = res {
+        if let Some(db_err) = e.as_database_error() {
+            if db_err.constraint() == Some("idx_groups_name_unique") {
+                return (StatusCode::CONFLICT, "group exists").into_response();
+            }
+        }
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let member_ids = body.member_ids.unwrap_or_default();
+    let res = sqlx::query(
+        "insert into group_members (group_id, user_id) select $1, u.id from users u where u.id = any($2) on conflict do nothing",
+    )
+    .bind(id)
+    .bind(&member_ids)
+    .execute(&mut *tx)
+    .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let created = sqlx::query_as::<_, GroupDto>(&format!("{GROUP_SELECT} where g.id = $1"))
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await;
+
+    match (created, tx.commit().await) {
+        (Ok(g), Ok(())) => (StatusCode::CREATED, Json(g)).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn set_group_members(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<SetGroupMembersRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let exists = sqlx::query_scalar::<_, bool>("select exists(select 1 from groups where id = $1)")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await;
+    match exists {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let res = sqlx::query("delete from group_members where group_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let res = sqlx::query(
+        "insert into group_members (group_id, user_id) select $1, u.id from users u where u.id = any($2) on conflict do nothing",
+    )
+    .bind(id)
+    .bind(&body.member_ids)
+    .execute(&mut *tx)
+    .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn delete_group(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let res = sqlx::query("delete from groups where id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            if let Some(db_err) = e.as_database_error() {
+                if db_err.is_foreign_key_violation() {
+                    return (StatusCode::CONFLICT, "group used by approval policy").into_response();
+                }
+            }
+            (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, Path as AxumPath, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{is_admin, AppState, AuthedUser};
+
+pub const REMOVE_FILE: &str = "storage.remove_file";
+pub const SWEEP_DOWNLOAD_REQUESTS: &str = "download_requests.sweep";
+pub const DISPATCH_EVENT: &str = "events.dispatch";
+pub const SEND_EMAIL: &str = "email.send";
+pub const DELIVER_WEBHOOK: &str = "webhook.deliver";
+pub const PURGE_TRASH: &str = "documents.purge_trash";
+pub const ENFORCE_RETENTION: &str = "documents.enforce_retention";
+pub const GENERATE_PREVIEWS: &str = "documents.generate_previews";
+pub const EXPIRE_UPLOAD_SESSIONS: &str = "uploads.expire_sessions";
+pub const SCAN_DOCUMENT: &str = "documents.scan";
+pub const BACKFILL_MIME_TYPES: &str = "documents.backfill_mime_types";
+pub const READDRESS_BLOBS: &str = "storage.readdress_blobs";
+
+const JOB_WORKERS_DEFAULT: usize = 2;
+const JOB_POLL_INTERVAL_MS_DEFAULT: u64 = 1000;
+pub const JOB_MAX_ATTEMPTS_DEFAULT: i32 = 5;
+const JOB_BACKOFF_BASE_SECS: i64 = 10;
+const JOB_BACKOFF_MAX_SECS: i64 = 3600;
+const JOB_LEASE_SECS: i64 = 60;
+const JOB_HEARTBEAT_SECS: u64 = 20;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRow {
+    id: Uuid,
+    kind: String,
+    payload: serde_json::Value,
+    status: String,
+    attempts: i32,
+    max_attempts: i32,
+    run_at: DateTime<Utc>,
+    last_error: Option<String>,
+    dedupe_key: Option<String>,
+    locked_by: Option<String>,
+    locked_at: Option<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveFilePayload {
+    pub storage_rel_path: String,
+}
+
+pub async fn enqueue<'e>(exec: impl PgExecutor<'e>, kind: &str, payload: serde_json::Value) -> sqlx::Result<Uuid> {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        "insert into jobs (id, kind, payload, status, max_attempts, run_at) values ($1,$2,$3,'queued',$4,now())",
+    )
+    .bind(id)
+    .bind(kind)
+    .bind(payload)
+    .bind(JOB_MAX_ATTEMPTS_DEFAULT)
+    .execute(exec)
+    .await?;
+    Ok(id)
+}
+
+pub async fn enqueue_unique<'e>(
+    exec: impl PgExecutor<'e>,
+    kind: &str,
+    payload: serde_json::Value,
+    dedupe_key: &str,
+) -> sqlx::Result<Option<Uuid>> {
+    let id = Uuid::new_v4();
+    let res = sqlx::query(
+        r#"
+        insert into jobs (id, kind, payload, status, max_attempts, run_at, dedupe_key)
+        values ($1,$2,$3,'queued',$4,now(),$5)
+        on conflict (dedupe_key) where status in ('queued','running') do nothing
+        "#,
+    )
+    .bind(id)
+    .bind(kind)
+    .bind(payload)
+    .bind(JOB_MAX_ATTEMPTS_DEFAULT)
+    .bind(dedupe_key)
+    .execute(exec)
+    .await?;
+    Ok((res.rows_affected() > 0).then_some(id))
+}
+
+pub fn spawn_periodic(state: &AppState, kind: &'static str, every: Duration) {
+    let pool = state.pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = enqueue_unique(&pool, kind, serde_json::json!({}), kind).await {
+                error!(?e, kind, "schedule periodic job failed");
+            }
+        }
+    });
+}
+
+pub fn spawn_workers(state: &AppState) {
+    let workers: usize = std::env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(JOB_WORKERS_DEFAULT);
+    let poll_ms: u64 = std::env::var("JOB_POLL_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(JOB_POLL_INTERVAL_MS_DEFAULT);
+
+    let instance = Uuid::new_v4().simple().to_string();
+    for i in 0..workers {
+        let state = state.clone();
+        let worker_id = format!("{}-{}", &instance[..8], i);
+        tokio::spawn(async move { worker_loop(state, worker_id, Duration::from_millis(poll_ms.max(50))).await });
+    }
+
+    let pool = state.pool.clone();
+    tokio::spawn(async move { reaper_loop(pool).await });
+
+    info!(workers, "job workers started");
+}
+
+async fn worker_loop(state: AppState, worker_id: String, poll: Duration) {
+    loop {
+        match claim_next(&state.pool, &worker_id).await {
+            Ok(Some(job)) => run_claimed(&state, &worker_id, job).await,
+            Ok(None) => tokio::time::sleep(poll).await,
+            Err(e) => {
+                error!(?e, "claim job failed");
+                tokio::time::sleep(poll).await;
+            }
+        }
+    }
+}
+
+async fn claim_next(pool: &PgPool, worker_id: &str) -> sqlx::Result<Option<JobRow>> {
+    sqlx::query_as::<_, JobRow>(
+        r#"
+        update jobs
+        set status = 'running', attempts = attempts + 1, locked_by = $1, locked_at = now(),
+            locked_until = now() + make_interval(secs => $2), updated_at = now()
+        where id = (
+            select id from jobs
+            where status = 'queued' and run_at <= now()
+            order by run_at asc
+            limit 1
+            for update skip locked
+        )
+        returning
+            id, kind, payload, status, attempts, max_attempts, run_at, last_error, dedupe_key,
+            locked_by, locked_at, locked_until, created_at, updated_at, finished_at
+        "#,
+    )
+    .bind(worker_id)
+    .bind(JOB_LEASE_SECS as f64)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn run_claimed(state: &AppState, worker_id: &str, job: JobRow) {
+    let task_state = state.clone();
+    let kind = job.kind.clone();
+    let payload = job.payload.clone();
+    let job_id = job.id;
+    let mut task = tokio::spawn(async move { run_job(&task_state, job_id, &kind, payload).await });
+
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(JOB_HEARTBEAT_SECS));
+    heartbeat.tick().await;
+    let outcome = loop {
+        tokio::select! {
+            outcome = &mut task => break outcome,
+            _ = heartbeat.tick() => match extend_lease(&state.pool, job.id, worker_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    task.abort();
+                    info!(job_id = %job.id, kind = %job.kind, "job cancelled or lease lost, stopped");
+                    return;
+                }
+                Err(e) => error!(?e, job_id = %job.id, "extend job lease failed"),
+            },
+        }
+    };
+
+    let err = match outcome {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{e:#}")),
+        Err(e) => Some(format!("job panicked: {e}")),
+    };
+
+    let res = match &err {
+        None => {
+            sqlx::query(
+                r#"
+                update jobs
+                set status = 'succeeded', locked_by = null, locked_at = null, locked_until = null, updated_at = now(), finished_at = now()
+                where id = $1 and status = 'running' and locked_by = $2
+                "#,
+            )
+            .bind(job.id)
+            .bind(worker_id)
+            .execute(&state.pool)
+            .await
+        }
+        Some(msg) if job.attempts >= job.max_attempts => {
+            warn!(job_id = %job.id, kind = %job.kind, error = %msg, "job failed permanently");
+            sqlx::query(
+                r#"
+                update jobs
+                set status = 'failed', last_error = $3, locked_by = null, locked_at = null, locked_until = null, updated_at = now(), finished_at = now()
+                where id = $1 and status = 'running' and locked_by = $2
+                "#,
+            )
+            .bind(job.id)
+            .bind(worker_id)
+            .bind(msg)
+            .execute(&state.pool)
+            .await
+        }
+        Some(msg) => {
+            let run_at = Utc::now() + chrono::Duration::seconds(backoff_secs(job.attempts));
+            warn!(job_id = %job.id, kind = %job.kind, error = %msg, %run_at, "job failed, retrying");
+            sqlx::query(
+                r#"
+                update jobs
+                set status = 'queued', last_error = $3, run_at = $4, locked_by = null, locked_at = null, locked_until = null, updated_at = now()
+                where id = $1 and status = 'running' and locked_by = $2
+                "#,
+            )
+            .bind(job.id)
+            .bind(worker_id)
+            .bind(msg)
+            .bind(run_at)
+            .execute(&state.pool)
+            .await
+        }
+    };
+
+    if let Err(e) = res {
+        error!(?e, job_id = %job.id, "update job status failed");
+    }
+}
+
+async fn extend_lease(pool: &PgPool, job_id: Uuid, worker_id: &str) -> sqlx::Result<bool> {
+    let res = sqlx::query(
+        r#"
+        update jobs
+        set locked_until = now() + make_interval(secs => $3), updated_at = now()
+        where id = $1 and status = 'running' and locked_by = $2
+        "#,
+    )
+    .bind(job_id)
+    .bind(worker_id)
+    .bind(JOB_LEASE_SECS as f64)
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+fn backoff_secs(attempts: i32) -> i64 {
+    let exp = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    JOB_BACKOFF_BASE_SECS
+        .saturating_mul(2_i64.saturating_pow(exp))
+        .min(JOB_BACKOFF_MAX_SECS)
+}
+
+async fn reaper_loop(pool: PgPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        // A job whose worker died has already used the attempt it was claimed with, so one that keeps
+        // crashing the process fails for good instead of being reclaimed forever.
+        let res = sqlx::query_scalar::<_, String>(
+            r#"
+            update jobs
+            set status = case when attempts >= max_attempts then 'failed' else 'queued' end,
+                finished_at = case when attempts >= max_attempts then now() end,
+                locked_by = null, locked_at = null, locked_until = null, updated_at = now(),
+                last_error = coalesce(last_error, 'worker lost')
+            where status = 'running' and locked_until < now()
+            returning status
+            "#,
+        )
+        .fetch_all(&pool)
+        .await;
+
+        match res {
+            Ok(statuses) if !statuses.is_empty() => {
+                let failed = statuses.iter().filter(|s| *s == "failed").count();
+                warn!(requeued = statuses.len() - failed, failed, "reaped stale jobs");
+            }
+            Ok(_) => {}
+            Err(e) => error!(?e, "reap stale jobs failed"),
+        }
+    }
+}
+
+async fn run_job(state: &AppState, job_id: Uuid, kind: &str, payload: serde_json::Value) -> anyhow::Result<()> {
+    match kind {
+        REMOVE_FILE => {
+            let p: RemoveFilePayload = serde_json::from_value(payload)?;
+            crate::storage::remove_file(state, &p.storage_rel_path).await
+        }
+        SWEEP_DOWNLOAD_REQUESTS => crate::sweep_download_requests(&state.pool).await,
+        PURGE_TRASH => crate::trash::purge_expired(state).await,
+        ENFORCE_RETENTION => crate::retention::enforce(state).await,
+        GENERATE_PREVIEWS => {
+            let p: crate::previews::GeneratePayload = serde_json::from_value(payload)?;
+            crate::previews::run_generate(state, p.document_id).await
+        }
+        SCAN_DOCUMENT => {
+            let p: crate::scanner::ScanPayload = serde_json::from_value(payload)?;
+            crate::scanner::run_scan(state, p.document_id).await
+        }
+        BACKFILL_MIME_TYPES => crate::content_types::backfill(state).await,
+        READDRESS_BLOBS => crate::storage::readdress_blobs(state).await,
+        EXPIRE_UPLOAD_SESSIONS => crate::upload_sessions::expire_sessions(state).await,
+        DISPATCH_EVENT => crate::events::dispatch(state, job_id, serde_json::from_value(payload)?).await,
+        SEND_EMAIL => {
+            let email: crate::mailer::OutgoingEmail = serde_json::from_value(payload)?;
+            state.mailer.send(&crate::notifications::render_email(state, email)).await
+        }
+        DELIVER_WEBHOOK => {
+            let p: crate::webhooks::DeliverPayload = serde_json::from_value(payload)?;
+            crate::webhooks::deliver(&state.pool, p.delivery_id).await
+        }
+        other => Err(anyhow::anyhow!("unknown job kind: {other}")),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListJobsQuery {
+    status: Option<String>,
+    kind: Option<String>,
+    limit: Option<i64>,
+}
+
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Query(q): Query<ListJobsQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, JobRow>(
+        r#"
+        select
+            id, kind, payload, status, attempts, max_attempts, run_at, last_error, dedupe_key,
+            locked_by, locked_at, locked_until, created_at, updated_at, finished_at
+        from jobs
+        where ($1::text is null or status = $1)
+          and ($2::text is null or kind = $2)
+        order by created_at desc
+        limit $3
+        "#,
+    )
+    .bind(q.status)
+    .bind(q.kind)
+    .bind(q.limit.unwrap_or(100).clamp(1, 1000))
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let row = sqlx::query_as::<_, JobRow>(
+        r#"
+        select
+            id, kind, payload, status, attempts, max_attempts, run_at, last_error, dedupe_key,
+            locked_by, locked_at, locked_until, created_at, updated_at, finished_at
+        from jobs
+        where id = $1
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match row {
+        Ok(Some(v)) => (StatusCode::OK, Json(v)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetryJobRequest {
+    run_at: Option<DateTime<Utc>>,
+}
+
+pub async fn retry_job(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    body: Option<Json<RetryJobRequest>>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let run_at = body.and_then(|Json(b)| b.run_at).unwrap_or_else(Utc::now);
+
+    let res = sqlx::query(
+        r#"
+        update jobs
+        set status = 'queued', attempts = 0, run_at = $2, locked_by = null, locked_at = null, locked_until = null,
+            updated_at = now(), finished_at = null
+        where id = $1 and status in ('failed','cancelled','queued')
+        "#,
+    )
+    .bind(id)
+    .bind(run_at)
+    .execute(&state.pool)
+    .await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::CONFLICT, "job not retryable").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            if let Some(db_err) = e.as_database_error() {
+                if db_err.constraint() == Some("idx_jobs_dedupe_key_active") {
+                    return (StatusCode::CONFLICT, "job already queued").into_response();
+                }
+            }
+            (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+        }
+    }
+}
+
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let res = sqlx::query(
+        r#"
+        update jobs
+        set status = 'cancelled', locked_by = null, locked_at = null, locked_until = null, updated_at = now(), finished_at = now()
+        where id = $1 and status in ('queued','running')
+        "#,
+    )
+    .bind(id)
+    .execute(&state.pool)
+    .await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::CONFLICT, "job not cancellable").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
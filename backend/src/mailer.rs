@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+const MAIL_FROM_DEFAULT: &str = "xdocs <noreply@xinference.local>";
+const SMTP_PORT_DEFAULT: u16 = 587;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share_link_id: Option<Uuid>,
+}
+
+#[derive(Clone)]
+pub enum MailTransport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(PathBuf),
+    Log,
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    from: Mailbox,
+    transport: MailTransport,
+}
+
+impl Mailer {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let from: Mailbox = std::env::var("MAIL_FROM")
+            .unwrap_or_else(|_| MAIL_FROM_DEFAULT.to_string())
+            .parse()
+            .context("Invalid MAIL_FROM")?;
+
+        let smtp_host = std::env::var("SMTP_HOST").ok().filter(|v| !v.trim().is_empty());
+        let kind = std::env::var("MAIL_TRANSPORT")
+            .unwrap_or_else(|_| if smtp_host.is_some() { "smtp" } else { "log" }.to_string());
+
+        let transport = match kind.as_str() {
+            "smtp" => {
+                let host = smtp_host.context("SMTP_HOST is required for MAIL_TRANSPORT=smtp")?;
+                let port: u16 = std::env::var("SMTP_PORT")
+                    .ok()
+                    .and_then(|v| v.parse::<u16>().ok())
+                    .unwrap_or(SMTP_PORT_DEFAULT);
+                let tls = std::env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string());
+
+                let mut builder = match tls.as_str() {
+                    "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+                    "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+                    "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+                    other => anyhow::bail!("Invalid SMTP_TLS: {other}"),
+                }
+                .port(port);
+
+                let user = std::env::var("SMTP_USERNAME").ok().filter(|v| !v.is_empty());
+                if let Some(user) = user {
+                    let pass = std::env::var("SMTP_PASSWORD").unwrap_or_default();
+                    builder = builder.credentials(Credentials::new(user, pass));
+                }
+                MailTransport::Smtp(builder.build())
+            }
+            "file" => {
+                let dir = std::env::var("MAIL_FILE_DIR").unwrap_or_else(|_| "./data/mail".to_string());
+                MailTransport::File(PathBuf::from(dir))
+            }
+            "log" => MailTransport::Log,
+            other => anyhow::bail!("Invalid MAIL_TRANSPORT: {other}"),
+        };
+
+        Ok(Self { from, transport })
+    }
+
+    pub async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse().context("invalid recipient")?)
+            .subject(&email.subject)
+            .body(email.body.clone())
+            .context("build email")?;
+
+        match &self.transport {
+            MailTransport::Smtp(smtp) => {
+                smtp.send(message).await.context("smtp send")?;
+            }
+            MailTransport::File(dir) => {
+                tokio::fs::create_dir_all(dir).await.context("create mail dir")?;
+                let path = dir.join(format!("{}-{}.eml", chrono::Utc::now().format("%Y%m%dT%H%M%S"), Uuid::new_v4()));
+                tokio::fs::write(&path, message.formatted()).await.context("write email file")?;
+            }
+            MailTransport::Log => {
+                info!(to = %email.to, subject = %email.subject, body = %email.body, "email");
+            }
+        }
+        Ok(())
+    }
+}
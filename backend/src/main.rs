@@ -1,33 +1,74 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use anyhow::Context;
 use argon2::{password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
 use axum::{
     extract::Extension,
     extract::DefaultBodyLimit,
-    extract::{Multipart, Path as AxumPath, State},
+    extract::{Multipart, Path as AxumPath, Query, State},
     http::{HeaderValue, StatusCode},
     middleware,
     response::IntoResponse,
-    routing::{delete, get, patch, post},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use chrono::{DateTime, Utc};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sqlx::{postgres::PgPoolOptions, PgConnection, PgExecutor, PgPool};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::{error, info};
 use uuid::Uuid;
 
+mod approvals;
+mod archive;
+mod content_types;
+mod events;
+mod groups;
+mod jobs;
+mod mailer;
+mod metadata;
+mod notifications;
+mod portal;
+mod previews;
+mod quotas;
+mod realtime;
+mod retention;
+mod scanner;
+mod share_links;
+mod storage;
+mod trash;
+mod upload_sessions;
+mod uploads;
+mod watermark;
+mod webhooks;
+
 const DOWNLOAD_APPROVAL_TTL_HOURS_DEFAULT: i64 = 24;
+const DOWNLOAD_APPROVAL_TTL_HOURS_MAX: i64 = 24 * 365;
+const DOWNLOAD_BATCH_MAX: usize = 500;
+const DOWNLOAD_PENDING_SLA_HOURS_DEFAULT: i32 = 72;
+const DOWNLOAD_EXPIRY_WARNING_HOURS_DEFAULT: i32 = 2;
+const DOWNLOAD_SWEEP_INTERVAL_SECS_DEFAULT: u64 = 60;
+const TRASH_PURGE_INTERVAL_SECS_DEFAULT: u64 = 3600;
+const RETENTION_SWEEP_INTERVAL_SECS_DEFAULT: u64 = 3600;
+const UPLOAD_SWEEP_INTERVAL_SECS_DEFAULT: u64 = 3600;
 
 #[derive(Clone)]
 struct AppState {
     pool: PgPool,
     jwt: JwtKeys,
     storage_root: PathBuf,
+    mailer: mailer::Mailer,
+    realtime: tokio::sync::broadcast::Sender<Arc<realtime::PushMessage>>,
+    share_secret: String,
+    keyring: storage::Keyring,
+    scanner: scanner::Scanner,
 }
 
 #[derive(Debug, Serialize, sqlx::FromRow)]
@@ -72,7 +113,10 @@ async fn approve_user(
 
     match res {
         Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Ok(_) => {
+            publish_event(&state.pool, events::Event::UserApproved { user_id: id }).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     }
 }
@@ -93,7 +137,10 @@ async fn disable_user(
 
     match res {
         Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Ok(_) => {
+            publish_event(&state.pool, events::Event::UserDisabled { user_id: id }).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     }
 }
@@ -152,6 +199,7 @@ struct LoginRequest {
 struct RegisterRequest {
     username: String,
     password: String,
+    email: Option<String>,
     note: Option<String>,
 }
 
@@ -190,11 +238,35 @@ struct DocumentRow {
     allowed_users: Vec<Uuid>,
     is_generated: bool,
     download_preauthorized: bool,
+    view_requires_approval: bool,
+    requestable: bool,
+    watermark: bool,
+    folder: String,
+    approval_policy_id: Option<Uuid>,
+    retention_policy_id: Option<Uuid>,
+    legal_hold: bool,
+    scan_status: String,
     storage_rel_path: String,
+    sha256: Option<String>,
+    encryption_key_id: Option<String>,
+    #[serde(skip)]
+    wrapped_data_key: Option<Vec<u8>>,
+    deleted_at: Option<DateTime<Utc>>,
+    deleted_by: Option<Uuid>,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
 }
 
+const DOCUMENT_COLUMNS: &str = r#"
+    d.id, d.name, d.mime_type, d.size, d.notes,
+    d.owner_id, u.username as owner_name,
+    d.permission, d.allowed_users, d.is_generated, d.download_preauthorized, d.view_requires_approval, d.requestable,
+    d.watermark, d.folder, d.approval_policy_id, d.retention_policy_id, d.legal_hold, d.scan_status,
+    d.storage_rel_path,
+    d.sha256, d.encryption_key_id, d.wrapped_data_key, d.deleted_at, d.deleted_by,
+    d.created_at, d.updated_at
+"#;
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct DocumentDto {
@@ -209,6 +281,17 @@ struct DocumentDto {
     allowed_users: Vec<Uuid>,
     is_generated: bool,
     download_preauthorized: bool,
+    view_requires_approval: bool,
+    requestable: bool,
+    watermark: bool,
+    folder: String,
+    approval_policy_id: Option<Uuid>,
+    retention_policy_id: Option<Uuid>,
+    legal_hold: bool,
+    scan_status: String,
+    sha256: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+    deleted_by: Option<Uuid>,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
 }
@@ -227,8 +310,21 @@ struct DocumentApiDto {
     allowed_users: Vec<Uuid>,
     is_generated: bool,
     download_preauthorized: bool,
+    view_requires_approval: bool,
+    requestable: bool,
+    watermark: bool,
+    folder: String,
+    approval_policy_id: Option<Uuid>,
+    retention_policy_id: Option<Uuid>,
+    legal_hold: bool,
+    scan_status: String,
+    sha256: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+    deleted_by: Option<Uuid>,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
+    tags: Vec<String>,
+    metadata: serde_json::Map<String, serde_json::Value>,
 }
 
 impl From<DocumentDto> for DocumentApiDto {
@@ -245,12 +341,54 @@ impl From<DocumentDto> for DocumentApiDto {
             allowed_users: d.allowed_users,
             is_generated: d.is_generated,
             download_preauthorized: d.download_preauthorized,
+            view_requires_approval: d.view_requires_approval,
+            requestable: d.requestable,
+            watermark: d.watermark,
+            folder: d.folder,
+            approval_policy_id: d.approval_policy_id,
+            retention_policy_id: d.retention_policy_id,
+            legal_hold: d.legal_hold,
+            scan_status: d.scan_status,
+            sha256: d.sha256,
+            deleted_at: d.deleted_at,
+            deleted_by: d.deleted_by,
             created_at: d.created_at,
             updated_at: d.updated_at,
+            tags: vec![],
+            metadata: serde_json::Map::new(),
+        }
+    }
+}
+
+impl DocumentApiDto {
+    fn with_labels(self, labels: metadata::Labels) -> Self {
+        Self {
+            tags: labels.tags,
+            metadata: labels.metadata,
+            ..self
         }
     }
 }
 
+async fn document_api_dto<'e>(exec: impl PgExecutor<'e>, doc: DocumentRow) -> sqlx::Result<DocumentApiDto> {
+    let mut labels = metadata::load_labels(exec, &[doc.id]).await?;
+    let doc_labels = labels.remove(&doc.id).unwrap_or_default();
+    Ok(DocumentApiDto::from(DocumentDto::from(doc)).with_labels(doc_labels))
+}
+
+// Builds API documents with their tags and metadata, which only the API representation carries.
+async fn document_api_dtos<'e>(exec: impl PgExecutor<'e>, rows: Vec<DocumentRow>) -> sqlx::Result<Vec<DocumentApiDto>> {
+    let ids: Vec<Uuid> = rows.iter().map(|d| d.id).collect();
+    let mut labels = metadata::load_labels(exec, &ids).await?;
+    Ok(rows
+        .into_iter()
+        .map(|d| {
+            let doc_labels = labels.remove(&d.id).unwrap_or_default();
+            DocumentApiDto::from(DocumentDto::from(d)).with_labels(doc_labels)
+        })
+        .collect())
+}
+
 impl From<DocumentRow> for DocumentDto {
     fn from(r: DocumentRow) -> Self {
         Self {
@@ -265,6 +403,17 @@ impl From<DocumentRow> for DocumentDto {
             allowed_users: r.allowed_users,
             is_generated: r.is_generated,
             download_preauthorized: r.download_preauthorized,
+            view_requires_approval: r.view_requires_approval,
+            requestable: r.requestable,
+            watermark: r.watermark,
+            folder: r.folder,
+            approval_policy_id: r.approval_policy_id,
+            retention_policy_id: r.retention_policy_id,
+            legal_hold: r.legal_hold,
+            scan_status: r.scan_status,
+            sha256: r.sha256,
+            deleted_at: r.deleted_at,
+            deleted_by: r.deleted_by,
             created_at: r.created_at,
             updated_at: r.updated_at,
         }
@@ -276,8 +425,9 @@ impl From<DocumentRow> for DocumentDto {
 struct DownloadRequestDto {
     id: Uuid,
     document_id: Uuid,
-    requester_id: Uuid,
+    requester_id: Option<Uuid>,
     requester_name: String,
+    external_email: Option<String>,
     document_name: String,
     owner_id: Uuid,
     owner_name: String,
@@ -288,11 +438,52 @@ struct DownloadRequestDto {
     status: String,
     approver_id: Option<Uuid>,
     approver_name: Option<String>,
+    approver_note: String,
+    reject_reason: String,
+    max_downloads: Option<i32>,
+    downloads_remaining: Option<i32>,
+    download_count: i32,
+    approval_policy_id: Option<Uuid>,
+    approval_stage: i32,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
     expires_at: Option<DateTime<Utc>>,
 }
 
+const DOWNLOAD_REQUEST_SELECT: &str = r#"
+    select
+        r.id,
+        r.document_id,
+        r.requester_id,
+        coalesce(ru.username, r.external_email) as requester_name,
+        r.external_email,
+        d.name as document_name,
+        d.owner_id,
+        ou.username as owner_name,
+        r.applicant_name,
+        r.applicant_company,
+        r.applicant_contact,
+        r.message,
+        r.status,
+        r.approver_id,
+        au.username as approver_name,
+        r.approver_note,
+        r.reject_reason,
+        r.max_downloads,
+        r.downloads_remaining,
+        r.download_count,
+        r.approval_policy_id,
+        r.approval_stage,
+        r.created_at,
+        r.updated_at,
+        r.expires_at
+    from download_requests r
+    join documents d on d.id = r.document_id
+    left join users ru on ru.id = r.requester_id
+    join users ou on ou.id = d.owner_id
+    left join users au on au.id = r.approver_id
+"#;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct CreateDownloadRequest {
     applicant_name: String,
@@ -309,6 +500,10 @@ async fn main() -> anyhow::Result<()> {
 
     let database_url = std::env::var("DATABASE_URL").context("DATABASE_URL is required")?;
     let jwt_secret = std::env::var("JWT_SECRET").context("JWT_SECRET is required")?;
+    let share_secret = std::env::var("SHARE_LINK_SECRET")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .context("SHARE_LINK_SECRET is required")?;
     let storage_root = std::env::var("STORAGE_ROOT").unwrap_or_else(|_| "../data/documents".to_string());
     let addr: SocketAddr = std::env::var("BIND_ADDR")
         .unwrap_or_else(|_| "127.0.0.1:8752".to_string())
@@ -323,8 +518,16 @@ async fn main() -> anyhow::Result<()> {
 
     sqlx::migrate!().run(&pool).await.context("Migration failed")?;
 
+    let keyring = storage::Keyring::from_env()?;
+    if std::env::args().nth(1).as_deref() == Some("rotate-keys") {
+        return storage::rotate_keys(&pool, &keyring).await;
+    }
+
     ensure_default_admin(&pool).await?;
 
+    let mailer = mailer::Mailer::from_env()?;
+    let scanner = scanner::Scanner::from_env()?;
+
     let state = AppState {
         pool,
         jwt: JwtKeys {
@@ -332,10 +535,51 @@ async fn main() -> anyhow::Result<()> {
             decoding: DecodingKey::from_secret(jwt_secret.as_bytes()),
         },
         storage_root: PathBuf::from(storage_root),
+        mailer,
+        realtime: realtime::channel(),
+        share_secret,
+        keyring,
+        scanner,
     };
 
     tokio::fs::create_dir_all(&state.storage_root).await.ok();
 
+    jobs::spawn_workers(&state);
+    realtime::spawn_listener(&state);
+
+    let sweep_secs: u64 = std::env::var("DOWNLOAD_SWEEP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DOWNLOAD_SWEEP_INTERVAL_SECS_DEFAULT);
+    jobs::spawn_periodic(
+        &state,
+        jobs::SWEEP_DOWNLOAD_REQUESTS,
+        std::time::Duration::from_secs(sweep_secs.max(1)),
+    );
+    let purge_secs: u64 = std::env::var("TRASH_PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(TRASH_PURGE_INTERVAL_SECS_DEFAULT);
+    jobs::spawn_periodic(&state, jobs::PURGE_TRASH, std::time::Duration::from_secs(purge_secs.max(1)));
+    let retention_secs: u64 = std::env::var("RETENTION_SWEEP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(RETENTION_SWEEP_INTERVAL_SECS_DEFAULT);
+    jobs::spawn_periodic(
+        &state,
+        jobs::ENFORCE_RETENTION,
+        std::time::Duration::from_secs(retention_secs.max(1)),
+    );
+    let upload_sweep_secs: u64 = std::env::var("UPLOAD_SWEEP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(UPLOAD_SWEEP_INTERVAL_SECS_DEFAULT);
+    jobs::spawn_periodic(
+        &state,
+        jobs::EXPIRE_UPLOAD_SESSIONS,
+        std::time::Duration::from_secs(upload_sweep_secs.max(1)),
+    );
+
     let cors = CorsLayer::new()
         .allow_origin([
             "http://localhost:5173".parse::<HeaderValue>().unwrap(),
@@ -354,19 +598,99 @@ async fn main() -> anyhow::Result<()> {
         .route("/auth/register", post(register))
         .route("/user-directory", get(list_user_directory))
         .route("/me", get(me))
+        .route("/me/storage", get(quotas::my_usage))
+        .route("/ws", get(realtime::ws_handler))
+        .route("/ws/tickets", post(realtime::create_ticket))
+        .route(
+            "/me/notification-preferences",
+            get(notifications::get_preferences).put(notifications::update_preferences),
+        )
+        .route("/notifications", get(notifications::list_notifications))
+        .route("/notifications/unread-count", get(notifications::unread_count))
+        .route("/notifications/read-all", post(notifications::mark_all_read))
+        .route("/notifications/{id}/read", post(notifications::mark_read))
         .route("/users", get(list_users).post(create_user))
         .route("/users/{id}", delete(delete_user))
         .route("/users/pending", get(list_pending_users))
         .route("/users/{id}/approve", post(approve_user))
         .route("/users/{id}/disable", post(disable_user))
+        .route("/users/{id}/storage-quota", put(quotas::set_user_quota))
         .route("/documents", get(list_documents).post(upload_document))
+        .route("/documents/archive", post(archive::download_archive))
+        .route("/uploads", get(upload_sessions::list_uploads).post(upload_sessions::create_upload))
+        .route(
+            "/uploads/{id}",
+            get(upload_sessions::get_upload)
+                .patch(upload_sessions::patch_upload)
+                .delete(upload_sessions::delete_upload),
+        )
+        .route("/uploads/{id}/finalize", post(upload_sessions::finalize_upload))
         .route("/documents/{id}", patch(patch_document).delete(delete_document))
         .route("/documents/{id}/download-requests", post(create_download_request))
         .route("/documents/{id}/download", get(download_document))
+        .route("/documents/{id}/view", get(view_document))
+        .route("/documents/{id}/preview", get(previews::preview))
+        .route("/documents/{id}/thumbnail", get(previews::thumbnail))
+        .route("/documents/{id}/rescan", post(scanner::rescan_document))
+        .route("/tags", get(metadata::list_tags))
+        .route("/metadata-fields", get(metadata::list_fields).post(metadata::create_field))
+        .route("/metadata-fields/{id}", put(metadata::update_field).delete(metadata::delete_field))
+        .route("/trash", get(trash::list_trash))
+        .route("/trash/{id}/restore", post(trash::restore_document))
+        .route(
+            "/documents/{id}/share-links",
+            get(share_links::list_share_links).post(share_links::create_share_link),
+        )
+        .route("/share-links/{id}/revoke", post(share_links::revoke_share_link))
+        .route("/share-links/{id}/accesses", get(share_links::list_accesses))
+        .route("/public/documents/{id}", get(portal::get_document))
+        .route("/public/documents/{id}/download-requests", post(portal::start_request))
+        .route("/public/verifications/{id}/confirm", post(portal::confirm_request))
+        .route("/s/{token}", get(share_links::share_info))
+        .route("/s/{token}/download", get(share_links::share_download).post(share_links::share_download_form))
+        .route("/s/{token}/view", get(share_links::share_view).post(share_links::share_view_form))
         .route("/download-requests/mine", get(list_my_download_requests))
         .route("/download-requests/pending", get(list_pending_download_requests))
         .route("/download-requests/{id}/approve", post(approve_download_request))
         .route("/download-requests/{id}/reject", post(reject_download_request))
+        .route("/download-requests/{id}/cancel", post(cancel_download_request))
+        .route("/download-requests/batch/approve", post(batch_approve_download_requests))
+        .route("/download-requests/batch/reject", post(batch_reject_download_requests))
+        .route("/download-requests/{id}/decisions", get(approvals::list_decisions))
+        .route("/documents/{id}/approval-policy", put(approvals::assign_document_policy))
+        .route("/approval-policies", get(approvals::list_policies).post(approvals::create_policy))
+        .route("/approval-policies/{id}", put(approvals::update_policy).delete(approvals::delete_policy))
+        .route("/folder-approval-policies", get(approvals::list_folder_policies).put(approvals::assign_folder_policy))
+        .route("/documents/{id}/retention", get(retention::get_document_retention))
+        .route("/documents/{id}/retention-policy", put(retention::assign_document_policy))
+        .route(
+            "/documents/{id}/legal-hold",
+            post(retention::place_legal_hold).delete(retention::release_legal_hold),
+        )
+        .route("/retention-policies", get(retention::list_policies).post(retention::create_policy))
+        .route("/retention-policies/{id}", put(retention::update_policy).delete(retention::delete_policy))
+        .route(
+            "/folder-retention-policies",
+            get(retention::list_folder_policies).put(retention::assign_folder_policy),
+        )
+        .route("/admin/retention/audit", get(retention::list_audit))
+        .route("/groups", get(groups::list_groups).post(groups::create_group))
+        .route("/groups/{id}", delete(groups::delete_group))
+        .route("/groups/{id}/members", put(groups::set_group_members))
+        .route("/groups/{id}/storage-quota", put(quotas::set_group_quota))
+        .route("/admin/webhooks", get(webhooks::list_webhooks).post(webhooks::create_webhook))
+        .route("/admin/webhooks/{id}", put(webhooks::update_webhook).delete(webhooks::delete_webhook))
+        .route("/admin/webhooks/{id}/deliveries", get(webhooks::list_deliveries))
+        .route("/admin/webhooks/{id}/test", post(webhooks::test_webhook))
+        .route("/admin/webhook-deliveries/{id}/redeliver", post(webhooks::redeliver))
+        .route("/admin/storage/fsck", get(storage::fsck))
+        .route("/admin/storage/usage", get(quotas::usage_report))
+        .route("/admin/storage/usage/recompute", post(quotas::recompute_usage))
+        .route("/admin/scans/rescan", post(scanner::rescan))
+        .route("/admin/jobs", get(jobs::list_jobs))
+        .route("/admin/jobs/{id}", get(jobs::get_job))
+        .route("/admin/jobs/{id}/retry", post(jobs::retry_job))
+        .route("/admin/jobs/{id}/cancel", post(jobs::cancel_job))
         .layer(DefaultBodyLimit::max(200 * 1024 * 1024))
         .layer(TraceLayer::new_for_http())
         .layer(cors)
@@ -374,7 +698,11 @@ async fn main() -> anyhow::Result<()> {
         .with_state(state);
 
     info!("listening on {addr}");
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, app).await?;
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
@@ -401,43 +729,82 @@ async fn auth_middleware(
     if path == "/healthz" || path == "/auth/login" || path == "/auth/register" {
         return next.run(req).await;
     }
+    if share_links::is_public_request(req.method(), path) || portal::is_public_request(req.method(), path) {
+        return next.run(req).await;
+    }
+    // The socket authenticates with a single-use ticket from /ws/tickets instead of the bearer token.
+    if path == "/ws" {
+        return next.run(req).await;
+    }
 
-    let Some(auth_header) = req.headers().get(axum::http::header::AUTHORIZATION) else {
+    let token = if let Some(auth_header) = req.headers().get(axum::http::header::AUTHORIZATION) {
+        let Ok(auth_str) = auth_header.to_str() else {
+            return (StatusCode::UNAUTHORIZED, "invalid authorization").into_response();
+        };
+        auth_str.strip_prefix("Bearer ").unwrap_or("").to_string()
+    } else {
         return (StatusCode::UNAUTHORIZED, "missing authorization").into_response();
     };
 
-    let Ok(auth_str) = auth_header.to_str() else {
-        return (StatusCode::UNAUTHORIZED, "invalid authorization").into_response();
-    };
-
-    let token = auth_str.strip_prefix("Bearer ").unwrap_or("");
     if token.is_empty() {
         return (StatusCode::UNAUTHORIZED, "invalid bearer token").into_response();
     }
 
-    let validation = Validation::default();
-    let decoded = match jsonwebtoken::decode::<Claims>(token, &state.jwt.decoding, &validation) {
-        Ok(d) => d,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "invalid token").into_response(),
-    };
-
-    let user_id = match Uuid::parse_str(&decoded.claims.sub) {
+    let authed = match authenticate_token(&state, &token) {
         Ok(v) => v,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "invalid token subject").into_response(),
+        Err(msg) => return (StatusCode::UNAUTHORIZED, msg).into_response(),
     };
 
-    req.extensions_mut().insert(AuthedUser {
-        id: user_id,
-        role: decoded.claims.role,
-    });
+    req.extensions_mut().insert(authed);
 
     next.run(req).await
 }
 
+fn authenticate_token(state: &AppState, token: &str) -> Result<AuthedUser, &'static str> {
+    let validation = Validation::default();
+    let decoded = jsonwebtoken::decode::<Claims>(token, &state.jwt.decoding, &validation).map_err(|_| "invalid token")?;
+    let user_id = Uuid::parse_str(&decoded.claims.sub).map_err(|_| "invalid token subject")?;
+    Ok(AuthedUser {
+        id: user_id,
+        role: decoded.claims.role,
+    })
+}
+
 fn is_admin(user: &AuthedUser) -> bool {
     user.role == "admin"
 }
 
+// The visitor's address. X-Forwarded-For is only believed when the peer is one of TRUSTED_PROXIES, and then
+// only back to the first hop that is not itself a trusted proxy.
+fn client_ip(peer: SocketAddr, headers: &axum::http::HeaderMap) -> IpAddr {
+    let trusted: Vec<IpAddr> = std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|v| v.trim().parse().ok())
+        .collect();
+    let mut ip = peer.ip();
+    if !trusted.contains(&ip) {
+        return ip;
+    }
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in hops.into_iter().rev() {
+        let Ok(hop) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        ip = hop;
+        if !trusted.contains(&ip) {
+            break;
+        }
+    }
+    ip
+}
+
 async fn ensure_default_admin(pool: &PgPool) -> anyhow::Result<()> {
     let email = std::env::var("DEFAULT_ADMIN_EMAIL").unwrap_or_else(|_| "admin@xinference.local".to_string());
     let username = std::env::var("DEFAULT_ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string());
@@ -564,12 +931,18 @@ async fn register(State(state): State<AppState>, Json(req): Json<RegisterRequest
 
     let id = Uuid::new_v4();
     let note = req.note.unwrap_or_default();
+    let email = req.email.map(|e| e.trim().to_string()).filter(|e| !e.is_empty());
+
+    if email.as_deref().is_some_and(|e| !e.contains('@')) {
+        return (StatusCode::BAD_REQUEST, "invalid email").into_response();
+    }
 
     let res = sqlx::query(
-        "insert into users (id, username, email, password_hash, role, status, note) values ($1,$2,null,$3,'user','pending',$4)",
+        "insert into users (id, username, email, password_hash, role, status, note) values ($1,$2,$3,$4,'user','pending',$5)",
     )
     .bind(id)
     .bind(req.username.trim())
+    .bind(email)
     .bind(&password_hash)
     .bind(note)
     .execute(&state.pool)
@@ -583,10 +956,15 @@ async fn register(State(state): State<AppState>, Json(req): Json<RegisterRequest
             if db_err.constraint() == Some("users_username_key") {
                 return (StatusCode::CONFLICT, "username exists").into_response();
             }
+            if db_err.constraint() == Some("idx_users_email_unique_not_null") {
+                return (StatusCode::CONFLICT, "email exists").into_response();
+            }
         }
         return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
     }
 
+    publish_event(&state.pool, events::Event::UserRegistered { user_id: id }).await;
+
     StatusCode::CREATED.into_response()
 }
 
@@ -723,7 +1101,7 @@ fn doc_accessible(doc: &DocumentRow, user: &AuthedUser) -> bool {
     if doc.permission == "public" {
         return true;
     }
-    if doc.permission == "specific" && doc.allowed_users.iter().any(|u| *u == user.id) {
+    if doc.permission == "specific" && doc.allowed_users.contains(&user.id) {
         return true;
     }
     false
@@ -736,20 +1114,34 @@ fn doc_editable(doc: &DocumentRow, user: &AuthedUser) -> bool {
     doc.owner_id == user.id
 }
 
-async fn list_documents(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+async fn list_documents(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Query(query): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let filter = match metadata::parse_filter(&state.pool, &query).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
 
-    let rows = sqlx::query_as::<_, DocumentRow>(
+    let rows = sqlx::query_as::<_, DocumentRow>(&format!(
         r#"
-        select
-            d.id, d.name, d.mime_type, d.size, d.notes,
-            d.owner_id, u.username as owner_name,
-            d.permission, d.allowed_users, d.is_generated, d.download_preauthorized, d.storage_rel_path,
-            d.created_at, d.updated_at
-        from documents d
-        join users u on u.id = d.owner_id
+        select {DOCUMENT_COLUMNS}
+        from documents d join users u on u.id = d.owner_id
+        where d.deleted_at is null
+            and ($5 or d.owner_id = $6 or d.permission = 'public' or (d.permission = 'specific' and $6 = any(d.allowed_users)))
+            and {}
         order by d.created_at desc
         "#,
-    )
+        metadata::DOCUMENT_FILTER
+    ))
+    .bind(&filter.tags)
+    .bind(&filter.field_ids)
+    .bind(&filter.ops)
+    .bind(&filter.values)
+    .bind(is_admin(&authed))
+    .bind(authed.id)
     .fetch_all(&state.pool)
     .await;
 
@@ -758,121 +1150,312 @@ async fn list_documents(State(state): State<AppState>, Extension(authed): Extens
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     };
 
-    let docs: Vec<DocumentApiDto> = rows
-        .into_iter()
-        .filter(|d| doc_accessible(d, &authed))
-        .map(DocumentDto::from)
-        .map(DocumentApiDto::from)
-        .collect();
+    match document_api_dtos(&state.pool, rows).await {
+        Ok(docs) => (StatusCode::OK, Json(docs)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+struct UploadSettings {
+    notes: String,
+    permission: String,
+    allowed_users: Vec<Uuid>,
+    is_generated: bool,
+    requestable: bool,
+    watermark: bool,
+    view_requires_approval: bool,
+}
 
-    (StatusCode::OK, Json(docs)).into_response()
+// Where an uploaded file lands and what it turned out to be.
+struct UploadedFile<'a> {
+    folder: &'a str,
+    name: &'a str,
+    mime_type: &'a str,
 }
 
 async fn upload_document(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>, mut multipart: Multipart) -> impl IntoResponse {
 
-    let mut notes: String = String::new();
-    let mut permission: String = "public".to_string();
-    let mut allowed_users: Vec<Uuid> = vec![];
-    let mut is_generated: bool = false;
-    let mut file_name: Option<String> = None;
-    let mut mime_type: Option<String> = None;
-    let mut file_bytes: Option<Vec<u8>> = None;
+    let mut settings = UploadSettings {
+        notes: String::new(),
+        permission: "public".to_string(),
+        allowed_users: vec![],
+        is_generated: false,
+        requestable: false,
+        watermark: false,
+        view_requires_approval: false,
+    };
+    let mut folder: String = String::new();
+    let mut unpack_zip: bool = false;
+    let mut files: Vec<(String, Option<String>, Vec<u8>)> = vec![];
 
     while let Ok(Some(field)) = multipart.next_field().await {
         let name = field.name().unwrap_or("").to_string();
-        if name == "file" {
-            file_name = field.file_name().map(|s| s.to_string());
-            mime_type = field.content_type().map(|s| s.to_string());
+        if name == "file" || name == "files" {
+            let file_name = field.file_name().map(|s| s.to_string()).unwrap_or_else(|| "upload.bin".to_string());
+            let content_type = field.content_type().map(|s| s.to_string());
             match field.bytes().await {
-                Ok(b) => file_bytes = Some(b.to_vec()),
+                Ok(b) => files.push((file_name, content_type, b.to_vec())),
                 Err(_) => return (StatusCode::BAD_REQUEST, "invalid file").into_response(),
             }
         } else if name == "notes" {
-            notes = field.text().await.unwrap_or_default();
+            settings.notes = field.text().await.unwrap_or_default();
         } else if name == "permission" {
-            permission = field.text().await.unwrap_or_else(|_| "public".to_string());
+            settings.permission = field.text().await.unwrap_or_else(|_| "public".to_string());
         } else if name == "allowed_users" {
             let txt = field.text().await.unwrap_or_default();
-            allowed_users = txt
+            settings.allowed_users = txt
                 .split(',')
                 .filter_map(|s| Uuid::parse_str(s.trim()).ok())
                 .collect();
         } else if name == "is_generated" {
             let txt = field.text().await.unwrap_or_default();
-            is_generated = txt.trim() == "1" || txt.trim().eq_ignore_ascii_case("true");
+            settings.is_generated = txt.trim() == "1" || txt.trim().eq_ignore_ascii_case("true");
+        } else if name == "requestable" {
+            let txt = field.text().await.unwrap_or_default();
+            settings.requestable = txt.trim() == "1" || txt.trim().eq_ignore_ascii_case("true");
+        } else if name == "watermark" {
+            let txt = field.text().await.unwrap_or_default();
+            settings.watermark = txt.trim() == "1" || txt.trim().eq_ignore_ascii_case("true");
+        } else if name == "view_requires_approval" {
+            let txt = field.text().await.unwrap_or_default();
+            settings.view_requires_approval = txt.trim() == "1" || txt.trim().eq_ignore_ascii_case("true");
+        } else if name == "unpack_zip" {
+            let txt = field.text().await.unwrap_or_default();
+            unpack_zip = txt.trim() == "1" || txt.trim().eq_ignore_ascii_case("true");
+        } else if name == "folder" {
+            folder = field.text().await.unwrap_or_default();
         }
     }
 
-    if permission != "public" && permission != "private" && permission != "specific" {
+    let Some(folder) = normalize_folder(&folder) else {
+        return (StatusCode::BAD_REQUEST, "invalid folder").into_response();
+    };
+
+    if settings.permission != "public" && settings.permission != "private" && settings.permission != "specific" {
         return (StatusCode::BAD_REQUEST, "invalid permission").into_response();
     }
-    if permission != "specific" {
-        allowed_users.clear();
+    if settings.permission != "specific" {
+        settings.allowed_users.clear();
+    }
+
+    if files.is_empty() {
+        return (StatusCode::BAD_REQUEST, "file is required").into_response();
+    }
+
+    if files.len() == 1 && !unpack_zip {
+        let (file_name, _, file_bytes) = files.remove(0);
+        return match store_document(&state, &authed, &settings, &folder, &file_name, &file_bytes).await {
+            Ok(doc) => {
+                let api = DocumentApiDto::from(DocumentDto::from(doc));
+                (StatusCode::CREATED, Json(api)).into_response()
+            }
+            Err((status, msg)) => (status, msg).into_response(),
+        };
+    }
+
+    let mut results = vec![];
+    for (file_name, content_type, data) in files {
+        if !(unpack_zip && uploads::is_zip_archive(&file_name, content_type.as_deref(), &data)) {
+            let item = uploads::UploadItem {
+                source: file_name.clone(),
+                folder: folder.clone(),
+                name: file_name,
+                data: Ok(data),
+            };
+            results.push(store_upload_item(&state, &authed, &settings, item).await);
+            continue;
+        }
+
+        // Entries are stored as they come out of the archive, so at most a couple are in memory at once.
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let (archive_name, base) = (file_name.clone(), folder.clone());
+        let unpacking = tokio::task::spawn_blocking(move || {
+            uploads::unpack_zip(&archive_name, &data, &base, |item| {
+                let _ = tx.blocking_send(item);
+            })
+        });
+        while let Some(item) = rx.recv().await {
+            results.push(store_upload_item(&state, &authed, &settings, item).await);
+        }
+        if unpacking.await.is_err() {
+            let item = uploads::UploadItem {
+                source: file_name.clone(),
+                folder: folder.clone(),
+                name: file_name,
+                data: Err("unpack failed".to_string()),
+            };
+            results.push(store_upload_item(&state, &authed, &settings, item).await);
+        }
     }
 
-    let file_name = file_name.unwrap_or_else(|| "upload.bin".to_string());
-    let mime_type = mime_type.unwrap_or_else(|| "application/octet-stream".to_string());
-    let file_bytes = match file_bytes {
-        Some(v) => v,
-        None => return (StatusCode::BAD_REQUEST, "file is required").into_response(),
+    let created = results.iter().filter(|r| r.document.is_some()).count();
+    let failed = results.len() - created;
+    let status = if failed == 0 { StatusCode::CREATED } else { StatusCode::MULTI_STATUS };
+    (status, Json(uploads::BulkUploadResponse { created, failed, results })).into_response()
+}
+
+async fn store_upload_item(state: &AppState, authed: &AuthedUser, settings: &UploadSettings, item: uploads::UploadItem) -> uploads::UploadResult {
+    let outcome = match &item.data {
+        Ok(data) => store_document(state, authed, settings, &item.folder, &item.name, data)
+            .await
+            .map_err(|(_, msg)| msg.to_string()),
+        Err(e) => Err(e.clone()),
+    };
+    let (status, document, error) = match outcome {
+        Ok(doc) => ("created", Some(DocumentApiDto::from(DocumentDto::from(doc))), None),
+        Err(e) => ("failed", None, Some(e)),
     };
+    uploads::UploadResult {
+        source: item.source,
+        folder: item.folder,
+        name: item.name,
+        status,
+        document,
+        error,
+    }
+}
 
-    let doc_id = Uuid::new_v4();
-    let rel_path = format!("{}/{}", doc_id, sanitize_filename(&file_name));
-    let abs_path = state.storage_root.join(&rel_path);
+// Stores one file as a new document in its own transaction, so a failure never affects other uploads.
+async fn store_document(
+    state: &AppState,
+    authed: &AuthedUser,
+    settings: &UploadSettings,
+    folder: &str,
+    file_name: &str,
+    file_bytes: &[u8],
+) -> Result<DocumentRow, (StatusCode, &'static str)> {
+    let mime_type = content_types::inspect(file_name, file_bytes, file_bytes.len() as u64)?;
+    if let Err(msg) = quotas::check_free_space(&state.storage_root, file_bytes.len() as u64) {
+        return Err((StatusCode::INSUFFICIENT_STORAGE, msg));
+    }
+    let staged = match storage::stage_blob(state, file_bytes).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!(?e, "store document blob failed");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "storage error"));
+        }
+    };
 
-    if let Some(parent) = abs_path.parent() {
-        if tokio::fs::create_dir_all(parent).await.is_err() {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => {
+            staged.discard().await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "db error"));
         }
+    };
+    let file = UploadedFile {
+        folder,
+        name: file_name,
+        mime_type: &mime_type,
+    };
+    let (doc, blob) = insert_document(&mut tx, state, authed, settings, &file, staged).await?;
+    if let Err(e) = tx.commit().await {
+        error!(?e, "insert document failed");
+        storage::discard(state, &blob).await;
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "db error"));
     }
+    announce_document(state, &doc).await;
+    Ok(doc)
+}
 
-    if tokio::fs::write(&abs_path, &file_bytes).await.is_err() {
-        return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+// Inserts the document for a staged blob within the caller's transaction. The caller announces it once
+// committed, or discards the returned blob if the commit fails.
+async fn insert_document(
+    conn: &mut PgConnection,
+    state: &AppState,
+    authed: &AuthedUser,
+    settings: &UploadSettings,
+    file: &UploadedFile<'_>,
+    staged: storage::StagedBlob,
+) -> Result<(DocumentRow, storage::Blob), (StatusCode, &'static str)> {
+    let doc_id = Uuid::new_v4();
+    let size = staged.size() as i64;
+
+    match quotas::reserve(&mut *conn, authed.id, size).await {
+        Ok(Ok(())) => {}
+        Ok(Err(msg)) => {
+            staged.discard().await;
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, msg));
+        }
+        Err(_) => {
+            staged.discard().await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "db error"));
+        }
     }
 
-    let size = file_bytes.len() as i64;
+    let blob = match storage::commit_blob(&mut *conn, state, staged).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!(?e, "store document blob failed");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "storage error"));
+        }
+    };
 
-    let inserted = sqlx::query_as::<_, DocumentRow>(
+    let inserted = sqlx::query_as::<_, DocumentRow>(&format!(
         r#"
-        insert into documents
-            (id, name, mime_type, size, notes, owner_id, permission, allowed_users, is_generated, download_preauthorized, storage_rel_path)
-        values
-            ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)
-        returning
-            id, name, mime_type, size, notes,
-            owner_id, (select username from users where id = owner_id) as owner_name,
-            permission, allowed_users, is_generated, download_preauthorized, storage_rel_path,
-            created_at, updated_at
-        "#,
-    )
+        with d as (
+            insert into documents
+                (id, name, mime_type, size, notes, owner_id, permission, allowed_users, is_generated, download_preauthorized, storage_rel_path, folder, requestable, watermark,
+                 encryption_key_id, wrapped_data_key, sha256, view_requires_approval, scan_status)
+            values
+                ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16,$17,$18,$19)
+            returning *
+        )
+        select {DOCUMENT_COLUMNS} from d join users u on u.id = d.owner_id
+        "#
+    ))
     .bind(doc_id)
-    .bind(&file_name)
-    .bind(&mime_type)
+    .bind(file.name)
+    .bind(file.mime_type)
     .bind(size)
-    .bind(&notes)
+    .bind(&settings.notes)
     .bind(authed.id)
-    .bind(&permission)
-    .bind(&allowed_users)
-    .bind(is_generated)
+    .bind(&settings.permission)
+    .bind(&settings.allowed_users)
+    .bind(settings.is_generated)
     .bind(false)
-    .bind(&rel_path)
-    .fetch_one(&state.pool)
+    .bind(&blob.storage_rel_path)
+    .bind(file.folder)
+    .bind(settings.requestable)
+    .bind(settings.watermark)
+    .bind(&blob.encryption_key_id)
+    .bind(&blob.wrapped_data_key)
+    .bind(&blob.sha256)
+    .bind(settings.view_requires_approval)
+    .bind(if state.scanner.enabled() { scanner::QUARANTINED } else { scanner::UNSCANNED })
+    .fetch_one(&mut *conn)
     .await;
 
-    match inserted {
+    let inserted = match inserted {
         Ok(doc) => {
-            let api = DocumentApiDto::from(DocumentDto::from(doc));
-            (StatusCode::CREATED, Json(api)).into_response()
+            // Quarantined uploads get their previews once the scan has cleared them.
+            let queued = if state.scanner.enabled() {
+                let payload = serde_json::json!(scanner::ScanPayload { document_id: doc.id });
+                jobs::enqueue(&mut *conn, jobs::SCAN_DOCUMENT, payload).await
+            } else {
+                let payload = serde_json::json!(previews::GeneratePayload { document_id: doc.id });
+                jobs::enqueue(&mut *conn, jobs::GENERATE_PREVIEWS, payload).await
+            };
+            queued.map(|_| doc)
         }
+        Err(e) => Err(e),
+    };
+
+    match inserted {
+        Ok(doc) => Ok((doc, blob)),
         Err(e) => {
             error!(?e, "insert document failed");
-            let _ = tokio::fs::remove_file(&abs_path).await;
-            (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+            storage::discard(state, &blob).await;
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "db error"))
         }
     }
 }
 
+async fn announce_document(state: &AppState, doc: &DocumentRow) {
+    publish_event(&state.pool, events::Event::DocumentCreated { document_id: doc.id }).await;
+    publish_document_shared(&state.pool, doc, &[]).await;
+}
+
 #[derive(Debug, Deserialize)]
 struct PatchDocumentRequest {
     name: Option<String>,
@@ -880,6 +1463,12 @@ struct PatchDocumentRequest {
     permission: Option<String>,
     allowed_users: Option<Vec<Uuid>>,
     download_preauthorized: Option<bool>,
+    requestable: Option<bool>,
+    watermark: Option<bool>,
+    view_requires_approval: Option<bool>,
+    folder: Option<String>,
+    tags: Option<Vec<String>>,
+    metadata: Option<HashMap<String, Option<serde_json::Value>>>,
 }
 
 async fn patch_document(
@@ -889,16 +1478,7 @@ async fn patch_document(
     Json(body): Json<PatchDocumentRequest>,
 ) -> impl IntoResponse {
     let existing = sqlx::query_as::<_, DocumentRow>(
-        r#"
-        select
-            d.id, d.name, d.mime_type, d.size, d.notes,
-            d.owner_id, u.username as owner_name,
-            d.permission, d.allowed_users, d.is_generated, d.download_preauthorized, d.storage_rel_path,
-            d.created_at, d.updated_at
-        from documents d
-        join users u on u.id = d.owner_id
-        where d.id = $1
-        "#,
+        &format!("select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"),
     )
     .bind(id)
     .fetch_optional(&state.pool)
@@ -916,57 +1496,183 @@ async fn patch_document(
         return (StatusCode::FORBIDDEN, "forbidden").into_response();
     }
 
+    let previous_permission = existing.permission.clone();
     let permission = body.permission.unwrap_or(existing.permission);
     if permission != "public" && permission != "private" && permission != "specific" {
         return (StatusCode::BAD_REQUEST, "invalid permission").into_response();
     }
 
+    let previously_allowed = existing.allowed_users.clone();
     let mut allowed_users = body.allowed_users.unwrap_or(existing.allowed_users);
     if permission != "specific" {
         allowed_users.clear();
     }
 
+    if existing.legal_hold && (permission != previous_permission || allowed_users != previously_allowed) {
+        let details = serde_json::json!({ "permission": permission, "allowedUsers": allowed_users });
+        if let Err(e) = retention::audit(&state.pool, Some(id), Some(authed.id), "patch.blocked", details).await {
+            error!(?e, "retention audit failed");
+        }
+        return (StatusCode::CONFLICT, "document is under legal hold").into_response();
+    }
+
     let name = body.name.unwrap_or(existing.name);
     let notes = body.notes.unwrap_or(existing.notes);
     let download_preauthorized = body.download_preauthorized.unwrap_or(existing.download_preauthorized);
+    let requestable = body.requestable.unwrap_or(existing.requestable);
+    let watermark = body.watermark.unwrap_or(existing.watermark);
+    let view_requires_approval = body.view_requires_approval.unwrap_or(existing.view_requires_approval);
+    let folder = match body.folder {
+        Some(f) => match normalize_folder(&f) {
+            Some(v) => v,
+            None => return (StatusCode::BAD_REQUEST, "invalid folder").into_response(),
+        },
+        None => existing.folder,
+    };
+    let tags = match body.tags.as_deref().map(metadata::normalize_tags) {
+        Some(Ok(v)) => Some(v),
+        Some(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        None => None,
+    };
+    let metadata_changes = match &body.metadata {
+        Some(patch) => match metadata::validate_metadata(&state.pool, patch).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        },
+        None => vec![],
+    };
 
-    let updated = sqlx::query_as::<_, DocumentRow>(
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let current_folder = sqlx::query_scalar::<_, String>("select folder from documents where id = $1 and deleted_at is null for update")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await;
+    let moved = match current_folder {
+        Ok(Some(current)) => retention::check_folder_move(&mut tx, id, &current, &folder).await,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let moved = match moved {
+        Ok(Ok(v)) => v,
+        Ok(Err(reason)) => {
+            drop(tx);
+            let details = serde_json::json!({ "folder": folder, "reason": reason });
+            if let Err(e) = retention::audit(&state.pool, Some(id), Some(authed.id), "patch.blocked", details).await {
+                error!(?e, "retention audit failed");
+            }
+            return (StatusCode::CONFLICT, reason).into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let updated = sqlx::query_as::<_, DocumentRow>(&format!(
         r#"
-        update documents
-        set name = $2, notes = $3, permission = $4, allowed_users = $5, download_preauthorized = $6, updated_at = now()
-        where id = $1
-        returning
-            id, name, mime_type, size, notes,
-            owner_id, (select username from users where id = owner_id) as owner_name,
-            permission, allowed_users, is_generated, download_preauthorized, storage_rel_path,
-            created_at, updated_at
-        "#,
-    )
+        with d as (
+            update documents
+            set name = $2, notes = $3, permission = $4, allowed_users = $5, download_preauthorized = $6, folder = $7,
+                requestable = $8, watermark = $9, view_requires_approval = $10, updated_at = now()
+            where id = $1 and deleted_at is null
+                and not (legal_hold and (permission <> $4 or allowed_users <> $5))
+            returning *
+        )
+        select {DOCUMENT_COLUMNS} from d join users u on u.id = d.owner_id
+        "#
+    ))
     .bind(id)
     .bind(&name)
     .bind(&notes)
     .bind(&permission)
     .bind(&allowed_users)
     .bind(download_preauthorized)
-    .fetch_one(&state.pool)
+    .bind(&folder)
+    .bind(requestable)
+    .bind(watermark)
+    .bind(view_requires_approval)
+    .fetch_optional(&mut *tx)
     .await;
+    let updated = match (updated, moved) {
+        (Ok(Some(doc)), Some(details)) => {
+            retention::audit(&mut *tx, Some(id), Some(authed.id), "document.moved", details).await.map(|_| Some(doc))
+        }
+        (updated, _) => updated,
+    };
+    let updated = match updated {
+        Ok(Some(doc)) => update_document_metadata(&mut tx, id, tags.as_deref(), &metadata_changes)
+            .await
+            .map(|labels| Some((doc, labels))),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    let updated = match updated {
+        Ok(Some(v)) => tx.commit().await.map(|_| Some(v)),
+        other => other,
+    };
 
     match updated {
-        Ok(doc) => {
-            let api = DocumentApiDto::from(DocumentDto::from(doc));
+        Ok(Some((doc, labels))) => {
+            let updated_event = events::Event::DocumentUpdated {
+                document_id: doc.id,
+                previous_permission,
+                previous_allowed_users: previously_allowed.clone(),
+            };
+            publish_event(&state.pool, updated_event).await;
+            publish_document_shared(&state.pool, &doc, &previously_allowed).await;
+            let api = DocumentApiDto::from(DocumentDto::from(doc)).with_labels(labels);
             (StatusCode::OK, Json(api)).into_response()
         }
+        Ok(None) => (StatusCode::CONFLICT, "document changed, retry").into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     }
 }
 
+// Applies tag and metadata changes and re-reads them so the response reflects them.
+async fn update_document_metadata(
+    conn: &mut PgConnection,
+    id: Uuid,
+    tags: Option<&[String]>,
+    changes: &[metadata::MetadataChange],
+) -> sqlx::Result<metadata::Labels> {
+    if let Some(tags) = tags {
+        metadata::set_tags(&mut *conn, id, tags).await?;
+    }
+    metadata::apply_metadata(&mut *conn, id, changes).await?;
+    let mut labels = metadata::load_labels(&mut *conn, &[id]).await?;
+    Ok(labels.remove(&id).unwrap_or_default())
+}
+
+async fn publish_document_shared(pool: &PgPool, doc: &DocumentRow, previously_allowed: &[Uuid]) {
+    let user_ids: Vec<Uuid> = doc
+        .allowed_users
+        .iter()
+        .filter(|u| **u != doc.owner_id && !previously_allowed.contains(u))
+        .copied()
+        .collect();
+    if user_ids.is_empty() {
+        return;
+    }
+    let event = events::Event::DocumentShared {
+        document_id: doc.id,
+        user_ids,
+    };
+    publish_event(pool, event).await;
+}
+
+async fn publish_event(pool: &PgPool, event: events::Event) {
+    if let Err(e) = events::publish(pool, event).await {
+        error!(?e, "publish event failed");
+    }
+}
+
 async fn delete_document(
     State(state): State<AppState>,
     Extension(authed): Extension<AuthedUser>,
     AxumPath(id): AxumPath<Uuid>,
 ) -> impl IntoResponse {
-    let existing = sqlx::query_as::<_, (String, Uuid, String)>(
-        "select storage_rel_path, owner_id, permission from documents where id = $1",
+    let existing = sqlx::query_as::<_, (Uuid, String, Vec<Uuid>)>(
+        "select owner_id, permission, allowed_users from documents where id = $1 and deleted_at is null",
     )
     .bind(id)
     .fetch_optional(&state.pool)
@@ -976,7 +1682,7 @@ async fn delete_document(
         Ok(v) => v,
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     };
-    let Some((storage_rel_path, owner_id, _permission)) = maybe else {
+    let Some((owner_id, permission, allowed_users)) = maybe else {
         return (StatusCode::NOT_FOUND, "not found").into_response();
     };
 
@@ -984,18 +1690,61 @@ async fn delete_document(
         return (StatusCode::FORBIDDEN, "forbidden").into_response();
     }
 
-    let res = sqlx::query("delete from documents where id = $1")
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let locked = sqlx::query("select 1 from documents where id = $1 and deleted_at is null for update")
         .bind(id)
-        .execute(&state.pool)
+        .fetch_optional(&mut *tx)
+        .await;
+    match locked {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    match retention::deletion_block(&mut *tx, id).await {
+        Ok(None) => {}
+        Ok(Some(reason)) => {
+            drop(tx);
+            let details = serde_json::json!({ "reason": reason });
+            if let Err(e) = retention::audit(&state.pool, Some(id), Some(authed.id), "delete.blocked", details).await {
+                error!(?e, "retention audit failed");
+            }
+            return (StatusCode::CONFLICT, reason).into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let res = sqlx::query("update documents set deleted_at = now(), deleted_by = $2 where id = $1 and deleted_at is null")
+        .bind(id)
+        .bind(authed.id)
+        .execute(&mut *tx)
         .await;
 
     match res {
         Ok(r) if r.rows_affected() == 0 => return (StatusCode::NOT_FOUND, "not found").into_response(),
-        Ok(_) => {
-            let abs_path = state.storage_root.join(storage_rel_path);
-            let _ = tokio::fs::remove_file(abs_path).await;
-            StatusCode::NO_CONTENT.into_response()
-        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let event = events::Event::DocumentDeleted {
+        document_id: id,
+        owner_id,
+        permission,
+        allowed_users,
+    };
+    if events::publish(&mut *tx, event).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+    if retention::audit(&mut *tx, Some(id), Some(authed.id), "document.deleted", serde_json::json!({})).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     }
 }
@@ -1006,17 +1755,148 @@ async fn download_document(
     AxumPath(id): AxumPath<Uuid>,
 ) -> impl IntoResponse {
     let row = sqlx::query_as::<_, DocumentRow>(
+        &format!("select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"),
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    let maybe = match row {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let Some(doc) = maybe else {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    };
+
+    if !doc_accessible(&doc, &authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if let Some((status, msg)) = scanner::blocked(&doc) {
+        return (status, msg).into_response();
+    }
+
+    if is_admin(&authed) || doc.owner_id == authed.id || doc.download_preauthorized {
+        return match document_stream(&state, &doc).await {
+            Ok(body) => file_response(body, &doc.mime_type, &doc.name, "attachment"),
+            Err(resp) => resp,
+        };
+    }
+
+    let request_id = match claim_download(&state.pool, doc.id, authed.id).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(reason)) => return (StatusCode::FORBIDDEN, reason).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let reader = match approved_reader(&state, &doc, request_id).await {
+        Ok(v) => v,
+        Err(resp) => {
+            refund_download(&state.pool, request_id).await;
+            return resp;
+        }
+    };
+    // The download is counted up front so concurrent transfers cannot exceed the limit, and given back if
+    // the client does not receive the whole file.
+    let pool = state.pool.clone();
+    let body = storage::body_or_else(reader, move || {
+        tokio::spawn(async move { refund_download(&pool, request_id).await });
+    });
+    file_response(body, &doc.mime_type, &doc.name, "attachment")
+}
+
+// Consumes one download from the user's newest usable approved request, or says why none is available.
+async fn claim_download(pool: &PgPool, document_id: Uuid, user_id: Uuid) -> sqlx::Result<Result<Uuid, &'static str>> {
+    let consumed = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        update download_requests
+        set download_count = download_count + 1,
+            downloads_remaining = downloads_remaining - 1,
+            updated_at = now()
+        where id = (
+            select r.id
+            from download_requests r
+            where r.document_id = $1
+              and r.requester_id = $2
+              and r.status = 'approved'
+              and (r.expires_at is null or r.expires_at > now())
+              and (r.downloads_remaining is null or r.downloads_remaining > 0)
+            order by r.approved_at desc
+            limit 1
+            for update
+        )
+        returning id
+        "#,
+    )
+    .bind(document_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(request_id) = consumed {
+        return Ok(Ok(request_id));
+    }
+
+    let exhausted = sqlx::query_scalar::<_, bool>(
+        r#"
+        select exists(
+            select 1
+            from download_requests r
+            where r.document_id = $1
+              and r.requester_id = $2
+              and r.status = 'approved'
+              and (r.expires_at is null or r.expires_at > now())
+              and r.downloads_remaining = 0
+        )
+        "#,
+    )
+    .bind(document_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Err(if exhausted { "download limit reached" } else { "download approval required" }))
+}
+
+// Gives back a download claimed for a transfer that did not complete.
+async fn refund_download(pool: &PgPool, request_id: Uuid) {
+    let res = sqlx::query(
         r#"
-        select
-            d.id, d.name, d.mime_type, d.size, d.notes,
-            d.owner_id, u.username as owner_name,
-            d.permission, d.allowed_users, d.is_generated, d.download_preauthorized, d.storage_rel_path,
-            d.created_at, d.updated_at
-        from documents d
-        join users u on u.id = d.owner_id
-        where d.id = $1
+        update download_requests
+        set download_count = greatest(download_count - 1, 0),
+            downloads_remaining = downloads_remaining + 1,
+            updated_at = now()
+        where id = $1
         "#,
     )
+    .bind(request_id)
+    .execute(pool)
+    .await;
+    if let Err(e) = res {
+        error!(?e, %request_id, "refund download failed");
+    }
+}
+
+async fn approved_reader(state: &AppState, doc: &DocumentRow, request_id: Uuid) -> Result<storage::BoxReader, axum::response::Response> {
+    if !watermark::applies(doc) {
+        return document_reader(state, doc).await;
+    }
+    let data = document_bytes(state, doc).await?;
+    match watermark::apply(state, doc, request_id, data).await {
+        Ok(v) => Ok(Box::new(std::io::Cursor::new(v))),
+        Err(e) => {
+            error!(?e, document_id = %doc.id, %request_id, "watermark failed");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "watermark failed").into_response())
+        }
+    }
+}
+
+async fn view_document(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let row = sqlx::query_as::<_, DocumentRow>(
+        &format!("select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"),
+    )
     .bind(id)
     .fetch_optional(&state.pool)
     .await;
@@ -1032,48 +1912,113 @@ async fn download_document(
     if !doc_accessible(&doc, &authed) {
         return (StatusCode::FORBIDDEN, "forbidden").into_response();
     }
+    if let Some((status, msg)) = scanner::blocked(&doc) {
+        return (status, msg).into_response();
+    }
 
-    if !is_admin(&authed) && doc.owner_id != authed.id {
-        if !doc.download_preauthorized {
-            let ok = sqlx::query_scalar::<_, bool>(
-                r#"
-                select exists(
-                    select 1
-                    from download_requests r
-                    where r.document_id = $1
-                      and r.requester_id = $2
-                      and r.status = 'approved'
-                      and (r.expires_at is null or r.expires_at > now())
-                )
-                "#,
-            )
-            .bind(doc.id)
-            .bind(authed.id)
-            .fetch_one(&state.pool)
-            .await;
-
-            match ok {
-                Ok(true) => {}
-                Ok(false) => return (StatusCode::FORBIDDEN, "download approval required").into_response(),
-                Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
-            }
-        }
+    // Viewing never consumes downloads; an approved, unexpired request is enough.
+    let approved = sqlx::query_scalar::<_, Uuid>(
+        r#"
+        select r.id
+        from download_requests r
+        where r.document_id = $1
+          and r.requester_id = $2
+          and r.status = 'approved'
+          and (r.expires_at is null or r.expires_at > now())
+        order by r.approved_at desc
+        limit 1
+        "#,
+    )
+    .bind(doc.id)
+    .bind(authed.id)
+    .fetch_optional(&state.pool)
+    .await;
+    let request_id = match approved {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let privileged = is_admin(&authed) || doc.owner_id == authed.id || doc.download_preauthorized;
+    if doc.view_requires_approval && !privileged && request_id.is_none() {
+        return (StatusCode::FORBIDDEN, "view approval required").into_response();
     }
 
-    let abs_path = state.storage_root.join(&doc.storage_rel_path);
-    let data = match tokio::fs::read(&abs_path).await {
+    // Owners and admins see the original; everyone else gets a stamped copy, traced to their approval if
+    // they have one and to themselves otherwise.
+    if is_admin(&authed) || doc.owner_id == authed.id || !watermark::applies(&doc) {
+        return match document_stream(&state, &doc).await {
+            Ok(body) => inline_response(body, &doc.mime_type, &doc.name),
+            Err(resp) => resp,
+        };
+    }
+    let data = match document_bytes(&state, &doc).await {
         Ok(v) => v,
-        Err(_) => return (StatusCode::NOT_FOUND, "file missing").into_response(),
+        Err(resp) => return resp,
+    };
+    let stamped = match request_id {
+        Some(request_id) => watermark::apply(&state, &doc, request_id, data).await,
+        None => watermark::apply_for_viewer(&state, &doc, authed.id, data).await,
+    };
+    match stamped {
+        Ok(data) => inline_response(data, &doc.mime_type, &doc.name),
+        Err(e) => {
+            error!(?e, document_id = %doc.id, "watermark failed");
+            (StatusCode::INTERNAL_SERVER_ERROR, "watermark failed").into_response()
+        }
+    }
+}
+
+fn inline_response(data: impl Into<axum::body::Body>, mime_type: &str, name: &str) -> axum::response::Response {
+    // Browsers refuse to run their PDF viewer inside a sandboxed document, so PDFs only get the fetch restrictions.
+    let csp = if mime_type.eq_ignore_ascii_case("application/pdf") {
+        "default-src 'none'; object-src 'self'; frame-ancestors 'self'"
+    } else {
+        "default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'; frame-ancestors 'self'; sandbox"
     };
+    let mut resp = file_response(data, mime_type, name, "inline");
+    let headers = resp.headers_mut();
+    headers.insert(axum::http::header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(csp));
+    headers.insert(axum::http::header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(axum::http::header::CACHE_CONTROL, HeaderValue::from_static("private, no-store"));
+    resp
+}
 
-    let mut resp = axum::response::Response::new(axum::body::Body::from(data));
+// Opens the document for streaming, mapping storage errors to responses.
+async fn document_reader(state: &AppState, doc: &DocumentRow) -> Result<storage::BoxReader, axum::response::Response> {
+    match storage::open_document(state, doc).await {
+        Ok(reader) => Ok(reader),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err((StatusCode::NOT_FOUND, "file missing").into_response()),
+        Err(e) => {
+            error!(?e, document_id = %doc.id, "open document failed");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response())
+        }
+    }
+}
+
+async fn document_stream(state: &AppState, doc: &DocumentRow) -> Result<axum::body::Body, axum::response::Response> {
+    document_reader(state, doc).await.map(storage::body)
+}
+
+async fn document_bytes(state: &AppState, doc: &DocumentRow) -> Result<Vec<u8>, axum::response::Response> {
+    match storage::read_document(state, doc).await {
+        Ok(v) => Ok(v),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err((StatusCode::NOT_FOUND, "file missing").into_response()),
+        Err(e) => {
+            error!(?e, document_id = %doc.id, "read document failed");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response())
+        }
+    }
+}
+
+fn file_response(data: impl Into<axum::body::Body>, mime_type: &str, name: &str, disposition: &'static str) -> axum::response::Response {
+    let mut resp = axum::response::Response::new(data.into());
     resp.headers_mut().insert(
         axum::http::header::CONTENT_TYPE,
-        HeaderValue::from_str(&doc.mime_type).unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
+        HeaderValue::from_str(mime_type).unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
     );
     resp.headers_mut().insert(
         axum::http::header::CONTENT_DISPOSITION,
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", doc.name)).unwrap_or_else(|_| HeaderValue::from_static("attachment")),
+        HeaderValue::from_str(&format!("{disposition}; filename=\"{name}\"")).unwrap_or_else(|_| HeaderValue::from_static(disposition)),
     );
     resp
 }
@@ -1092,16 +2037,7 @@ async fn create_download_request(
     }
 
     let doc = sqlx::query_as::<_, DocumentRow>(
-        r#"
-        select
-            d.id, d.name, d.mime_type, d.size, d.notes,
-            d.owner_id, u.username as owner_name,
-            d.permission, d.allowed_users, d.is_generated, d.download_preauthorized, d.storage_rel_path,
-            d.created_at, d.updated_at
-        from documents d
-        join users u on u.id = d.owner_id
-        where d.id = $1
-        "#,
+        &format!("select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"),
     )
     .bind(id)
     .fetch_optional(&state.pool)
@@ -1127,23 +2063,30 @@ async fn create_download_request(
         return (StatusCode::BAD_REQUEST, "download preauthorized").into_response();
     }
 
+    let approval_policy_id = match approvals::resolve_policy(&state.pool, doc.id).await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
     let message = body.message.unwrap_or_default();
+    let request_id = Uuid::new_v4();
     let res = sqlx::query(
         r#"
         insert into download_requests (
             id, document_id, requester_id,
             applicant_name, applicant_company, applicant_contact, message,
-            status
-        ) values ($1,$2,$3,$4,$5,$6,$7,'pending')
+            status, approval_policy_id
+        ) values ($1,$2,$3,$4,$5,$6,$7,'pending',$8)
         "#,
     )
-    .bind(Uuid::new_v4())
+    .bind(request_id)
     .bind(doc.id)
     .bind(authed.id)
     .bind(body.applicant_name.trim())
     .bind(body.applicant_company.trim())
     .bind(body.applicant_contact.trim())
     .bind(message)
+    .bind(approval_policy_id)
     .execute(&state.pool)
     .await;
 
@@ -1156,6 +2099,8 @@ async fn create_download_request(
         return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
     }
 
+    publish_event(&state.pool, events::Event::DownloadRequestCreated { request_id }).await;
+
     StatusCode::CREATED.into_response()
 }
 
@@ -1163,35 +2108,9 @@ async fn list_my_download_requests(
     State(state): State<AppState>,
     Extension(authed): Extension<AuthedUser>,
 ) -> impl IntoResponse {
-    let rows = sqlx::query_as::<_, DownloadRequestDto>(
-        r#"
-        select
-            r.id,
-            r.document_id,
-            r.requester_id,
-            ru.username as requester_name,
-            d.name as document_name,
-            d.owner_id,
-            ou.username as owner_name,
-            r.applicant_name,
-            r.applicant_company,
-            r.applicant_contact,
-            r.message,
-            r.status,
-            r.approver_id,
-            au.username as approver_name,
-            r.created_at,
-            r.updated_at,
-            r.expires_at
-        from download_requests r
-        join documents d on d.id = r.document_id
-        join users ru on ru.id = r.requester_id
-        join users ou on ou.id = d.owner_id
-        left join users au on au.id = r.approver_id
-        where r.requester_id = $1
-        order by r.created_at desc
-        "#,
-    )
+    let rows = sqlx::query_as::<_, DownloadRequestDto>(&format!(
+        "{DOWNLOAD_REQUEST_SELECT} where r.requester_id = $1 order by r.created_at desc"
+    ))
     .bind(authed.id)
     .fetch_all(&state.pool)
     .await;
@@ -1207,67 +2126,16 @@ async fn list_pending_download_requests(
     Extension(authed): Extension<AuthedUser>,
 ) -> impl IntoResponse {
     let rows = if is_admin(&authed) {
-        sqlx::query_as::<_, DownloadRequestDto>(
-            r#"
-            select
-                r.id,
-                r.document_id,
-                r.requester_id,
-                ru.username as requester_name,
-                d.name as document_name,
-                d.owner_id,
-                ou.username as owner_name,
-                r.applicant_name,
-                r.applicant_company,
-                r.applicant_contact,
-                r.message,
-                r.status,
-                r.approver_id,
-                au.username as approver_name,
-                r.created_at,
-                r.updated_at,
-                r.expires_at
-            from download_requests r
-            join documents d on d.id = r.document_id
-            join users ru on ru.id = r.requester_id
-            join users ou on ou.id = d.owner_id
-            left join users au on au.id = r.approver_id
-            where r.status = 'pending'
-            order by r.created_at asc
-            "#,
-        )
+        sqlx::query_as::<_, DownloadRequestDto>(&format!(
+            "{DOWNLOAD_REQUEST_SELECT} where r.status = 'pending' and d.deleted_at is null order by r.created_at asc"
+        ))
         .fetch_all(&state.pool)
         .await
     } else {
-        sqlx::query_as::<_, DownloadRequestDto>(
-            r#"
-            select
-                r.id,
-                r.document_id,
-                r.requester_id,
-                ru.username as requester_name,
-                d.name as document_name,
-                d.owner_id,
-                ou.username as owner_name,
-                r.applicant_name,
-                r.applicant_company,
-                r.applicant_contact,
-                r.message,
-                r.status,
-                r.approver_id,
-                au.username as approver_name,
-                r.created_at,
-                r.updated_at,
-                r.expires_at
-            from download_requests r
-            join documents d on d.id = r.document_id
-            join users ru on ru.id = r.requester_id
-            join users ou on ou.id = d.owner_id
-            left join users au on au.id = r.approver_id
-            where r.status = 'pending' and d.owner_id = $1
-            order by r.created_at asc
-            "#,
-        )
+        sqlx::query_as::<_, DownloadRequestDto>(&format!(
+            "{DOWNLOAD_REQUEST_SELECT} where r.status = 'pending' and d.deleted_at is null and {} order by r.created_at asc",
+            approvals::APPROVER_PENDING_FILTER
+        ))
         .bind(authed.id)
         .fetch_all(&state.pool)
         .await
@@ -1279,87 +2147,357 @@ async fn list_pending_download_requests(
     }
 }
 
+#[derive(Debug, Default, Deserialize)]
+struct ApproveDownloadRequest {
+    ttl_hours: Option<i64>,
+    max_downloads: Option<i32>,
+    note: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RejectDownloadRequest {
+    reason: Option<String>,
+}
+
 async fn approve_download_request(
     State(state): State<AppState>,
     Extension(authed): Extension<AuthedUser>,
     AxumPath(id): AxumPath<Uuid>,
+    body: Option<Json<ApproveDownloadRequest>>,
 ) -> impl IntoResponse {
-    let ttl_hours: i64 = std::env::var("DOWNLOAD_APPROVAL_TTL_HOURS")
+    let body = body.map(|Json(b)| b).unwrap_or_default();
+
+    let grant = match approval_grant(body) {
+        Ok(v) => v,
+        Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+
+    decide_download_request(&state, &authed, id, approvals::Decision::Approve(grant)).await
+}
+
+fn default_approval_expiry() -> DateTime<Utc> {
+    let ttl_hours = std::env::var("DOWNLOAD_APPROVAL_TTL_HOURS")
         .ok()
         .and_then(|v| v.parse::<i64>().ok())
-        .unwrap_or(DOWNLOAD_APPROVAL_TTL_HOURS_DEFAULT);
+        .unwrap_or(DOWNLOAD_APPROVAL_TTL_HOURS_DEFAULT)
+        .max(1);
+    Utc::now() + chrono::Duration::hours(ttl_hours)
+}
 
-    let expires_at = Utc::now() + chrono::Duration::hours(ttl_hours.max(1));
+fn approval_grant(body: ApproveDownloadRequest) -> Result<approvals::ApprovalGrant, &'static str> {
+    if matches!(body.ttl_hours, Some(v) if !(1..=DOWNLOAD_APPROVAL_TTL_HOURS_MAX).contains(&v)) {
+        return Err("invalid ttl_hours");
+    }
 
-    let res = if is_admin(&authed) {
-        sqlx::query(
-            "update download_requests set status = 'approved', approver_id = $2, approved_at = now(), updated_at = now(), expires_at = $3 where id = $1 and status = 'pending'",
-        )
-        .bind(id)
-        .bind(authed.id)
-        .bind(expires_at)
-        .execute(&state.pool)
-        .await
-    } else {
-        sqlx::query(
-            r#"
-            update download_requests r
-            set status = 'approved', approver_id = $2, approved_at = now(), updated_at = now(), expires_at = $3
-            from documents d
-            where r.id = $1 and r.status = 'pending' and d.id = r.document_id and d.owner_id = $2
-            "#,
-        )
-        .bind(id)
-        .bind(authed.id)
-        .bind(expires_at)
-        .execute(&state.pool)
-        .await
+    if matches!(body.max_downloads, Some(n) if n < 1) {
+        return Err("invalid max_downloads");
+    }
+
+    Ok(approvals::ApprovalGrant {
+        expires_at: body.ttl_hours.map(|v| Utc::now() + chrono::Duration::hours(v)),
+        max_downloads: body.max_downloads,
+        note: body.note.unwrap_or_default().trim().to_string(),
+    })
+}
+
+async fn reject_download_request(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    body: Option<Json<RejectDownloadRequest>>,
+) -> impl IntoResponse {
+    let reason = body.and_then(|Json(b)| b.reason).unwrap_or_default();
+    let decision = approvals::Decision::Reject {
+        reason: reason.trim().to_string(),
     };
 
-    match res {
-        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+    decide_download_request(&state, &authed, id, decision).await
+}
+
+async fn decide_download_request(
+    state: &AppState,
+    authed: &AuthedUser,
+    id: Uuid,
+    decision: approvals::Decision,
+) -> axum::response::Response {
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    if let Err(e) = approvals::decide(&mut tx, authed, id, &decision).await {
+        return e.into_response();
+    }
+
+    match tx.commit().await {
         Ok(_) => StatusCode::NO_CONTENT.into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     }
 }
 
-async fn reject_download_request(
+#[derive(Debug, Default, Deserialize)]
+struct BatchDownloadRequestFilter {
+    document_id: Option<Uuid>,
+    company: Option<String>,
+    requester_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchApproveDownloadRequests {
+    ids: Option<Vec<Uuid>>,
+    filter: Option<BatchDownloadRequestFilter>,
+    #[serde(flatten)]
+    options: ApproveDownloadRequest,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRejectDownloadRequests {
+    ids: Option<Vec<Uuid>>,
+    filter: Option<BatchDownloadRequestFilter>,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchItemResult {
+    id: Uuid,
+    ok: bool,
+    status: Option<&'static str>,
+    approval_stage: Option<i32>,
+    error: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchResult {
+    succeeded: usize,
+    failed: usize,
+    results: Vec<BatchItemResult>,
+}
+
+async fn batch_approve_download_requests(
     State(state): State<AppState>,
     Extension(authed): Extension<AuthedUser>,
-    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<BatchApproveDownloadRequests>,
 ) -> impl IntoResponse {
-    let res = if is_admin(&authed) {
-        sqlx::query(
-            "update download_requests set status = 'rejected', approver_id = $2, rejected_at = now(), updated_at = now() where id = $1 and status = 'pending'",
-        )
-        .bind(id)
-        .bind(authed.id)
-        .execute(&state.pool)
-        .await
-    } else {
-        sqlx::query(
+    let grant = match approval_grant(body.options) {
+        Ok(v) => v,
+        Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+
+    batch_decide_download_requests(&state, &authed, body.ids, body.filter, approvals::Decision::Approve(grant)).await
+}
+
+async fn batch_reject_download_requests(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<BatchRejectDownloadRequests>,
+) -> impl IntoResponse {
+    let decision = approvals::Decision::Reject {
+        reason: body.reason.unwrap_or_default().trim().to_string(),
+    };
+
+    batch_decide_download_requests(&state, &authed, body.ids, body.filter, decision).await
+}
+
+async fn batch_decide_download_requests(
+    state: &AppState,
+    authed: &AuthedUser,
+    ids: Option<Vec<Uuid>>,
+    filter: Option<BatchDownloadRequestFilter>,
+    decision: approvals::Decision,
+) -> axum::response::Response {
+    let mut targets: Vec<Uuid> = ids.unwrap_or_default();
+
+    if let Some(f) = filter {
+        let company = f.company.map(|c| c.trim().to_string()).filter(|c| !c.is_empty());
+        if f.document_id.is_none() && company.is_none() && f.requester_id.is_none() {
+            return (StatusCode::BAD_REQUEST, "empty filter").into_response();
+        }
+
+        let matched = sqlx::query_scalar::<_, Uuid>(&format!(
             r#"
-            update download_requests r
-            set status = 'rejected', approver_id = $2, rejected_at = now(), updated_at = now()
-            from documents d
-            where r.id = $1 and r.status = 'pending' and d.id = r.document_id and d.owner_id = $2
+            select r.id
+            from download_requests r
+            join documents d on d.id = r.document_id
+            where r.status = 'pending'
+              and d.deleted_at is null
+              and ($2::uuid is null or r.document_id = $2)
+              and ($3::text is null or lower(r.applicant_company) = lower($3))
+              and ($4::uuid is null or r.requester_id = $4)
+              and ($5 or {})
+            order by r.created_at asc
+            limit $6
             "#,
-        )
-        .bind(id)
+            approvals::APPROVER_PENDING_FILTER
+        ))
         .bind(authed.id)
-        .execute(&state.pool)
-        .await
+        .bind(f.document_id)
+        .bind(company)
+        .bind(f.requester_id)
+        .bind(is_admin(authed))
+        .bind(DOWNLOAD_BATCH_MAX as i64)
+        .fetch_all(&state.pool)
+        .await;
+
+        match matched {
+            Ok(v) => targets.extend(v),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    targets.retain(|id| seen.insert(*id));
+
+    if targets.is_empty() {
+        return (StatusCode::BAD_REQUEST, "no requests selected").into_response();
+    }
+    if targets.len() > DOWNLOAD_BATCH_MAX {
+        return (StatusCode::BAD_REQUEST, "too many requests").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     };
 
+    let mut results = Vec::with_capacity(targets.len());
+    for id in targets {
+        match approvals::decide(&mut tx, authed, id, &decision).await {
+            Ok(outcome) => results.push(BatchItemResult {
+                id,
+                ok: true,
+                status: Some(outcome.status),
+                approval_stage: Some(outcome.approval_stage),
+                error: None,
+            }),
+            Err(approvals::DecisionError::Db(e)) => {
+                error!(?e, "batch download request decision failed");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+            }
+            Err(e) => results.push(BatchItemResult {
+                id,
+                ok: false,
+                status: None,
+                approval_stage: None,
+                error: Some(e.status_and_message().1),
+            }),
+        }
+    }
+
+    if tx.commit().await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let succeeded = results.iter().filter(|r| r.ok).count();
+    let failed = results.len() - succeeded;
+    (StatusCode::OK, Json(BatchResult { succeeded, failed, results })).into_response()
+}
+
+async fn cancel_download_request(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let res = sqlx::query(
+        "update download_requests set status = 'cancelled', cancelled_at = now(), updated_at = now() where id = $1 and requester_id = $2 and status = 'pending'",
+    )
+    .bind(id)
+    .bind(authed.id)
+    .execute(&state.pool)
+    .await;
+
     match res {
         Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Ok(_) => {
+            publish_event(&state.pool, events::Event::DownloadRequestCancelled { request_id: id }).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
     }
 }
 
-fn sanitize_filename(name: &str) -> String {
-    name.chars()
-        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
-        .collect()
+async fn sweep_download_requests(pool: &PgPool) -> anyhow::Result<()> {
+    let sla_hours: i32 = std::env::var("DOWNLOAD_PENDING_SLA_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(DOWNLOAD_PENDING_SLA_HOURS_DEFAULT);
+    let warning_hours: i32 = std::env::var("DOWNLOAD_EXPIRY_WARNING_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(DOWNLOAD_EXPIRY_WARNING_HOURS_DEFAULT);
+
+    let mut tx = pool.begin().await?;
+    let expired_ids = sqlx::query_scalar::<_, Uuid>(
+        "update download_requests set status = 'expired', expired_at = now(), updated_at = now() where status = 'approved' and expires_at <= now() returning id",
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .context("expire approved download requests")?;
+
+    for request_id in &expired_ids {
+        events::publish(&mut *tx, events::Event::DownloadRequestExpired { request_id: *request_id }).await?;
+    }
+    tx.commit().await?;
+    let expired = expired_ids.len();
+
+    let mut auto_rejected = 0;
+    if sla_hours > 0 {
+        let mut tx = pool.begin().await?;
+        let ids = sqlx::query_scalar::<_, Uuid>(
+            "update download_requests set status = 'rejected', rejected_at = now(), updated_at = now(), reject_reason = 'no decision within SLA' where status = 'pending' and created_at <= now() - make_interval(hours => $1) returning id",
+        )
+        .bind(sla_hours)
+        .fetch_all(&mut *tx)
+        .await
+        .context("auto-reject stale download requests")?;
+
+        for request_id in &ids {
+            events::publish(&mut *tx, events::Event::DownloadRequestRejected { request_id: *request_id }).await?;
+        }
+        tx.commit().await?;
+        auto_rejected = ids.len();
+    }
+
+    let mut expiry_warned = 0;
+    if warning_hours > 0 {
+        let mut tx = pool.begin().await?;
+        let ids = sqlx::query_scalar::<_, Uuid>(
+            "update download_requests set expiry_warned_at = now() where status = 'approved' and expiry_warned_at is null and expires_at > now() and expires_at <= now() + make_interval(hours => $1) returning id",
+        )
+        .bind(warning_hours)
+        .fetch_all(&mut *tx)
+        .await
+        .context("flag expiring download requests")?;
+
+        for request_id in &ids {
+            events::publish(&mut *tx, events::Event::DownloadRequestExpiringSoon { request_id: *request_id }).await?;
+        }
+        tx.commit().await?;
+        expiry_warned = ids.len();
+    }
+
+    if expired > 0 || auto_rejected > 0 || expiry_warned > 0 {
+        info!(expired, auto_rejected, expiry_warned, "swept download requests");
+    }
+    Ok(())
+}
+
+fn normalize_folder(raw: &str) -> Option<String> {
+    let mut parts: Vec<&str> = vec![];
+    for part in raw.split(['/', '\\']) {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        if part == "." || part == ".." || part.chars().any(char::is_control) {
+            return None;
+        }
+        parts.push(part);
+    }
+    let folder = parts.join("/");
+    if folder.len() > 512 {
+        return None;
+    }
+    Some(folder)
 }
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Path as AxumPath, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgExecutor};
+use uuid::Uuid;
+
+use crate::{is_admin, AppState, AuthedUser};
+
+const TAG_MAX_LEN: usize = 64;
+const TAGS_PER_DOCUMENT_MAX: usize = 50;
+const TEXT_VALUE_MAX_LEN: usize = 1000;
+const AUTOCOMPLETE_LIMIT_DEFAULT: i64 = 20;
+const AUTOCOMPLETE_LIMIT_MAX: i64 = 100;
+const FIELD_TYPES: &[&str] = &["text", "number", "date", "enum"];
+
+// Filter over documents `d`: $1 lower-cased tag names that must all be present, and $2/$3/$4 parallel
+// arrays of metadata field id, operator and (already validated) value that must all match.
+pub const DOCUMENT_FILTER: &str = r#"
+    cardinality($1::text[]) = (
+        select count(distinct lower(t.name))
+        from document_tags dt join tags t on t.id = dt.tag_id
+        where dt.document_id = d.id and lower(t.name) = any($1::text[])
+    )
+    and not exists (
+        select 1
+        from unnest($2::uuid[], $3::text[], $4::text[]) as f(field_id, op, value)
+        where not exists (
+            select 1
+            from document_metadata m join metadata_fields mf on mf.id = m.field_id
+            where m.document_id = d.id and m.field_id = f.field_id and case mf.field_type
+                when 'number' then case f.op
+                    when 'eq' then m.value_number = f.value::double precision
+                    when 'gt' then m.value_number > f.value::double precision
+                    when 'gte' then m.value_number >= f.value::double precision
+                    when 'lt' then m.value_number < f.value::double precision
+                    when 'lte' then m.value_number <= f.value::double precision
+                end
+                when 'date' then case f.op
+                    when 'eq' then m.value_date = f.value::date
+                    when 'gt' then m.value_date > f.value::date
+                    when 'gte' then m.value_date >= f.value::date
+                    when 'lt' then m.value_date < f.value::date
+                    when 'lte' then m.value_date <= f.value::date
+                end
+                else case f.op
+                    when 'eq' then lower(m.value_text) = lower(f.value)
+                    when 'contains' then strpos(lower(m.value_text), lower(f.value)) > 0
+                end
+            end
+        )
+    )
+"#;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataFieldDto {
+    id: Uuid,
+    key: String,
+    label: String,
+    field_type: String,
+    options: Vec<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateFieldRequest {
+    key: String,
+    label: String,
+    field_type: String,
+    options: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateFieldRequest {
+    label: Option<String>,
+    options: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct TagDto {
+    id: Uuid,
+    name: String,
+    document_count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagQuery {
+    q: Option<String>,
+    limit: Option<i64>,
+}
+
+pub enum MetadataValue {
+    Text(String),
+    Number(f64),
+    Date(NaiveDate),
+}
+
+pub struct MetadataChange {
+    field_id: Uuid,
+    value: Option<MetadataValue>,
+}
+
+#[derive(Debug, Default)]
+pub struct Labels {
+    pub tags: Vec<String>,
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(sqlx::FromRow)]
+struct LabelsRow {
+    id: Uuid,
+    tags: Vec<String>,
+    metadata: sqlx::types::Json<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Default)]
+pub struct DocumentFilter {
+    pub tags: Vec<String>,
+    pub field_ids: Vec<Uuid>,
+    pub ops: Vec<String>,
+    pub values: Vec<String>,
+}
+
+async fn load_fields<'e>(exec: impl PgExecutor<'e>) -> sqlx::Result<HashMap<String, MetadataFieldDto>> {
+    let rows = sqlx::query_as::<_, MetadataFieldDto>(
+        "select id, key, label, field_type, options, created_at, updated_at from metadata_fields",
+    )
+    .fetch_all(exec)
+    .await?;
+    Ok(rows.into_iter().map(|f| (f.key.clone(), f)).collect())
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() <= 64
+        && key.starts_with(|c: char| c.is_ascii_lowercase())
+        && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn clean_options(options: Vec<String>) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    for o in options.into_iter().map(|o| o.trim().to_string()).filter(|o| !o.is_empty()) {
+        if !out.contains(&o) {
+            out.push(o);
+        }
+    }
+    out
+}
+
+// Trims, collapses whitespace and drops case-insensitive duplicates, keeping the first spelling.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, &'static str> {
+    let mut out: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+        if tag.is_empty() {
+            continue;
+        }
+        if tag.chars().count() > TAG_MAX_LEN || tag.contains(',') {
+            return Err("invalid tag");
+        }
+        if !out.iter().any(|t| t.to_lowercase() == tag.to_lowercase()) {
+            out.push(tag);
+        }
+    }
+    if out.len() > TAGS_PER_DOCUMENT_MAX {
+        return Err("too many tags");
+    }
+    Ok(out)
+}
+
+fn parse_value(field: &MetadataFieldDto, value: &serde_json::Value) -> Result<MetadataValue, &'static str> {
+    match (field.field_type.as_str(), value) {
+        ("number", serde_json::Value::Number(n)) => n.as_f64().filter(|v| v.is_finite()).map(MetadataValue::Number).ok_or("invalid number"),
+        ("number", _) => Err("invalid number"),
+        ("date", serde_json::Value::String(s)) => NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+            .map(MetadataValue::Date)
+            .map_err(|_| "invalid date"),
+        ("date", _) => Err("invalid date"),
+        ("enum", serde_json::Value::String(s)) if field.options.contains(s) => Ok(MetadataValue::Text(s.clone())),
+        ("enum", _) => Err("invalid enum value"),
+        (_, serde_json::Value::String(s)) if s.chars().count() <= TEXT_VALUE_MAX_LEN => Ok(MetadataValue::Text(s.clone())),
+        _ => Err("invalid text value"),
+    }
+}
+
+// Checks a `key -> value` patch against the field definitions; `null` clears a field.
+pub async fn validate_metadata<'e>(
+    exec: impl PgExecutor<'e>,
+    patch: &HashMap<String, Option<serde_json::Value>>,
+) -> sqlx::Result<Result<Vec<MetadataChange>, &'static str>> {
+    let fields = load_fields(exec).await?;
+    let mut changes = vec![];
+    for (key, value) in patch {
+        let Some(field) = fields.get(key) else {
+            return Ok(Err("unknown metadata field"));
+        };
+        let value = match value {
+            Some(v) => match parse_value(field, v) {
+                Ok(v) => Some(v),
+                Err(msg) => return Ok(Err(msg)),
+            },
+            None => None,
+        };
+        changes.push(MetadataChange { field_id: field.id, value });
+    }
+    Ok(Ok(changes))
+}
+
+pub async fn apply_metadata(conn: &mut PgConnection, document_id: Uuid, changes: &[MetadataChange]) -> sqlx::Result<()> {
+    for change in changes {
+        let (text, number, date) = match &change.value {
+            None => {
+                sqlx::query("delete from document_metadata where document_id = $1 and field_id = $2")
+                    .bind(document_id)
+                    .bind(change.field_id)
+                    .execute(&mut *conn)
+                    .await?;
+                continue;
+            }
+            Some(MetadataValue::Text(v)) => (Some(v.as_str()), None, None),
+            Some(MetadataValue::Number(v)) => (None, Some(*v), None),
+            Some(MetadataValue::Date(v)) => (None, None, Some(*v)),
+        };
+        sqlx::query(
+            r#"
+            insert into document_metadata (document_id, field_id, value_text, value_number, value_date)
+            values ($1,$2,$3,$4,$5)
+            on conflict (document_id, field_id)
+            do update set value_text = excluded.value_text, value_number = excluded.value_number, value_date = excluded.value_date
+            "#,
+        )
+        .bind(document_id)
+        .bind(change.field_id)
+        .bind(text)
+        .bind(number)
+        .bind(date)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+// Tags and metadata values of the given documents, keyed by document id.
+pub async fn load_labels<'e>(exec: impl PgExecutor<'e>, document_ids: &[Uuid]) -> sqlx::Result<HashMap<Uuid, Labels>> {
+    let rows = sqlx::query_as::<_, LabelsRow>(
+        r#"
+        select
+            d.id,
+            coalesce((
+                select array_agg(t.name order by lower(t.name))
+                from document_tags dt join tags t on t.id = dt.tag_id
+                where dt.document_id = d.id
+            ), '{}') as tags,
+            coalesce((
+                select jsonb_object_agg(f.key, coalesce(to_jsonb(m.value_text), to_jsonb(m.value_number), to_jsonb(m.value_date)))
+                from document_metadata m join metadata_fields f on f.id = m.field_id
+                where m.document_id = d.id
+            ), '{}'::jsonb) as metadata
+        from unnest($1::uuid[]) as d(id)
+        "#,
+    )
+    .bind(document_ids)
+    .fetch_all(exec)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| (r.id, Labels { tags: r.tags, metadata: r.metadata.0 }))
+        .collect())
+}
+
+// Replaces the document's tags, creating tags that do not exist yet.
+pub async fn set_tags(conn: &mut PgConnection, document_id: Uuid, tags: &[String]) -> sqlx::Result<()> {
+    sqlx::query("delete from document_tags where document_id = $1")
+        .bind(document_id)
+        .execute(&mut *conn)
+        .await?;
+    for tag in tags {
+        let tag_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            with ins as (
+                insert into tags (id, name) values ($1, $2)
+                on conflict (lower(name)) do nothing
+                returning id
+            )
+            select id from ins
+            union all
+            select id from tags where lower(name) = lower($2)
+            limit 1
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(tag)
+        .fetch_one(&mut *conn)
+        .await?;
+        sqlx::query("insert into document_tags (document_id, tag_id) values ($1, $2) on conflict do nothing")
+            .bind(document_id)
+            .bind(tag_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+// Reads `tags=a,b` and `meta.<key>[.<op>]=<value>` filters from a document list query.
+pub async fn parse_filter<'e>(
+    exec: impl PgExecutor<'e>,
+    query: &HashMap<String, String>,
+) -> sqlx::Result<Result<DocumentFilter, &'static str>> {
+    let mut filter = DocumentFilter::default();
+    if let Some(tags) = query.get("tags") {
+        let tags: Vec<String> = tags.split(',').map(str::to_string).collect();
+        match normalize_tags(&tags) {
+            Ok(v) => filter.tags = v.into_iter().map(|t| t.to_lowercase()).collect(),
+            Err(msg) => return Ok(Err(msg)),
+        }
+    }
+
+    let meta: Vec<(&str, &String)> = query.iter().filter_map(|(k, v)| Some((k.strip_prefix("meta.")?, v))).collect();
+    if meta.is_empty() {
+        return Ok(Ok(filter));
+    }
+    let fields = load_fields(exec).await?;
+    for (key, value) in meta {
+        let (key, op) = key.split_once('.').unwrap_or((key, "eq"));
+        let Some(field) = fields.get(key) else {
+            return Ok(Err("unknown metadata field"));
+        };
+        let value = value.trim();
+        let valid = match (field.field_type.as_str(), op) {
+            ("number", "eq" | "gt" | "gte" | "lt" | "lte") => value.parse::<f64>().is_ok_and(f64::is_finite),
+            ("date", "eq" | "gt" | "gte" | "lt" | "lte") => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            ("text", "eq" | "contains") | ("enum", "eq") => true,
+            _ => return Ok(Err("unsupported metadata filter")),
+        };
+        if !valid {
+            return Ok(Err("invalid metadata filter value"));
+        }
+        filter.field_ids.push(field.id);
+        filter.ops.push(op.to_string());
+        filter.values.push(value.to_string());
+    }
+    Ok(Ok(filter))
+}
+
+// Autocomplete over tags on documents the caller can see, most used first.
+pub async fn list_tags(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Query(q): Query<TagQuery>,
+) -> impl IntoResponse {
+    let prefix = q.q.unwrap_or_default().trim().to_lowercase();
+    let limit = q.limit.unwrap_or(AUTOCOMPLETE_LIMIT_DEFAULT).clamp(1, AUTOCOMPLETE_LIMIT_MAX);
+    let rows = sqlx::query_as::<_, TagDto>(
+        r#"
+        select t.id, t.name, count(*) as document_count
+        from tags t
+        join document_tags dt on dt.tag_id = t.id
+        join documents d on d.id = dt.document_id
+        where d.deleted_at is null
+            and starts_with(lower(t.name), $1)
+            and ($2 or d.owner_id = $3 or d.permission = 'public' or (d.permission = 'specific' and $3 = any(d.allowed_users)))
+        group by t.id, t.name
+        order by count(*) desc, lower(t.name)
+        limit $4
+        "#,
+    )
+    .bind(&prefix)
+    .bind(is_admin(&authed))
+    .bind(authed.id)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_fields(State(state): State<AppState>) -> impl IntoResponse {
+    let rows = sqlx::query_as::<_, MetadataFieldDto>(
+        "select id, key, label, field_type, options, created_at, updated_at from metadata_fields order by lower(label)",
+    )
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn create_field(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<CreateFieldRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let key = body.key.trim();
+    let label = body.label.trim();
+    if !valid_key(key) {
+        return (StatusCode::BAD_REQUEST, "invalid key").into_response();
+    }
+    if label.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    if !FIELD_TYPES.contains(&body.field_type.as_str()) {
+        return (StatusCode::BAD_REQUEST, "invalid field type").into_response();
+    }
+    let options = clean_options(body.options.unwrap_or_default());
+    if (body.field_type == "enum") == options.is_empty() {
+        return (StatusCode::BAD_REQUEST, "options are required for enum fields only").into_response();
+    }
+
+    let created = sqlx::query_as::<_, MetadataFieldDto>(
+        r#"
+        insert into metadata_fields (id, key, label, field_type, options)
+        values ($1,$2,$3,$4,$5)
+        returning id, key, label, field_type, options, created_at, updated_at
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(key)
+    .bind(label)
+    .bind(&body.field_type)
+    .bind(&options)
+    .fetch_one(&state.pool)
+    .await;
+
+    match created {
+        Ok(v) => (StatusCode::CREATED, Json(v)).into_response(),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => (StatusCode::CONFLICT, "key already exists").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+// The type and key are fixed once created; enum options still in use cannot be removed.
+pub async fn update_field(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<UpdateFieldRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let existing = sqlx::query_as::<_, MetadataFieldDto>(
+        "select id, key, label, field_type, options, created_at, updated_at from metadata_fields where id = $1 for update",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await;
+    let existing = match existing {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let label = body.label.map(|l| l.trim().to_string()).unwrap_or(existing.label);
+    if label.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    let options = match body.options {
+        Some(_) if existing.field_type != "enum" => {
+            return (StatusCode::BAD_REQUEST, "options are required for enum fields only").into_response();
+        }
+        Some(o) => clean_options(o),
+        None => existing.options,
+    };
+    if existing.field_type == "enum" {
+        if options.is_empty() {
+            return (StatusCode::BAD_REQUEST, "options are required for enum fields only").into_response();
+        }
+        let in_use = sqlx::query_scalar::<_, bool>(
+            "select exists (select 1 from document_metadata where field_id = $1 and not (value_text = any($2)))",
+        )
+        .bind(id)
+        .bind(&options)
+        .fetch_one(&mut *tx)
+        .await;
+        match in_use {
+            Ok(true) => return (StatusCode::CONFLICT, "option in use").into_response(),
+            Ok(false) => {}
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        }
+    }
+
+    let updated = sqlx::query_as::<_, MetadataFieldDto>(
+        r#"
+        update metadata_fields set label = $2, options = $3, updated_at = now()
+        where id = $1
+        returning id, key, label, field_type, options, created_at, updated_at
+        "#,
+    )
+    .bind(id)
+    .bind(&label)
+    .bind(&options)
+    .fetch_one(&mut *tx)
+    .await;
+
+    match updated {
+        Ok(v) => match tx.commit().await {
+            Ok(_) => (StatusCode::OK, Json(v)).into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        },
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn delete_field(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let res = sqlx::query("delete from metadata_fields where id = $1").bind(id).execute(&state.pool).await;
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Path as AxumPath, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::{approvals, events::Event, jobs, mailer::OutgoingEmail, share_links, AppState, AuthedUser};
+
+pub const EMAIL_EVENT_TYPES: &[&str] = &[
+    "user.registered",
+    "user.approved",
+    "user.disabled",
+    "download_request.created",
+    "download_request.stage_advanced",
+    "download_request.approved",
+    "download_request.rejected",
+    "document.infected",
+    "document.unscannable",
+];
+
+pub const INBOX_EVENT_TYPES: &[&str] = &[
+    "user.registered",
+    "user.approved",
+    "download_request.created",
+    "download_request.stage_advanced",
+    "download_request.approved",
+    "download_request.rejected",
+    "download_request.expiring_soon",
+    "document.shared",
+    "document.infected",
+    "document.unscannable",
+];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPreferenceDto {
+    event_type: String,
+    email_enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationPreferenceInput {
+    event_type: String,
+    email_enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateNotificationPreferencesRequest {
+    preferences: Vec<NotificationPreferenceInput>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RequestMailRow {
+    document_id: Uuid,
+    document_name: String,
+    requester_id: Option<Uuid>,
+    requester_name: String,
+    external_email: Option<String>,
+    applicant_name: String,
+    applicant_company: String,
+    applicant_contact: String,
+    message: String,
+    status: String,
+    approver_note: String,
+    reject_reason: String,
+    max_downloads: Option<i32>,
+    expires_at: Option<DateTime<Utc>>,
+    approver_ids: Vec<Uuid>,
+}
+
+enum Recipient {
+    User(Uuid),
+    External(String),
+}
+
+struct Notice {
+    recipient: Recipient,
+    title: String,
+    body: String,
+    document_id: Option<Uuid>,
+    download_request_id: Option<Uuid>,
+    share_link_id: Option<Uuid>,
+}
+
+impl Notice {
+    fn new(user_id: Uuid, title: impl Into<String>, body: impl Into<String>) -> Self {
+        Self {
+            recipient: Recipient::User(user_id),
+            title: title.into(),
+            body: body.into(),
+            document_id: None,
+            download_request_id: None,
+            share_link_id: None,
+        }
+    }
+
+    fn to_requester(r: &RequestMailRow, title: impl Into<String>, body: impl Into<String>) -> Option<Self> {
+        let recipient = match (r.requester_id, &r.external_email) {
+            (Some(id), _) => Recipient::User(id),
+            (None, Some(email)) => Recipient::External(email.clone()),
+            (None, None) => return None,
+        };
+        Some(Self {
+            recipient,
+            title: title.into(),
+            body: body.into(),
+            document_id: None,
+            download_request_id: None,
+            share_link_id: None,
+        })
+    }
+
+    fn for_request(mut self, r: &RequestMailRow, request_id: Uuid) -> Self {
+        self.document_id = Some(r.document_id);
+        self.download_request_id = Some(request_id);
+        self
+    }
+
+    fn with_share_link(mut self, share_link_id: Option<Uuid>) -> Self {
+        self.share_link_id = share_link_id;
+        self
+    }
+}
+
+fn format_expiry(expires_at: Option<DateTime<Utc>>) -> String {
+    expires_at
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| "never".to_string())
+}
+
+async fn build_notices(state: &AppState, event: &Event) -> anyhow::Result<Vec<Notice>> {
+    let pool = &state.pool;
+    let mut notices: Vec<Notice> = vec![];
+
+    match event {
+        Event::UserRegistered { user_id } => {
+            let Some((username, note)) =
+                sqlx::query_as::<_, (String, String)>("select username, note from users where id = $1")
+                    .bind(user_id)
+                    .fetch_optional(pool)
+                    .await?
+            else {
+                return Ok(notices);
+            };
+            let admins = sqlx::query_scalar::<_, Uuid>("select id from users where role = 'admin' and status = 'active'")
+                .fetch_all(pool)
+                .await?;
+            let title = format!("New registration pending approval: {username}");
+            let body = format!(
+                "A new account is waiting for approval.\n\nUsername: {username}\nNote: {note}\n\nApprove or reject it from the Users page."
+            );
+            notices.extend(admins.into_iter().map(|id| Notice::new(id, title.clone(), body.clone())));
+        }
+        Event::UserApproved { user_id } => {
+            notices.push(Notice::new(
+                *user_id,
+                "Your account has been approved",
+                "Your xdocs account has been approved. You can now sign in.",
+            ));
+        }
+        Event::UserDisabled { user_id } => {
+            notices.push(Notice::new(
+                *user_id,
+                "Your account has been disabled",
+                "Your xdocs account has been disabled by an administrator.",
+            ));
+        }
+        Event::DocumentShared { document_id, user_ids } => {
+            let Some((document_name, owner_name)) = sqlx::query_as::<_, (String, String)>(
+                "select d.name, u.username from documents d join users u on u.id = d.owner_id where d.id = $1",
+            )
+            .bind(document_id)
+            .fetch_optional(pool)
+            .await?
+            else {
+                return Ok(notices);
+            };
+            for user_id in user_ids {
+                let mut n = Notice::new(
+                    *user_id,
+                    format!("Document shared with you: {document_name}"),
+                    format!("{owner_name} shared \"{document_name}\" with you."),
+                );
+                n.document_id = Some(*document_id);
+                notices.push(n);
+            }
+        }
+        Event::DocumentInfected { document_id, .. } | Event::DocumentUnscannable { document_id, .. } => {
+            let Some((document_name, owner_id)) =
+                sqlx::query_as::<_, (String, Uuid)>("select name, owner_id from documents where id = $1")
+                    .bind(document_id)
+                    .fetch_optional(pool)
+                    .await?
+            else {
+                return Ok(notices);
+            };
+            let mut recipients = sqlx::query_scalar::<_, Uuid>("select id from users where role = 'admin' and status = 'active'")
+                .fetch_all(pool)
+                .await?;
+            if !recipients.contains(&owner_id) {
+                recipients.push(owner_id);
+            }
+            let (title, body) = match event {
+                Event::DocumentInfected { signature, .. } => (
+                    format!("Malware detected: {document_name}"),
+                    format!(
+                        "The virus scanner flagged \"{document_name}\" as infected ({signature}).\n\nThe document has been blocked from download."
+                    ),
+                ),
+                _ => (
+                    format!("Virus scan not possible: {document_name}"),
+                    format!(
+                        "The virus scanner could not scan \"{document_name}\".\n\nThe document stays blocked from download until it is rescanned successfully."
+                    ),
+                ),
+            };
+            for user_id in recipients {
+                let mut n = Notice::new(user_id, title.clone(), body.clone());
+                n.document_id = Some(*document_id);
+                notices.push(n);
+            }
+        }
+        Event::DownloadRequestCreated { request_id }
+        | Event::DownloadRequestStageAdvanced { request_id }
+        | Event::DownloadRequestApproved { request_id }
+        | Event::DownloadRequestRejected { request_id }
+        | Event::DownloadRequestExpiringSoon { request_id } => {
+            let Some(r) = load_request(pool, *request_id).await? else {
+                return Ok(notices);
+            };
+            let notice = match event {
+                Event::DownloadRequestCreated { .. } | Event::DownloadRequestStageAdvanced { .. } => {
+                    let title = match event {
+                        Event::DownloadRequestCreated { .. } => format!("New download request for {}", r.document_name),
+                        _ => format!("Download request awaiting your approval: {}", r.document_name),
+                    };
+                    let body = format!(
+                        "{} requested to download \"{}\".\n\nApplicant: {}\nCompany: {}\nContact: {}\nMessage: {}\n\nReview it from the Download Requests page.",
+                        r.requester_name, r.document_name, r.applicant_name, r.applicant_company, r.applicant_contact, r.message
+                    );
+                    for user_id in r.approver_ids.iter().filter(|id| Some(**id) != r.requester_id) {
+                        notices.push(Notice::new(*user_id, title.clone(), body.clone()).for_request(&r, *request_id));
+                    }
+                    None
+                }
+                Event::DownloadRequestApproved { .. } => {
+                    let limit = match (r.max_downloads, r.requester_id) {
+                        (Some(n), _) => n.to_string(),
+                        (None, None) => "1".to_string(),
+                        (None, Some(_)) => "unlimited".to_string(),
+                    };
+                    let mut body = format!(
+                        "Your request to download \"{}\" has been approved.\n\nValid until: {}\nDownloads allowed: {limit}",
+                        r.document_name,
+                        format_expiry(r.expires_at)
+                    );
+                    if !r.approver_note.is_empty() {
+                        body.push_str(&format!("\nNote from approver: {}", r.approver_note));
+                    }
+                    let share_link_id = match r.requester_id {
+                        Some(_) => None,
+                        None => share_links::request_link_id(pool, *request_id).await?,
+                    };
+                    Notice::to_requester(&r, format!("Download approved: {}", r.document_name), body)
+                        .map(|n| n.with_share_link(share_link_id))
+                }
+                Event::DownloadRequestExpiringSoon { .. } => {
+                    if r.status != "approved" {
+                        return Ok(notices);
+                    }
+                    let body = format!(
+                        "Your approval to download \"{}\" expires at {}.",
+                        r.document_name,
+                        format_expiry(r.expires_at)
+                    );
+                    Notice::to_requester(&r, format!("Download approval expiring soon: {}", r.document_name), body)
+                }
+                _ => {
+                    let mut body = format!("Your request to download \"{}\" has been rejected.", r.document_name);
+                    if !r.reject_reason.is_empty() {
+                        body.push_str(&format!("\n\nReason: {}", r.reject_reason));
+                    }
+                    Notice::to_requester(&r, format!("Download rejected: {}", r.document_name), body)
+                }
+            };
+            notices.extend(notice.map(|n| n.for_request(&r, *request_id)));
+        }
+        _ => {}
+    }
+
+    Ok(notices)
+}
+
+pub async fn deliver(state: &AppState, conn: &mut PgConnection, event: &Event) -> anyhow::Result<()> {
+    let event_type = event.event_type();
+    if !INBOX_EVENT_TYPES.contains(&event_type) && !EMAIL_EVENT_TYPES.contains(&event_type) {
+        return Ok(());
+    }
+
+    let notices = build_notices(state, event).await?;
+    if notices.is_empty() {
+        return Ok(());
+    }
+
+    if INBOX_EVENT_TYPES.contains(&event_type) {
+        for n in &notices {
+            let Recipient::User(user_id) = n.recipient else {
+                continue;
+            };
+            sqlx::query(
+                r#"
+                insert into notifications (id, user_id, event_type, title, body, document_id, download_request_id)
+                values ($1,$2,$3,$4,$5,$6,$7)
+                "#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(event_type)
+            .bind(&n.title)
+            .bind(&n.body)
+            .bind(n.document_id)
+            .bind(n.download_request_id)
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    let mut emails: HashMap<Uuid, String> = HashMap::new();
+    if EMAIL_EVENT_TYPES.contains(&event_type) {
+        let user_ids: Vec<Uuid> = notices
+            .iter()
+            .filter_map(|n| match n.recipient {
+                Recipient::User(id) => Some(id),
+                Recipient::External(_) => None,
+            })
+            .collect();
+        emails = sqlx::query_as::<_, (Uuid, String)>(
+            r#"
+            select u.id, u.email
+            from users u
+            where u.id = any($1)
+              and u.email is not null and u.email <> ''
+              and not exists (
+                  select 1 from notification_preferences p
+                  where p.user_id = u.id and p.event_type = $2 and not p.email_enabled
+              )
+            "#,
+        )
+        .bind(&user_ids)
+        .bind(event_type)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .collect();
+    }
+
+    for n in notices {
+        let to = match n.recipient {
+            Recipient::User(id) => emails.get(&id).cloned(),
+            Recipient::External(email) => Some(email),
+        };
+        let Some(to) = to else {
+            continue;
+        };
+        let email = OutgoingEmail {
+            to,
+            subject: n.title,
+            body: n.body,
+            share_link_id: n.share_link_id,
+        };
+        jobs::enqueue(&mut *conn, jobs::SEND_EMAIL, serde_json::to_value(&email)?).await?;
+    }
+
+    Ok(())
+}
+
+// Download links are signed only when the email goes out, so queued jobs never hold a usable URL.
+pub fn render_email(state: &AppState, mut email: OutgoingEmail) -> OutgoingEmail {
+    if let Some(id) = email.share_link_id.take() {
+        email.body.push_str(&format!("\n\nDownload link: {}", share_links::download_url(&state.share_secret, id)));
+    }
+    email
+}
+
+async fn load_request(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<RequestMailRow>> {
+    sqlx::query_as::<_, RequestMailRow>(&format!(
+        r#"
+        select
+            r.document_id,
+            d.name as document_name,
+            r.requester_id,
+            coalesce(ru.username, r.external_email) as requester_name,
+            r.external_email,
+            r.applicant_name,
+            r.applicant_company,
+            r.applicant_contact,
+            r.message,
+            r.status,
+            r.approver_note,
+            r.reject_reason,
+            r.max_downloads,
+            r.expires_at,
+            {} as approver_ids
+        from download_requests r
+        join documents d on d.id = r.document_id
+        left join users ru on ru.id = r.requester_id
+        where r.id = $1
+        "#,
+        approvals::CURRENT_STAGE_APPROVERS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_preferences(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let rows = sqlx::query_as::<_, (String, bool)>(
+        "select event_type, email_enabled from notification_preferences where user_id = $1",
+    )
+    .bind(authed.id)
+    .fetch_all(&state.pool)
+    .await;
+
+    let stored: HashMap<String, bool> = match rows {
+        Ok(v) => v.into_iter().collect(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let out: Vec<NotificationPreferenceDto> = EMAIL_EVENT_TYPES
+        .iter()
+        .map(|t| NotificationPreferenceDto {
+            event_type: t.to_string(),
+            email_enabled: stored.get(*t).copied().unwrap_or(true),
+        })
+        .collect();
+
+    (StatusCode::OK, Json(out)).into_response()
+}
+
+pub async fn update_preferences(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<UpdateNotificationPreferencesRequest>,
+) -> impl IntoResponse {
+    if body
+        .preferences
+        .iter()
+        .any(|p| !EMAIL_EVENT_TYPES.contains(&p.event_type.as_str()))
+    {
+        return (StatusCode::BAD_REQUEST, "invalid event_type").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    for p in &body.preferences {
+        let res = sqlx::query(
+            r#"
+            insert into notification_preferences (user_id, event_type, email_enabled)
+            values ($1,$2,$3)
+            on conflict (user_id, event_type) do update set email_enabled = excluded.email_enabled
+            "#,
+        )
+        .bind(authed.id)
+        .bind(&p.event_type)
+        .bind(p.email_enabled)
+        .execute(&mut *tx)
+        .await;
+        if res.is_err() {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+        }
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationDto {
+    id: Uuid,
+    event_type: String,
+    title: String,
+    body: String,
+    document_id: Option<Uuid>,
+    download_request_id: Option<Uuid>,
+    read_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListNotificationsQuery {
+    unread_only: Option<bool>,
+    before: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnreadCountDto {
+    unread: i64,
+}
+
+pub async fn list_notifications(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Query(q): Query<ListNotificationsQuery>,
+) -> impl IntoResponse {
+    let rows = sqlx::query_as::<_, NotificationDto>(
+        r#"
+        select id, event_type, title, body, document_id, download_request_id, read_at, created_at
+        from notifications
+        where user_id = $1
+          and (not $2 or read_at is null)
+          and ($3::timestamptz is null or created_at < $3)
+        order by created_at desc
+        limit $4
+        "#,
+    )
+    .bind(authed.id)
+    .bind(q.unread_only.unwrap_or(false))
+    .bind(q.before)
+    .bind(q.limit.unwrap_or(50).clamp(1, 200))
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn unread_count(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let count = sqlx::query_scalar::<_, i64>("select count(*) from notifications where user_id = $1 and read_at is null")
+        .bind(authed.id)
+        .fetch_one(&state.pool)
+        .await;
+
+    match count {
+        Ok(unread) => (StatusCode::OK, Json(UnreadCountDto { unread })).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn mark_read(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let res = sqlx::query("update notifications set read_at = coalesce(read_at, now()) where id = $1 and user_id = $2")
+        .bind(id)
+        .bind(authed.id)
+        .execute(&state.pool)
+        .await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn mark_all_read(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let res = sqlx::query("update notifications set read_at = now() where user_id = $1 and read_at is null")
+        .bind(authed.id)
+        .execute(&state.pool)
+        .await;
+
+    match res {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
@@ -0,0 +1,309 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path as AxumPath, State},
+    http::{HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{approvals, client_ip, events, jobs, mailer::OutgoingEmail, AppState};
+
+const VERIFICATION_TTL_MINUTES: i32 = 15;
+const VERIFICATION_MAX_ATTEMPTS: i32 = 5;
+const VERIFICATIONS_PER_EMAIL_PER_HOUR: i64 = 5;
+const VERIFICATIONS_PER_IP_PER_HOUR: i64 = 20;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicDocumentDto {
+    id: Uuid,
+    name: String,
+    mime_type: String,
+    size: i64,
+    notes: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicDownloadRequest {
+    email: String,
+    applicant_name: String,
+    applicant_company: String,
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmVerificationRequest {
+    code: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationDto {
+    verification_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfirmedRequestDto {
+    request_id: Uuid,
+    status: &'static str,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct VerificationRow {
+    document_id: Uuid,
+    email: String,
+    code_hash: String,
+    applicant_name: String,
+    applicant_company: String,
+    message: String,
+    attempts: i32,
+    expires_at: DateTime<Utc>,
+    verified_at: Option<DateTime<Utc>>,
+}
+
+pub fn is_public_request(method: &Method, path: &str) -> bool {
+    (method == Method::GET || method == Method::POST) && path.starts_with("/public/")
+}
+
+fn code_hash(verification_id: Uuid, code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(verification_id.as_bytes());
+    hasher.update(code.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+async fn load_requestable(state: &AppState, id: Uuid) -> Result<PublicDocumentDto, axum::response::Response> {
+    let row = sqlx::query_as::<_, PublicDocumentDto>(
+        "select id, name, mime_type, size, notes from documents where id = $1 and requestable and deleted_at is null",
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match row {
+        Ok(Some(v)) => Ok(v),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "not found").into_response()),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()),
+    }
+}
+
+pub async fn get_document(State(state): State<AppState>, AxumPath(id): AxumPath<Uuid>) -> impl IntoResponse {
+    match load_requestable(&state, id).await {
+        Ok(doc) => (StatusCode::OK, Json(doc)).into_response(),
+        Err(resp) => resp,
+    }
+}
+
+pub async fn start_request(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<PublicDownloadRequest>,
+) -> impl IntoResponse {
+    let email = body.email.trim().to_lowercase();
+    let applicant_name = body.applicant_name.trim();
+    let applicant_company = body.applicant_company.trim();
+    if email.is_empty() || applicant_name.is_empty() || applicant_company.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    if email.parse::<lettre::Address>().is_err() {
+        return (StatusCode::BAD_REQUEST, "invalid email").into_response();
+    }
+
+    let doc = match load_requestable(&state, id).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    let ip = client_ip(addr, &headers).to_string();
+    let recent = sqlx::query_as::<_, (i64, i64)>(
+        r#"
+        select count(*) filter (where lower(email) = $1), count(*) filter (where ip = $2)
+        from email_verifications
+        where created_at > now() - interval '1 hour' and (lower(email) = $1 or ip = $2)
+        "#,
+    )
+    .bind(&email)
+    .bind(&ip)
+    .fetch_one(&state.pool)
+    .await;
+    match recent {
+        Ok((per_email, per_ip))
+            if per_email >= VERIFICATIONS_PER_EMAIL_PER_HOUR || per_ip >= VERIFICATIONS_PER_IP_PER_HOUR =>
+        {
+            return (StatusCode::TOO_MANY_REQUESTS, "too many requests").into_response();
+        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let verification_id = Uuid::new_v4();
+    let code = format!("{:06}", OsRng.next_u32() % 1_000_000);
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let expires_at = sqlx::query_scalar::<_, DateTime<Utc>>(
+        r#"
+        insert into email_verifications
+            (id, document_id, email, code_hash, applicant_name, applicant_company, message, expires_at, ip)
+        values ($1,$2,$3,$4,$5,$6,$7, now() + make_interval(mins => $8), $9)
+        returning expires_at
+        "#,
+    )
+    .bind(verification_id)
+    .bind(doc.id)
+    .bind(&email)
+    .bind(code_hash(verification_id, &code))
+    .bind(applicant_name)
+    .bind(applicant_company)
+    .bind(body.message.unwrap_or_default())
+    .bind(VERIFICATION_TTL_MINUTES)
+    .bind(&ip)
+    .fetch_one(&mut *tx)
+    .await;
+    let expires_at = match expires_at {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let mail = OutgoingEmail {
+        to: email,
+        subject: format!("Your verification code for {}", doc.name),
+        body: format!(
+            "Your verification code is {code}.\n\nEnter it to submit your request to download \"{}\". The code expires in {VERIFICATION_TTL_MINUTES} minutes.\n\nIf you did not request this, you can ignore this email.",
+            doc.name
+        ),
+        share_link_id: None,
+    };
+    if jobs::enqueue(&mut *tx, jobs::SEND_EMAIL, serde_json::json!(mail)).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => (StatusCode::ACCEPTED, Json(VerificationDto { verification_id, expires_at })).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn confirm_request(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<ConfirmVerificationRequest>,
+) -> impl IntoResponse {
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let row = sqlx::query_as::<_, VerificationRow>(
+        r#"
+        select document_id, email, code_hash, applicant_name, applicant_company, message, attempts, expires_at, verified_at
+        from email_verifications
+        where id = $1
+        for update
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await;
+    let v = match row {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    if v.verified_at.is_some() {
+        return (StatusCode::CONFLICT, "already verified").into_response();
+    }
+    if v.expires_at <= Utc::now() {
+        return (StatusCode::GONE, "code expired").into_response();
+    }
+    if v.attempts >= VERIFICATION_MAX_ATTEMPTS {
+        return (StatusCode::GONE, "too many attempts").into_response();
+    }
+
+    if code_hash(id, body.code.trim()) != v.code_hash {
+        let res = sqlx::query("update email_verifications set attempts = attempts + 1 where id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await;
+        if res.is_err() || tx.commit().await.is_err() {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+        }
+        return (StatusCode::FORBIDDEN, "invalid code").into_response();
+    }
+
+    let requestable = sqlx::query_scalar::<_, bool>("select requestable from documents where id = $1 and deleted_at is null")
+        .bind(v.document_id)
+        .fetch_optional(&mut *tx)
+        .await;
+    match requestable {
+        Ok(Some(true)) => {}
+        Ok(_) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let approval_policy_id = match approvals::resolve_policy(&mut *tx, v.document_id).await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let request_id = Uuid::new_v4();
+    let res = sqlx::query(
+        r#"
+        insert into download_requests (
+            id, document_id, requester_id, external_email,
+            applicant_name, applicant_company, applicant_contact, message,
+            status, approval_policy_id
+        ) values ($1,$2,null,$3,$4,$5,$3,$6,'pending',$7)
+        "#,
+    )
+    .bind(request_id)
+    .bind(v.document_id)
+    .bind(&v.email)
+    .bind(&v.applicant_name)
+    .bind(&v.applicant_company)
+    .bind(&v.message)
+    .bind(approval_policy_id)
+    .execute(&mut *tx)
+    .await;
+
+    if let Err(e) = res {
+        if let Some(db_err) = e.as_database_error() {
+            if db_err.constraint() == Some("idx_download_requests_external_active_unique") {
+                return (StatusCode::CONFLICT, "request already pending").into_response();
+            }
+        }
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let res = sqlx::query("update email_verifications set verified_at = now(), download_request_id = $2 where id = $1")
+        .bind(id)
+        .bind(request_id)
+        .execute(&mut *tx)
+        .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    if events::publish(&mut *tx, events::Event::DownloadRequestCreated { request_id }).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => (StatusCode::CREATED, Json(ConfirmedRequestDto { request_id, status: "pending" })).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
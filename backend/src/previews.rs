@@ -0,0 +1,411 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Path as AxumPath, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{DynamicImage, ImageFormat};
+use lopdf::Object;
+use pulldown_cmark::{Event, Options, Parser};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::{
+    doc_accessible, scanner,
+    storage::{self, DataKey},
+    watermark, AppState, AuthedUser, DocumentRow, DOCUMENT_COLUMNS,
+};
+
+const THUMBNAIL_SUFFIX: &str = ".thumbnail.png";
+const PREVIEW_IMAGE_SUFFIX: &str = ".preview.png";
+const PREVIEW_MARKDOWN_SUFFIX: &str = ".preview.markdown.html";
+const PREVIEW_TEXT_SUFFIX: &str = ".preview.text.html";
+const UNAVAILABLE_SUFFIX: &str = ".preview.none";
+const SUFFIXES: [&str; 7] = [
+    THUMBNAIL_SUFFIX,
+    PREVIEW_IMAGE_SUFFIX,
+    PREVIEW_MARKDOWN_SUFFIX,
+    PREVIEW_TEXT_SUFFIX,
+    UNAVAILABLE_SUFFIX,
+    // Older HTML renditions embedded the document name; they are never served but still purged.
+    ".preview.md.html",
+    ".preview.txt.html",
+];
+
+const THUMBNAIL_SIZE: u32 = 256;
+const PREVIEW_SIZE: u32 = 1024;
+const TEXT_PREVIEW_MAX_BYTES: usize = 256 * 1024;
+const PDF_RENDERER_DEFAULT: &str = "pdftoppm";
+const PDF_RENDER_TIMEOUT_SECS: u64 = 30;
+const PREVIEW_CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; img-src data:; sandbox";
+
+const CODE_EXTENSIONS: &[&str] = &[
+    "txt", "log", "csv", "tsv", "json", "yaml", "yml", "toml", "ini", "xml", "html", "css", "js", "mjs", "ts", "tsx",
+    "jsx", "rs", "go", "py", "rb", "java", "kt", "c", "h", "cc", "cpp", "hpp", "cs", "php", "sh", "bash", "sql", "swift",
+    "scala", "lua", "r", "vue", "svelte", "dockerfile", "makefile",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GeneratePayload {
+    pub document_id: Uuid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextKind {
+    Markdown,
+    Code,
+}
+
+impl TextKind {
+    fn suffix(self) -> &'static str {
+        match self {
+            TextKind::Markdown => PREVIEW_MARKDOWN_SUFFIX,
+            TextKind::Code => PREVIEW_TEXT_SUFFIX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rendition {
+    Thumbnail,
+    Preview,
+}
+
+fn extension(name: &str) -> String {
+    let lower = name.to_ascii_lowercase();
+    match lower.rsplit_once('.') {
+        Some((_, ext)) => ext.to_string(),
+        None => lower,
+    }
+}
+
+fn text_kind(doc: &DocumentRow) -> Option<TextKind> {
+    let ext = extension(&doc.name);
+    let mime = doc.mime_type.to_ascii_lowercase();
+    if ext == "md" || ext == "markdown" || mime == "text/markdown" {
+        return Some(TextKind::Markdown);
+    }
+    if mime.starts_with("text/")
+        || matches!(mime.as_str(), "application/json" | "application/xml" | "application/javascript")
+        || CODE_EXTENSIONS.contains(&ext.as_str())
+    {
+        return Some(TextKind::Code);
+    }
+    None
+}
+
+fn rendition_path(storage_root: &Path, doc: &DocumentRow, suffix: &str) -> PathBuf {
+    storage_root.join(format!("{}{suffix}", doc.storage_rel_path))
+}
+
+// Maps a stored rendition back to the file it was generated from.
+pub fn source_rel_path(rel: &str) -> Option<&str> {
+    SUFFIXES.iter().find_map(|s| rel.strip_suffix(s))
+}
+
+pub async fn relocate(storage_root: &Path, from: &str, to: &str) {
+    for suffix in SUFFIXES {
+        let path = storage_root.join(format!("{from}{suffix}"));
+        match tokio::fs::rename(&path, storage_root.join(format!("{to}{suffix}"))).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!(?e, from, to, "move preview failed"),
+        }
+    }
+}
+
+pub async fn purge(storage_root: &Path, storage_rel_path: &str) {
+    for suffix in SUFFIXES {
+        let path = storage_root.join(format!("{storage_rel_path}{suffix}"));
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!(?e, storage_rel_path, "remove preview failed"),
+        }
+    }
+}
+
+async fn write_rendition(path: &Path, key: Option<&DataKey>, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4().simple()));
+    storage::write(&tmp, key, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+fn encode_png(img: &DynamicImage, size: u32) -> anyhow::Result<Vec<u8>> {
+    let scaled = if img.width() > size || img.height() > size { img.thumbnail(size, size) } else { img.clone() };
+    let mut out = Vec::new();
+    scaled.to_rgba8().write_to(&mut io::Cursor::new(&mut out), ImageFormat::Png)?;
+    Ok(out)
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn render_html(kind: TextKind, data: &[u8]) -> String {
+    let truncated = data.len() > TEXT_PREVIEW_MAX_BYTES;
+    let text = String::from_utf8_lossy(&data[..data.len().min(TEXT_PREVIEW_MAX_BYTES)]);
+    let text = if truncated { text.trim_end_matches('\u{fffd}') } else { &text };
+
+    let body = match kind {
+        TextKind::Markdown => {
+            let parser = Parser::new_ext(text, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS)
+                .map(|e| match e {
+                    Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+                    other => other,
+                });
+            let mut html = String::new();
+            pulldown_cmark::html::push_html(&mut html, parser);
+            format!("<article>{html}</article>")
+        }
+        TextKind::Code => format!("<pre><code>{}</code></pre>", escape_html(text)),
+    };
+    let notice = if truncated { "<p class=\"truncated\">Preview truncated.</p>" } else { "" };
+
+    format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\"><title>Preview</title><style>\
+body{{font:14px/1.5 system-ui,sans-serif;margin:16px;color:#1f2328}}\
+pre{{white-space:pre-wrap;word-break:break-word;font:13px/1.45 ui-monospace,monospace}}\
+table{{border-collapse:collapse}}td,th{{border:1px solid #d0d7de;padding:4px 8px}}\
+.truncated{{color:#656d76;font-style:italic}}</style></head><body>{body}{notice}</body></html>"
+    )
+}
+
+async fn render_pdf_external(data: &[u8]) -> Option<DynamicImage> {
+    let renderer = std::env::var("PREVIEW_PDF_RENDERER").unwrap_or_else(|_| PDF_RENDERER_DEFAULT.to_string());
+    if renderer.trim().is_empty() {
+        return None;
+    }
+
+    let mut child = tokio::process::Command::new(renderer.trim())
+        .args(["-f", "1", "-l", "1", "-singlefile", "-png", "-scale-to"])
+        .arg(PREVIEW_SIZE.to_string())
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| debug!(?e, "pdf renderer unavailable"))
+        .ok()?;
+
+    let mut stdin = child.stdin.take()?;
+    let input = data.to_vec();
+    let writer = tokio::spawn(async move {
+        let _ = stdin.write_all(&input).await;
+    });
+    let output = tokio::time::timeout(Duration::from_secs(PDF_RENDER_TIMEOUT_SECS), child.wait_with_output()).await;
+    writer.abort();
+
+    match output {
+        Ok(Ok(out)) if out.status.success() => image::load_from_memory_with_format(&out.stdout, ImageFormat::Png).ok(),
+        Ok(Ok(out)) => {
+            warn!(status = ?out.status, "pdf renderer failed");
+            None
+        }
+        Ok(Err(e)) => {
+            warn!(?e, "pdf renderer failed");
+            None
+        }
+        Err(_) => {
+            warn!("pdf renderer timed out");
+            None
+        }
+    }
+}
+
+// Without a renderer, scanned PDFs still get a preview from the largest JPEG on their first page.
+fn first_page_jpeg(data: &[u8]) -> Option<DynamicImage> {
+    let pdf = lopdf::Document::load_mem(data).ok()?;
+    let page_id = *pdf.get_pages().values().next()?;
+    let resources = match watermark::inherited(&pdf, page_id, b"Resources")? {
+        Object::Dictionary(d) => d,
+        _ => return None,
+    };
+    let (_, xobjects) = pdf.dereference(resources.get(b"XObject").ok()?).ok()?;
+    let xobjects = xobjects.as_dict().ok()?;
+
+    let mut best: Option<(i64, &[u8])> = None;
+    for (_, obj) in xobjects.iter() {
+        let Ok((_, Object::Stream(stream))) = pdf.dereference(obj) else {
+            continue;
+        };
+        let dict = &stream.dict;
+        if dict.get(b"Subtype").and_then(Object::as_name).ok() != Some(b"Image".as_slice()) {
+            continue;
+        }
+        let dct = match dict.get(b"Filter") {
+            Ok(Object::Name(n)) => n == b"DCTDecode",
+            Ok(Object::Array(a)) => a.len() == 1 && a[0].as_name().ok() == Some(b"DCTDecode".as_slice()),
+            _ => false,
+        };
+        if !dct {
+            continue;
+        }
+        let area = dict.get(b"Width").and_then(Object::as_i64).unwrap_or(0)
+            * dict.get(b"Height").and_then(Object::as_i64).unwrap_or(0);
+        if best.is_none_or(|(a, _)| area > a) {
+            best = Some((area, &stream.content));
+        }
+    }
+
+    image::load_from_memory_with_format(best?.1, ImageFormat::Jpeg).ok()
+}
+
+async fn raster_source(data: Vec<u8>) -> Option<DynamicImage> {
+    if data.starts_with(b"%PDF-") {
+        if let Some(img) = render_pdf_external(&data).await {
+            return Some(img);
+        }
+        return tokio::task::spawn_blocking(move || first_page_jpeg(&data)).await.ok().flatten();
+    }
+    match image::guess_format(&data) {
+        Ok(f @ (ImageFormat::Png | ImageFormat::Jpeg)) => {
+            tokio::task::spawn_blocking(move || image::load_from_memory_with_format(&data, f).ok()).await.ok().flatten()
+        }
+        _ => None,
+    }
+}
+
+pub async fn generate(state: &AppState, doc: &DocumentRow) -> anyhow::Result<()> {
+    let key = state.keyring.document_key(doc)?;
+    let data = storage::read_document(state, doc).await.context("read document")?;
+
+    if let Some(kind) = text_kind(doc) {
+        let html = render_html(kind, &data);
+        write_rendition(&rendition_path(&state.storage_root, doc, kind.suffix()), key.as_ref(), html.as_bytes()).await?;
+        return Ok(());
+    }
+
+    let Some(img) = raster_source(data).await else {
+        write_rendition(&rendition_path(&state.storage_root, doc, UNAVAILABLE_SUFFIX), key.as_ref(), b"").await?;
+        return Ok(());
+    };
+    let (thumbnail, preview) = tokio::task::spawn_blocking(move || {
+        anyhow::Ok((encode_png(&img, THUMBNAIL_SIZE)?, encode_png(&img, PREVIEW_SIZE)?))
+    })
+    .await??;
+    write_rendition(&rendition_path(&state.storage_root, doc, THUMBNAIL_SUFFIX), key.as_ref(), &thumbnail).await?;
+    write_rendition(&rendition_path(&state.storage_root, doc, PREVIEW_IMAGE_SUFFIX), key.as_ref(), &preview).await?;
+    Ok(())
+}
+
+pub async fn run_generate(state: &AppState, document_id: Uuid) -> anyhow::Result<()> {
+    let doc = sqlx::query_as::<_, DocumentRow>(&format!(
+        "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"
+    ))
+    .bind(document_id)
+    .fetch_optional(&state.pool)
+    .await?;
+    match doc {
+        Some(doc) => generate(state, &doc).await,
+        None => Ok(()),
+    }
+}
+
+async fn load_rendition(state: &AppState, doc: &DocumentRow, rendition: Rendition) -> anyhow::Result<Option<(Vec<u8>, bool)>> {
+    let key = state.keyring.document_key(doc)?;
+    let text = text_kind(doc);
+    let suffix = match (rendition, text) {
+        (Rendition::Thumbnail, Some(_)) => return Ok(None),
+        (Rendition::Thumbnail, None) => THUMBNAIL_SUFFIX,
+        (Rendition::Preview, Some(kind)) => kind.suffix(),
+        (Rendition::Preview, None) => PREVIEW_IMAGE_SUFFIX,
+    };
+    let path = rendition_path(&state.storage_root, doc, suffix);
+
+    for attempt in 0..2 {
+        match storage::read(&path, key.as_ref()).await {
+            Ok(data) => return Ok(Some((data, text.is_some()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && attempt == 0 => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if text.is_none() && tokio::fs::try_exists(rendition_path(&state.storage_root, doc, UNAVAILABLE_SUFFIX)).await? {
+            return Ok(None);
+        }
+        generate(state, doc).await?;
+    }
+    Ok(None)
+}
+
+async fn serve(state: &AppState, authed: &AuthedUser, id: Uuid, rendition: Rendition) -> Response {
+    let row = sqlx::query_as::<_, DocumentRow>(&format!(
+        "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"
+    ))
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    let doc = match row {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    if !doc_accessible(&doc, authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if let Some((status, msg)) = scanner::blocked(&doc) {
+        return (status, msg).into_response();
+    }
+    if matches!(rendition, Rendition::Preview) && doc.watermark {
+        return (StatusCode::NOT_FOUND, "preview not available").into_response();
+    }
+
+    let (data, html) = match load_rendition(state, &doc, rendition).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "preview not available").into_response(),
+        Err(e) => {
+            error!(?e, document_id = %doc.id, "preview failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "preview failed").into_response();
+        }
+    };
+
+    let mut resp = Response::new(axum::body::Body::from(data));
+    let headers = resp.headers_mut();
+    if html {
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(PREVIEW_CSP));
+    } else {
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    }
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, max-age=300"));
+    resp
+}
+
+pub async fn thumbnail(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    serve(&state, &authed, id, Rendition::Thumbnail).await
+}
+
+pub async fn preview(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    serve(&state, &authed, id, Rendition::Preview).await
+}
@@ -0,0 +1,342 @@
+use std::path::Path;
+
+use axum::{
+    extract::{Extension, Path as AxumPath, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{is_admin, AppState, AuthedUser};
+
+const STORAGE_MIN_FREE_BYTES_DEFAULT: u64 = 1024 * 1024 * 1024;
+
+pub const USER_QUOTA_EXCEEDED: &str = "storage quota exceeded";
+pub const GROUP_QUOTA_EXCEEDED: &str = "group storage quota exceeded";
+pub const DISK_FULL: &str = "insufficient storage";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUsageDto {
+    user_id: Uuid,
+    username: String,
+    used_bytes: i64,
+    quota_bytes: Option<i64>,
+    effective_quota_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupUsageDto {
+    group_id: Uuid,
+    name: String,
+    used_bytes: i64,
+    quota_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskDto {
+    total_bytes: u64,
+    available_bytes: u64,
+    min_free_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReportDto {
+    default_quota_bytes: Option<i64>,
+    disk: Option<DiskDto>,
+    users: Vec<UserUsageDto>,
+    groups: Vec<GroupUsageDto>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MyUsageDto {
+    used_bytes: i64,
+    quota_bytes: Option<i64>,
+    groups: Vec<GroupUsageDto>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetQuotaRequest {
+    quota_bytes: Option<i64>,
+}
+
+// Applies to non-admin users without an explicit quota. Unset means unlimited.
+fn default_quota() -> Option<i64> {
+    std::env::var("STORAGE_QUOTA_DEFAULT_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .filter(|v| *v >= 0)
+}
+
+fn min_free_bytes() -> u64 {
+    std::env::var("STORAGE_MIN_FREE_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(STORAGE_MIN_FREE_BYTES_DEFAULT)
+}
+
+// Refuses writes that would leave less than STORAGE_MIN_FREE_BYTES on the storage volume.
+pub fn check_free_space(storage_root: &Path, bytes: u64) -> Result<(), &'static str> {
+    match fs4::available_space(storage_root) {
+        Ok(available) if available.saturating_sub(bytes) < min_free_bytes() => Err(DISK_FULL),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!(?e, "query free disk space failed");
+            Ok(())
+        }
+    }
+}
+
+// Adds `bytes` to the owner's usage counter if neither the user quota nor any quota of their groups
+// would be exceeded. Locks the user's counter and the limited groups (in id order) until the caller's
+// transaction ends, so concurrent uploads are checked against each other.
+pub async fn reserve(conn: &mut PgConnection, user_id: Uuid, bytes: i64) -> sqlx::Result<Result<(), &'static str>> {
+    sqlx::query("insert into storage_usage (user_id) values ($1) on conflict (user_id) do nothing")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    let (used, quota) = sqlx::query_as::<_, (i64, Option<i64>)>(
+        r#"
+        select su.used_bytes, coalesce(u.storage_quota_bytes, case when u.role = 'admin' then null else $2::bigint end)
+        from storage_usage su
+        join users u on u.id = su.user_id
+        where su.user_id = $1
+        for update of su
+        "#,
+    )
+    .bind(user_id)
+    .bind(default_quota())
+    .fetch_one(&mut *conn)
+    .await?;
+    if quota.is_some_and(|q| used.saturating_add(bytes) > q) {
+        return Ok(Err(USER_QUOTA_EXCEEDED));
+    }
+
+    let groups = sqlx::query_as::<_, (Uuid, i64)>(
+        r#"
+        select g.id, g.storage_quota_bytes
+        from groups g
+        join group_members gm on gm.group_id = g.id
+        where gm.user_id = $1 and g.storage_quota_bytes is not null
+        order by g.id
+        for update of g
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    for (group_id, quota) in groups {
+        let used = sqlx::query_scalar::<_, i64>(
+            r#"
+            select coalesce(sum(su.used_bytes), 0)::bigint
+            from storage_usage su
+            join group_members gm on gm.user_id = su.user_id
+            where gm.group_id = $1
+            "#,
+        )
+        .bind(group_id)
+        .fetch_one(&mut *conn)
+        .await?;
+        if used.saturating_add(bytes) > quota {
+            return Ok(Err(GROUP_QUOTA_EXCEEDED));
+        }
+    }
+
+    sqlx::query("update storage_usage set used_bytes = used_bytes + $2, updated_at = now() where user_id = $1")
+        .bind(user_id)
+        .bind(bytes)
+        .execute(&mut *conn)
+        .await?;
+    Ok(Ok(()))
+}
+
+pub async fn release(conn: &mut PgConnection, user_id: Uuid, bytes: i64) -> sqlx::Result<()> {
+    sqlx::query("update storage_usage set used_bytes = greatest(used_bytes - $2, 0), updated_at = now() where user_id = $1")
+        .bind(user_id)
+        .bind(bytes)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+const GROUP_USAGE_SELECT: &str = r#"
+    select
+        g.id as group_id, g.name,
+        coalesce((
+            select sum(su.used_bytes) from storage_usage su join group_members gm on gm.user_id = su.user_id where gm.group_id = g.id
+        ), 0)::bigint as used_bytes,
+        g.storage_quota_bytes as quota_bytes
+    from groups g
+"#;
+
+pub async fn my_usage(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let user = sqlx::query_as::<_, (i64, Option<i64>)>(
+        r#"
+        select coalesce(su.used_bytes, 0), coalesce(u.storage_quota_bytes, case when u.role = 'admin' then null else $2::bigint end)
+        from users u
+        left join storage_usage su on su.user_id = u.id
+        where u.id = $1
+        "#,
+    )
+    .bind(authed.id)
+    .bind(default_quota())
+    .fetch_one(&state.pool)
+    .await;
+    let groups = sqlx::query_as::<_, GroupUsageDto>(&format!(
+        "{GROUP_USAGE_SELECT} where g.storage_quota_bytes is not null and exists (select 1 from group_members gm where gm.group_id = g.id and gm.user_id = $1) order by lower(g.name)"
+    ))
+    .bind(authed.id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match (user, groups) {
+        (Ok((used_bytes, quota_bytes)), Ok(groups)) => (
+            StatusCode::OK,
+            Json(MyUsageDto {
+                used_bytes,
+                quota_bytes,
+                groups,
+            }),
+        )
+            .into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn usage_report(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let default_quota_bytes = default_quota();
+    let users = sqlx::query_as::<_, UserUsageDto>(
+        r#"
+        select
+            u.id as user_id, u.username,
+            coalesce(su.used_bytes, 0) as used_bytes,
+            u.storage_quota_bytes as quota_bytes,
+            coalesce(u.storage_quota_bytes, case when u.role = 'admin' then null else $1::bigint end) as effective_quota_bytes
+        from users u
+        left join storage_usage su on su.user_id = u.id
+        order by used_bytes desc, lower(u.username)
+        "#,
+    )
+    .bind(default_quota_bytes)
+    .fetch_all(&state.pool)
+    .await;
+    let groups = sqlx::query_as::<_, GroupUsageDto>(&format!("{GROUP_USAGE_SELECT} order by lower(g.name)"))
+        .fetch_all(&state.pool)
+        .await;
+    let disk = fs4::statvfs(&state.storage_root).ok().map(|s| DiskDto {
+        total_bytes: s.total_space(),
+        available_bytes: s.available_space(),
+        min_free_bytes: min_free_bytes(),
+    });
+
+    match (users, groups) {
+        (Ok(users), Ok(groups)) => (
+            StatusCode::OK,
+            Json(UsageReportDto {
+                default_quota_bytes,
+                disk,
+                users,
+                groups,
+            }),
+        )
+            .into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn set_user_quota(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<SetQuotaRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if body.quota_bytes.is_some_and(|v| v < 0) {
+        return (StatusCode::BAD_REQUEST, "invalid quota").into_response();
+    }
+
+    let res = sqlx::query("update users set storage_quota_bytes = $2 where id = $1")
+        .bind(id)
+        .bind(body.quota_bytes)
+        .execute(&state.pool)
+        .await;
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn set_group_quota(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<SetQuotaRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if body.quota_bytes.is_some_and(|v| v < 0) {
+        return (StatusCode::BAD_REQUEST, "invalid quota").into_response();
+    }
+
+    let res = sqlx::query("update groups set storage_quota_bytes = $2 where id = $1")
+        .bind(id)
+        .bind(body.quota_bytes)
+        .execute(&state.pool)
+        .await;
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+// Rebuilds the counters from `documents.size`. The table lock waits for in-flight uploads holding a
+// reservation, so their documents are counted exactly once.
+pub async fn recompute_usage(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let recomputed = async {
+        let mut tx = state.pool.begin().await?;
+        sqlx::query("lock table storage_usage in exclusive mode").execute(&mut *tx).await?;
+        let corrected = sqlx::query(
+            r#"
+            insert into storage_usage (user_id, used_bytes)
+            select u.id, coalesce(sum(d.size), 0)::bigint from users u left join documents d on d.owner_id = u.id group by u.id
+            on conflict (user_id) do update set used_bytes = excluded.used_bytes, updated_at = now()
+            where storage_usage.used_bytes <> excluded.used_bytes
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(corrected)
+    }
+    .await;
+
+    match recomputed {
+        Ok(corrected) => {
+            info!(corrected, "recomputed storage usage");
+            (StatusCode::OK, Json(serde_json::json!({ "corrected": corrected }))).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
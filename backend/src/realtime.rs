@@ -0,0 +1,411 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Extension, Query, State,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{SinkExt, StreamExt};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{events::Event, is_admin, AppState, AuthedUser};
+
+pub const CHANNEL: &str = "xdocs_events";
+const BROADCAST_CAPACITY: usize = 1024;
+const PING_INTERVAL_SECS: u64 = 30;
+const TICKET_TTL_SECS: i32 = 30;
+// Postgres rejects NOTIFY payloads of 8000 bytes or more.
+const NOTIFY_PAYLOAD_MAX: usize = 7900;
+const STORED_MESSAGE_TTL_MINUTES: i32 = 60;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Audience {
+    everyone: bool,
+    admins: bool,
+    user_ids: Vec<Uuid>,
+}
+
+impl Audience {
+    fn admins_and(user_ids: Vec<Uuid>) -> Self {
+        Self {
+            everyone: false,
+            admins: true,
+            user_ids,
+        }
+    }
+
+    fn includes(&self, user: &AuthedUser) -> bool {
+        self.everyone || (self.admins && is_admin(user)) || self.user_ids.contains(&user.id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushMessage {
+    audience: Audience,
+    #[serde(rename = "type")]
+    event_type: String,
+    data: serde_json::Value,
+    // A user whose open sockets are closed once this message has been delivered to them.
+    #[serde(default)]
+    disconnect: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Notification {
+    Inline(PushMessage),
+    Stored { stored: Uuid },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TicketDto {
+    ticket: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WsQuery {
+    ticket: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClientMessage<'a> {
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    data: &'a serde_json::Value,
+}
+
+pub fn channel() -> broadcast::Sender<Arc<PushMessage>> {
+    broadcast::channel(BROADCAST_CAPACITY).0
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RequestAudienceRow {
+    document_id: Uuid,
+    status: String,
+    requester_id: Option<Uuid>,
+    owner_id: Uuid,
+    approver_ids: Vec<Uuid>,
+}
+
+async fn document_audience(pool: &PgPool, document_id: Uuid) -> sqlx::Result<Option<Audience>> {
+    let row = sqlx::query_as::<_, (Uuid, String, Vec<Uuid>)>(
+        "select owner_id, permission, allowed_users from documents where id = $1",
+    )
+    .bind(document_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(owner_id, permission, allowed_users)| visibility_audience(owner_id, &permission, &allowed_users)))
+}
+
+fn visibility_audience(owner_id: Uuid, permission: &str, allowed_users: &[Uuid]) -> Audience {
+    let mut audience = Audience::admins_and(vec![owner_id]);
+    match permission {
+        "public" => audience.everyone = true,
+        "specific" => audience.user_ids.extend_from_slice(allowed_users),
+        _ => {}
+    }
+    audience
+}
+
+async fn build(pool: &PgPool, event: &Event) -> anyhow::Result<Option<PushMessage>> {
+    let (audience, data) = match event {
+        Event::UserRegistered { user_id } => (Audience::admins_and(vec![]), serde_json::json!({ "userId": user_id })),
+        Event::UserApproved { user_id } | Event::UserDisabled { user_id } => {
+            (Audience::admins_and(vec![*user_id]), serde_json::json!({ "userId": user_id }))
+        }
+        Event::DocumentCreated { document_id }
+        | Event::DocumentRestored { document_id }
+        | Event::DocumentShared { document_id, .. } => {
+            let Some(audience) = document_audience(pool, *document_id).await? else {
+                return Ok(None);
+            };
+            (audience, serde_json::json!({ "documentId": document_id }))
+        }
+        Event::DocumentInfected { document_id, .. } | Event::DocumentUnscannable { document_id, .. } => {
+            let Some(owner_id) = sqlx::query_scalar::<_, Uuid>("select owner_id from documents where id = $1")
+                .bind(document_id)
+                .fetch_optional(pool)
+                .await?
+            else {
+                return Ok(None);
+            };
+            (Audience::admins_and(vec![owner_id]), serde_json::json!({ "documentId": document_id }))
+        }
+        Event::DocumentUpdated {
+            document_id,
+            previous_permission,
+            previous_allowed_users,
+        } => {
+            let Some(mut audience) = document_audience(pool, *document_id).await? else {
+                return Ok(None);
+            };
+            let previous = visibility_audience(Uuid::nil(), previous_permission, previous_allowed_users);
+            audience.everyone |= previous.everyone;
+            audience.user_ids.extend(previous.user_ids.into_iter().filter(|u| !u.is_nil()));
+            (audience, serde_json::json!({ "documentId": document_id }))
+        }
+        Event::DocumentDeleted {
+            document_id,
+            owner_id,
+            permission,
+            allowed_users,
+        } => (
+            visibility_audience(*owner_id, permission, allowed_users),
+            serde_json::json!({ "documentId": document_id }),
+        ),
+        Event::DownloadRequestCreated { request_id }
+        | Event::DownloadRequestStageAdvanced { request_id }
+        | Event::DownloadRequestApproved { request_id }
+        | Event::DownloadRequestRejected { request_id }
+        | Event::DownloadRequestCancelled { request_id }
+        | Event::DownloadRequestExpired { request_id }
+        | Event::DownloadRequestExpiringSoon { request_id } => {
+            let Some(r) = sqlx::query_as::<_, RequestAudienceRow>(
+                r#"
+                select
+                    r.document_id, r.status, r.requester_id, d.owner_id,
+                    coalesce((
+                        select array_agg(distinct a.user_id)
+                        from approval_policy_stages s
+                        cross join lateral (
+                            select unnest(s.user_ids) as user_id
+                            union
+                            select gm.user_id from group_members gm where gm.group_id = s.group_id
+                        ) a
+                        where s.policy_id = r.approval_policy_id
+                    ), '{}') as approver_ids
+                from download_requests r
+                join documents d on d.id = r.document_id
+                where r.id = $1
+                "#,
+            )
+            .bind(request_id)
+            .fetch_optional(pool)
+            .await?
+            else {
+                return Ok(None);
+            };
+            let mut user_ids = vec![r.owner_id];
+            user_ids.extend(r.requester_id);
+            user_ids.extend(r.approver_ids);
+            (
+                Audience::admins_and(user_ids),
+                serde_json::json!({ "requestId": request_id, "documentId": r.document_id, "status": r.status }),
+            )
+        }
+    };
+
+    let mut audience = audience;
+    audience.user_ids.sort();
+    audience.user_ids.dedup();
+
+    Ok(Some(PushMessage {
+        audience,
+        event_type: event.event_type().to_string(),
+        data,
+        disconnect: match event {
+            Event::UserDisabled { user_id } => Some(*user_id),
+            _ => None,
+        },
+    }))
+}
+
+pub async fn notify(pool: &PgPool, event: &Event) -> anyhow::Result<()> {
+    let Some(message) = build(pool, event).await? else {
+        return Ok(());
+    };
+    let mut payload = serde_json::to_string(&message)?;
+    if payload.len() > NOTIFY_PAYLOAD_MAX {
+        let id = Uuid::new_v4();
+        sqlx::query("delete from realtime_messages where created_at < now() - make_interval(mins => $1)")
+            .bind(STORED_MESSAGE_TTL_MINUTES)
+            .execute(pool)
+            .await?;
+        sqlx::query("insert into realtime_messages (id, payload) values ($1, $2)")
+            .bind(id)
+            .bind(serde_json::to_value(&message)?)
+            .execute(pool)
+            .await?;
+        payload = serde_json::json!({ "stored": id }).to_string();
+    }
+    sqlx::query("select pg_notify($1, $2)")
+        .bind(CHANNEL)
+        .bind(payload)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn resolve(pool: &PgPool, payload: &str) -> anyhow::Result<Option<PushMessage>> {
+    match serde_json::from_str::<Notification>(payload)? {
+        Notification::Inline(message) => Ok(Some(message)),
+        Notification::Stored { stored } => {
+            let payload = sqlx::query_scalar::<_, serde_json::Value>("select payload from realtime_messages where id = $1")
+                .bind(stored)
+                .fetch_optional(pool)
+                .await?;
+            Ok(payload.map(serde_json::from_value).transpose()?)
+        }
+    }
+}
+
+pub fn spawn_listener(state: &AppState) {
+    let pool = state.pool.clone();
+    let tx = state.realtime.clone();
+    tokio::spawn(async move {
+        loop {
+            let mut listener = match PgListener::connect_with(&pool).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(?e, "realtime listener connect failed");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            if let Err(e) = listener.listen(CHANNEL).await {
+                error!(?e, "realtime listen failed");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+            info!("realtime listener started");
+
+            loop {
+                match listener.recv().await {
+                    Ok(n) => match resolve(&pool, n.payload()).await {
+                        Ok(Some(message)) => {
+                            let _ = tx.send(Arc::new(message));
+                        }
+                        Ok(None) => warn!("stored realtime message missing"),
+                        Err(e) => warn!(?e, "invalid realtime payload"),
+                    },
+                    Err(e) => {
+                        error!(?e, "realtime listener error");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn ticket_hash(ticket: &str) -> String {
+    hex::encode(Sha256::digest(ticket.as_bytes()))
+}
+
+// Browsers cannot set headers on WebSocket handshakes, so clients trade their bearer token for a short-lived,
+// single-use ticket and pass that in the /ws query string instead.
+pub async fn create_ticket(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let mut raw = [0u8; 32];
+    OsRng.fill_bytes(&mut raw);
+    let ticket = hex::encode(raw);
+
+    if let Err(e) = sqlx::query("delete from ws_tickets where expires_at < now()").execute(&state.pool).await {
+        warn!(?e, "expire ws tickets failed");
+    }
+    let expires_at = sqlx::query_scalar::<_, DateTime<Utc>>(
+        "insert into ws_tickets (ticket_hash, user_id, expires_at) values ($1, $2, now() + make_interval(secs => $3)) returning expires_at",
+    )
+    .bind(ticket_hash(&ticket))
+    .bind(authed.id)
+    .bind(TICKET_TTL_SECS)
+    .fetch_one(&state.pool)
+    .await;
+
+    match expires_at {
+        Ok(expires_at) => (StatusCode::CREATED, Json(TicketDto { ticket, expires_at })).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+async fn redeem_ticket(pool: &PgPool, ticket: &str) -> sqlx::Result<Option<AuthedUser>> {
+    let row = sqlx::query_as::<_, (Uuid, String)>(
+        r#"
+        with t as (
+            delete from ws_tickets where ticket_hash = $1 returning user_id, expires_at
+        )
+        select u.id, u.role
+        from t
+        join users u on u.id = t.user_id
+        where t.expires_at > now() and u.status = 'active'
+        "#,
+    )
+    .bind(ticket_hash(ticket))
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(id, role)| AuthedUser { id, role }))
+}
+
+pub async fn ws_handler(State(state): State<AppState>, Query(query): Query<WsQuery>, ws: WebSocketUpgrade) -> impl IntoResponse {
+    let Some(ticket) = query.ticket.filter(|t| !t.is_empty()) else {
+        return (StatusCode::UNAUTHORIZED, "missing ticket").into_response();
+    };
+    let authed = match redeem_ticket(&state.pool, &ticket).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, "invalid ticket").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let rx = state.realtime.subscribe();
+    ws.on_upgrade(move |socket| client(socket, rx, authed))
+}
+
+async fn client(socket: WebSocket, mut rx: broadcast::Receiver<Arc<PushMessage>>, authed: AuthedUser) {
+    let (mut sink, mut stream) = socket.split();
+    let mut ping = tokio::time::interval(Duration::from_secs(PING_INTERVAL_SECS));
+
+    loop {
+        let mut disconnect = false;
+        let outgoing = tokio::select! {
+            received = rx.recv() => match received {
+                Ok(message) => {
+                    if !message.audience.includes(&authed) {
+                        continue;
+                    }
+                    disconnect = message.disconnect == Some(authed.id);
+                    let text = ClientMessage {
+                        event_type: &message.event_type,
+                        data: &message.data,
+                    };
+                    match serde_json::to_string(&text) {
+                        Ok(v) => Message::Text(v.into()),
+                        Err(_) => continue,
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    Message::Text(r#"{"type":"resync","data":{}}"#.into())
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = stream.next() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = ping.tick() => Message::Ping(Vec::new().into()),
+        };
+
+        if sink.send(outgoing).await.is_err() {
+            break;
+        }
+        if disconnect {
+            let frame = CloseFrame {
+                code: CLOSE_POLICY_VIOLATION,
+                reason: "account disabled".into(),
+            };
+            let _ = sink.send(Message::Close(Some(frame))).await;
+            break;
+        }
+    }
+}
@@ -0,0 +1,736 @@
+use axum::{
+    extract::{Extension, Path as AxumPath, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgExecutor};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{events, is_admin, normalize_folder, trash, AppState, AuthedUser};
+
+const AUDIT_LIMIT_DEFAULT: i64 = 100;
+const AUDIT_LIMIT_MAX: i64 = 1000;
+const ENFORCE_BATCH: i64 = 100;
+
+// Joins the effective retention policy `p` of document `d`: its own policy, else the deepest folder policy.
+pub const EFFECTIVE_POLICY_JOIN: &str = r#"
+    left join retention_policies p on p.id = coalesce(
+        d.retention_policy_id,
+        (
+            select fp.policy_id
+            from folder_retention_policies fp
+            where fp.folder = '' or d.folder = fp.folder or starts_with(d.folder, fp.folder || '/')
+            order by length(fp.folder) desc
+            limit 1
+        )
+    )
+"#;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicyDto {
+    id: Uuid,
+    name: String,
+    min_retention_days: Option<i32>,
+    max_retention_days: Option<i32>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionPolicyRequest {
+    name: String,
+    min_retention_days: Option<i32>,
+    max_retention_days: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssignPolicyRequest {
+    policy_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssignFolderPolicyRequest {
+    folder: String,
+    policy_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderPolicyDto {
+    folder: String,
+    policy_id: Uuid,
+    policy_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegalHoldRequest {
+    reason: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentRetentionDto {
+    document_id: Uuid,
+    owner_id: Uuid,
+    policy_id: Option<Uuid>,
+    policy_name: Option<String>,
+    inherited: bool,
+    retain_until: Option<DateTime<Utc>>,
+    purge_after: Option<DateTime<Utc>>,
+    legal_hold: bool,
+    legal_hold_reason: String,
+    legal_hold_by: Option<Uuid>,
+    legal_hold_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    document_id: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntryDto {
+    id: Uuid,
+    document_id: Option<Uuid>,
+    actor_id: Option<Uuid>,
+    actor_name: Option<String>,
+    action: String,
+    details: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ExpiredRow {
+    id: Uuid,
+    owner_id: Uuid,
+    permission: String,
+    allowed_users: Vec<Uuid>,
+    deleted_at: Option<DateTime<Utc>>,
+    policy_id: Uuid,
+    purge_after: DateTime<Utc>,
+}
+
+pub async fn audit<'e>(
+    exec: impl PgExecutor<'e>,
+    document_id: Option<Uuid>,
+    actor_id: Option<Uuid>,
+    action: &str,
+    details: serde_json::Value,
+) -> sqlx::Result<()> {
+    sqlx::query("insert into retention_audit_log (id, document_id, actor_id, action, details) values ($1,$2,$3,$4,$5)")
+        .bind(Uuid::new_v4())
+        .bind(document_id)
+        .bind(actor_id)
+        .bind(action)
+        .bind(details)
+        .execute(exec)
+        .await?;
+    Ok(())
+}
+
+// Returns why the document (locked by the caller) may not be deleted yet, if anything.
+pub async fn deletion_block<'e>(exec: impl PgExecutor<'e>, document_id: Uuid) -> sqlx::Result<Option<&'static str>> {
+    let row = sqlx::query_as::<_, (bool, bool)>(&format!(
+        r#"
+        select d.legal_hold,
+            coalesce(d.created_at + make_interval(days => p.min_retention_days) > now(), false)
+        from documents d
+        {EFFECTIVE_POLICY_JOIN}
+        where d.id = $1
+        "#
+    ))
+    .bind(document_id)
+    .fetch_optional(exec)
+    .await?;
+
+    Ok(match row {
+        Some((true, _)) => Some("document is under legal hold"),
+        Some((false, true)) => Some("retention period has not ended"),
+        _ => None,
+    })
+}
+
+// Minimum retention days and policy the document would have if it lived in `folder`.
+async fn effective_policy(conn: &mut PgConnection, document_id: Uuid, folder: &str) -> sqlx::Result<(i32, Option<Uuid>)> {
+    sqlx::query_as::<_, (i32, Option<Uuid>)>(&format!(
+        r#"
+        select coalesce(p.min_retention_days, 0), p.id
+        from (select id, retention_policy_id, $2::text as folder from documents where id = $1) d
+        {EFFECTIVE_POLICY_JOIN}
+        "#
+    ))
+    .bind(document_id)
+    .bind(folder)
+    .fetch_one(&mut *conn)
+    .await
+}
+
+// Refuses moving the document (locked by the caller) out from under its inherited minimum retention, and
+// returns audit details when the move changes which policy governs it.
+pub async fn check_folder_move(
+    conn: &mut PgConnection,
+    document_id: Uuid,
+    from: &str,
+    to: &str,
+) -> sqlx::Result<Result<Option<serde_json::Value>, &'static str>> {
+    if from == to {
+        return Ok(Ok(None));
+    }
+    let (min_before, policy_before) = effective_policy(conn, document_id, from).await?;
+    let (min_after, policy_after) = effective_policy(conn, document_id, to).await?;
+    if min_after < min_before {
+        return Ok(Err("folder move would shorten retention"));
+    }
+    Ok(Ok((policy_before != policy_after).then(|| {
+        serde_json::json!({ "from": from, "to": to, "previousPolicyId": policy_before, "policyId": policy_after })
+    })))
+}
+
+fn validate_policy(body: &RetentionPolicyRequest) -> Result<(), &'static str> {
+    if body.name.trim().is_empty() {
+        return Err("missing fields");
+    }
+    match (body.min_retention_days, body.max_retention_days) {
+        (None, None) => Err("min_retention_days or max_retention_days is required"),
+        (Some(min), _) if min < 0 => Err("invalid min_retention_days"),
+        (_, Some(max)) if max < 1 => Err("invalid max_retention_days"),
+        (Some(min), Some(max)) if min > max => Err("min_retention_days exceeds max_retention_days"),
+        _ => Ok(()),
+    }
+}
+
+fn policy_write_error(e: sqlx::Error) -> Response {
+    if let Some(db_err) = e.as_database_error() {
+        if db_err.is_unique_violation() {
+            return (StatusCode::CONFLICT, "policy name already exists").into_response();
+        }
+        if db_err.is_foreign_key_violation() {
+            return (StatusCode::BAD_REQUEST, "unknown policy").into_response();
+        }
+    }
+    (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()
+}
+
+pub async fn list_policies(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, RetentionPolicyDto>(
+        "select id, name, min_retention_days, max_retention_days, created_at, updated_at from retention_policies order by name",
+    )
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn create_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<RetentionPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if let Err(msg) = validate_policy(&body) {
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let created = sqlx::query_as::<_, RetentionPolicyDto>(
+        r#"
+        insert into retention_policies (id, name, min_retention_days, max_retention_days, created_by)
+        values ($1,$2,$3,$4,$5)
+        returning id, name, min_retention_days, max_retention_days, created_at, updated_at
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(body.name.trim())
+    .bind(body.min_retention_days)
+    .bind(body.max_retention_days)
+    .bind(authed.id)
+    .fetch_one(&mut *tx)
+    .await;
+    let policy = match created {
+        Ok(v) => v,
+        Err(e) => return policy_write_error(e),
+    };
+
+    let details = serde_json::json!({
+        "policyId": policy.id,
+        "name": policy.name,
+        "minRetentionDays": policy.min_retention_days,
+        "maxRetentionDays": policy.max_retention_days,
+    });
+    if audit(&mut *tx, None, Some(authed.id), "policy.created", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => (StatusCode::CREATED, Json(policy)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn update_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<RetentionPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if let Err(msg) = validate_policy(&body) {
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let updated = sqlx::query_as::<_, RetentionPolicyDto>(
+        r#"
+        update retention_policies
+        set name = $2, min_retention_days = $3, max_retention_days = $4, updated_at = now()
+        where id = $1
+        returning id, name, min_retention_days, max_retention_days, created_at, updated_at
+        "#,
+    )
+    .bind(id)
+    .bind(body.name.trim())
+    .bind(body.min_retention_days)
+    .bind(body.max_retention_days)
+    .fetch_optional(&mut *tx)
+    .await;
+    let policy = match updated {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => return policy_write_error(e),
+    };
+
+    let details = serde_json::json!({
+        "policyId": policy.id,
+        "name": policy.name,
+        "minRetentionDays": policy.min_retention_days,
+        "maxRetentionDays": policy.max_retention_days,
+    });
+    if audit(&mut *tx, None, Some(authed.id), "policy.updated", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => (StatusCode::OK, Json(policy)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn delete_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let deleted = sqlx::query_scalar::<_, String>("delete from retention_policies where id = $1 returning name")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await;
+    let name = match deleted {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let details = serde_json::json!({ "policyId": id, "name": name });
+    if audit(&mut *tx, None, Some(authed.id), "policy.deleted", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn assign_document_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<AssignPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let previous = sqlx::query_scalar::<_, Option<Uuid>>(
+        r#"
+        update documents d set retention_policy_id = $2, updated_at = now()
+        from documents old
+        where d.id = $1 and old.id = d.id
+        returning old.retention_policy_id
+        "#,
+    )
+    .bind(id)
+    .bind(body.policy_id)
+    .fetch_optional(&mut *tx)
+    .await;
+    let previous = match previous {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => return policy_write_error(e),
+    };
+
+    let details = serde_json::json!({ "policyId": body.policy_id, "previousPolicyId": previous });
+    if audit(&mut *tx, Some(id), Some(authed.id), "policy.assigned", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_folder_policies(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, FolderPolicyDto>(
+        r#"
+        select fp.folder, fp.policy_id, p.name as policy_name
+        from folder_retention_policies fp
+        join retention_policies p on p.id = fp.policy_id
+        order by fp.folder
+        "#,
+    )
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn assign_folder_policy(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<AssignFolderPolicyRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let Some(folder) = normalize_folder(&body.folder) else {
+        return (StatusCode::BAD_REQUEST, "invalid folder").into_response();
+    };
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let res = match body.policy_id {
+        Some(policy_id) => {
+            sqlx::query(
+                "insert into folder_retention_policies (folder, policy_id) values ($1,$2) on conflict (folder) do update set policy_id = excluded.policy_id",
+            )
+            .bind(&folder)
+            .bind(policy_id)
+            .execute(&mut *tx)
+            .await
+        }
+        None => {
+            sqlx::query("delete from folder_retention_policies where folder = $1")
+                .bind(&folder)
+                .execute(&mut *tx)
+                .await
+        }
+    };
+    if let Err(e) = res {
+        return policy_write_error(e);
+    }
+
+    let details = serde_json::json!({ "folder": folder, "policyId": body.policy_id });
+    if audit(&mut *tx, None, Some(authed.id), "folder_policy.assigned", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn get_document_retention(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let row = sqlx::query_as::<_, DocumentRetentionDto>(&format!(
+        r#"
+        select
+            d.id as document_id, d.owner_id,
+            p.id as policy_id, p.name as policy_name,
+            (p.id is not null and d.retention_policy_id is null) as inherited,
+            d.created_at + make_interval(days => p.min_retention_days) as retain_until,
+            d.created_at + make_interval(days => p.max_retention_days) as purge_after,
+            d.legal_hold, d.legal_hold_reason, d.legal_hold_by, d.legal_hold_at
+        from documents d
+        {EFFECTIVE_POLICY_JOIN}
+        where d.id = $1
+        "#
+    ))
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match row {
+        Ok(Some(v)) if is_admin(&authed) || v.owner_id == authed.id => (StatusCode::OK, Json(v)).into_response(),
+        Ok(Some(_)) => (StatusCode::FORBIDDEN, "forbidden").into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn place_legal_hold(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<LegalHoldRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    let reason = body.reason.trim();
+    if reason.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let held = sqlx::query_scalar::<_, bool>("select legal_hold from documents where id = $1 for update")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await;
+    match held {
+        Ok(Some(false)) => {}
+        Ok(Some(true)) => return (StatusCode::CONFLICT, "document is already under legal hold").into_response(),
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let res = sqlx::query(
+        "update documents set legal_hold = true, legal_hold_reason = $2, legal_hold_by = $3, legal_hold_at = now() where id = $1",
+    )
+    .bind(id)
+    .bind(reason)
+    .bind(authed.id)
+    .execute(&mut *tx)
+    .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let details = serde_json::json!({ "reason": reason });
+    if audit(&mut *tx, Some(id), Some(authed.id), "legal_hold.placed", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn release_legal_hold(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let hold = sqlx::query_as::<_, (bool, String, Option<Uuid>, Option<DateTime<Utc>>)>(
+        "select legal_hold, legal_hold_reason, legal_hold_by, legal_hold_at from documents where id = $1 for update",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await;
+    let (reason, held_by, held_at) = match hold {
+        Ok(Some((true, reason, held_by, held_at))) => (reason, held_by, held_at),
+        Ok(Some((false, ..))) => return (StatusCode::CONFLICT, "document is not under legal hold").into_response(),
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let res = sqlx::query(
+        "update documents set legal_hold = false, legal_hold_reason = '', legal_hold_by = null, legal_hold_at = null where id = $1",
+    )
+    .bind(id)
+    .execute(&mut *tx)
+    .await;
+    if res.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let details = serde_json::json!({ "reason": reason, "placedBy": held_by, "placedAt": held_at });
+    if audit(&mut *tx, Some(id), Some(authed.id), "legal_hold.released", details).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_audit(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, AuditEntryDto>(
+        r#"
+        select a.id, a.document_id, a.actor_id, u.username as actor_name, a.action, a.details, a.created_at
+        from retention_audit_log a
+        left join users u on u.id = a.actor_id
+        where ($1::uuid is null or a.document_id = $1)
+        order by a.created_at desc
+        limit $2
+        "#,
+    )
+    .bind(query.document_id)
+    .bind(query.limit.unwrap_or(AUDIT_LIMIT_DEFAULT).clamp(1, AUDIT_LIMIT_MAX))
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+async fn expire_document(state: &AppState, id: Uuid) -> anyhow::Result<bool> {
+    let mut tx = state.pool.begin().await?;
+    let row = sqlx::query_as::<_, ExpiredRow>(&format!(
+        r#"
+        select d.id, d.owner_id, d.permission, d.allowed_users, d.deleted_at, p.id as policy_id,
+            d.created_at + make_interval(days => p.max_retention_days) as purge_after
+        from documents d
+        {EFFECTIVE_POLICY_JOIN}
+        where d.id = $1 and not d.legal_hold
+            and d.created_at + make_interval(days => p.max_retention_days) <= now()
+        for update of d
+        "#
+    ))
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(doc) = row else {
+        return Ok(false);
+    };
+
+    if doc.deleted_at.is_none() {
+        sqlx::query("update documents set deleted_at = now(), deleted_by = null where id = $1")
+            .bind(doc.id)
+            .execute(&mut *tx)
+            .await?;
+        let event = events::Event::DocumentDeleted {
+            document_id: doc.id,
+            owner_id: doc.owner_id,
+            permission: doc.permission,
+            allowed_users: doc.allowed_users,
+        };
+        events::publish(&mut *tx, event).await?;
+    }
+
+    let details = serde_json::json!({ "policyId": doc.policy_id, "purgeAfter": doc.purge_after });
+    audit(&mut *tx, Some(doc.id), None, "retention.expired", details).await?;
+    tx.commit().await?;
+
+    trash::purge_document(state, doc.id).await
+}
+
+pub async fn enforce(state: &AppState) -> anyhow::Result<()> {
+    let mut purged = 0u64;
+    let mut seen: Vec<Uuid> = vec![];
+    loop {
+        let ids = sqlx::query_scalar::<_, Uuid>(&format!(
+            r#"
+            select d.id
+            from documents d
+            {EFFECTIVE_POLICY_JOIN}
+            where not d.legal_hold
+                and d.created_at + make_interval(days => p.max_retention_days) <= now()
+                and d.id <> all($1)
+            order by d.created_at
+            limit $2
+            "#
+        ))
+        .bind(&seen)
+        .bind(ENFORCE_BATCH)
+        .fetch_all(&state.pool)
+        .await?;
+        if ids.is_empty() {
+            break;
+        }
+        for id in ids {
+            seen.push(id);
+            if expire_document(state, id).await? {
+                purged += 1;
+            }
+        }
+    }
+
+    if purged > 0 {
+        info!(purged, "purged documents past maximum retention");
+    }
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Path as AxumPath, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{events, is_admin, jobs, previews, publish_event, storage, AppState, AuthedUser, DocumentRow, DOCUMENT_COLUMNS};
+
+const CLAMD_ADDRESS_DEFAULT: &str = "tcp://127.0.0.1:3310";
+const AV_SCAN_TIMEOUT_SECS_DEFAULT: u64 = 120;
+const CLAMD_CHUNK: usize = 64 * 1024;
+const RESCAN_LIMIT: i64 = 10_000;
+
+const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+pub const QUARANTINED: &str = "quarantined";
+pub const CLEAN: &str = "clean";
+pub const INFECTED: &str = "infected";
+pub const UNSCANNED: &str = "unscanned";
+pub const TOO_LARGE: &str = "too_large";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScanPayload {
+    pub document_id: Uuid,
+}
+
+#[derive(Clone)]
+enum ClamdAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+#[derive(Clone)]
+enum Backend {
+    Clamd(ClamdAddress),
+    // Flags only the EICAR test file; for development and tests without a clamd.
+    Local,
+}
+
+#[derive(Clone)]
+pub struct Scanner {
+    backend: Option<Backend>,
+    timeout: Duration,
+}
+
+pub enum Verdict {
+    Clean,
+    Infected(String),
+    TooLarge(String),
+}
+
+impl Scanner {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let kind = std::env::var("AV_SCANNER").unwrap_or_else(|_| "none".to_string());
+        let backend = match kind.trim() {
+            "" | "none" => None,
+            "local" => Some(Backend::Local),
+            "clamd" => {
+                let address = std::env::var("CLAMD_ADDRESS").unwrap_or_else(|_| CLAMD_ADDRESS_DEFAULT.to_string());
+                let address = if let Some(path) = address.strip_prefix("unix://") {
+                    ClamdAddress::Unix(PathBuf::from(path))
+                } else if let Some(host) = address.strip_prefix("tcp://") {
+                    ClamdAddress::Tcp(host.to_string())
+                } else {
+                    anyhow::bail!("CLAMD_ADDRESS must start with tcp:// or unix://");
+                };
+                Some(Backend::Clamd(address))
+            }
+            other => anyhow::bail!("Invalid AV_SCANNER: {other}"),
+        };
+        let timeout = std::env::var("AV_SCAN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(AV_SCAN_TIMEOUT_SECS_DEFAULT);
+        if backend.is_some() {
+            info!(scanner = %kind.trim(), "virus scanning enabled");
+        }
+        Ok(Self {
+            backend,
+            timeout: Duration::from_secs(timeout.max(1)),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    pub async fn scan(&self, reader: storage::BoxReader) -> anyhow::Result<Verdict> {
+        match &self.backend {
+            None => anyhow::bail!("no virus scanner configured"),
+            Some(Backend::Local) => local_scan(reader).await,
+            Some(Backend::Clamd(address)) => tokio::time::timeout(self.timeout, clamd_scan(address, reader))
+                .await
+                .context("clamd scan timed out")?,
+        }
+    }
+}
+
+async fn local_scan(mut reader: storage::BoxReader) -> anyhow::Result<Verdict> {
+    let mut window = Vec::with_capacity(CLAMD_CHUNK + EICAR.len());
+    let mut buf = vec![0u8; CLAMD_CHUNK];
+    loop {
+        let n = reader.read(&mut buf).await.context("read document")?;
+        if n == 0 {
+            return Ok(Verdict::Clean);
+        }
+        window.extend_from_slice(&buf[..n]);
+        if window.windows(EICAR.len()).any(|w| w == EICAR) {
+            return Ok(Verdict::Infected("Eicar-Test-Signature".to_string()));
+        }
+        window.drain(..window.len().saturating_sub(EICAR.len() - 1));
+    }
+}
+
+async fn clamd_scan(address: &ClamdAddress, reader: storage::BoxReader) -> anyhow::Result<Verdict> {
+    let reply = match address {
+        ClamdAddress::Tcp(host) => instream(tokio::net::TcpStream::connect(host).await.context("connect clamd")?, reader).await?,
+        ClamdAddress::Unix(path) => instream(tokio::net::UnixStream::connect(path).await.context("connect clamd")?, reader).await?,
+    };
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").map(str::trim).unwrap_or(reply);
+    if result == "OK" {
+        return Ok(Verdict::Clean);
+    }
+    if result.contains("size limit exceeded") {
+        return Ok(Verdict::TooLarge(result.to_string()));
+    }
+    match result.strip_suffix(" FOUND") {
+        Some(signature) => Ok(Verdict::Infected(signature.trim().to_string())),
+        None => anyhow::bail!("clamd: {reply}"),
+    }
+}
+
+// clamd INSTREAM: length-prefixed chunks terminated by a zero length, answered with a single reply line.
+// clamd replies early and closes the connection when the stream exceeds its size limit.
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    mut reader: storage::BoxReader,
+) -> anyhow::Result<String> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut buf = vec![0u8; CLAMD_CHUNK];
+    loop {
+        let n = reader.read(&mut buf).await.context("read document")?;
+        let sent = async {
+            stream.write_all(&(n as u32).to_be_bytes()).await?;
+            stream.write_all(&buf[..n]).await?;
+            stream.flush().await
+        }
+        .await;
+        if let Err(e) = sent {
+            return match read_reply(&mut stream).await {
+                Ok(reply) if !reply.trim_matches(['\0', '\n', ' ']).is_empty() => Ok(reply),
+                _ => Err(e.into()),
+            };
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(read_reply(&mut stream).await?)
+}
+
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<String> {
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    Ok(String::from_utf8_lossy(&reply).into_owned())
+}
+
+// Why the document's content may not be handed out, if it may not.
+pub fn blocked(doc: &DocumentRow) -> Option<(StatusCode, &'static str)> {
+    match doc.scan_status.as_str() {
+        QUARANTINED => Some((StatusCode::CONFLICT, "document is awaiting virus scan")),
+        INFECTED => Some((StatusCode::FORBIDDEN, "document failed virus scan")),
+        TOO_LARGE => Some((StatusCode::FORBIDDEN, "document is too large to virus scan")),
+        _ => None,
+    }
+}
+
+pub async fn run_scan(state: &AppState, document_id: Uuid) -> anyhow::Result<()> {
+    let doc = sqlx::query_as::<_, DocumentRow>(&format!(
+        "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1"
+    ))
+    .bind(document_id)
+    .fetch_optional(&state.pool)
+    .await?;
+    let Some(doc) = doc else {
+        return Ok(());
+    };
+
+    if !state.scanner.enabled() {
+        if doc.scan_status == QUARANTINED {
+            warn!(%document_id, "virus scanning disabled; releasing quarantined document unscanned");
+            sqlx::query("update documents set scan_status = $2 where id = $1 and scan_status = $3")
+                .bind(document_id)
+                .bind(UNSCANNED)
+                .bind(QUARANTINED)
+                .execute(&state.pool)
+                .await?;
+        }
+        return Ok(());
+    }
+
+    let reader = storage::open_document(state, &doc).await.context("open document")?;
+    let (status, signature) = match state.scanner.scan(reader).await? {
+        Verdict::Clean => (CLEAN, None),
+        Verdict::Infected(signature) => (INFECTED, Some(signature)),
+        Verdict::TooLarge(reason) => {
+            warn!(%document_id, %reason, "document too large to virus scan");
+            sqlx::query(
+                "update documents set scan_status = $2, scan_signature = null, scanned_at = now() where id = $1",
+            )
+            .bind(document_id)
+            .bind(TOO_LARGE)
+            .execute(&state.pool)
+            .await?;
+            if doc.scan_status != TOO_LARGE {
+                publish_event(&state.pool, events::Event::DocumentUnscannable { document_id, reason }).await;
+            }
+            return Ok(());
+        }
+    };
+    sqlx::query("update documents set scan_status = $2, scan_signature = $3, scanned_at = now() where id = $1")
+        .bind(document_id)
+        .bind(status)
+        .bind(&signature)
+        .execute(&state.pool)
+        .await?;
+
+    match signature {
+        Some(signature) => {
+            warn!(%document_id, %signature, "virus detected");
+            if doc.scan_status != INFECTED {
+                publish_event(&state.pool, events::Event::DocumentInfected { document_id, signature }).await;
+            }
+        }
+        None if doc.scan_status == QUARANTINED && doc.deleted_at.is_none() => {
+            let payload = serde_json::json!(previews::GeneratePayload { document_id });
+            jobs::enqueue(&state.pool, jobs::GENERATE_PREVIEWS, payload).await?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RescanRequest {
+    document_ids: Option<Vec<Uuid>>,
+    #[serde(default)]
+    include_scanned: bool,
+}
+
+async fn enqueue_scans(state: &AppState, ids: &[Uuid]) -> sqlx::Result<()> {
+    let mut tx = state.pool.begin().await?;
+    for id in ids {
+        jobs::enqueue(&mut *tx, jobs::SCAN_DOCUMENT, serde_json::json!(ScanPayload { document_id: *id })).await?;
+    }
+    tx.commit().await
+}
+
+pub async fn rescan_document(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if !state.scanner.enabled() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "virus scanning is not configured").into_response();
+    }
+
+    let exists = sqlx::query_scalar::<_, Uuid>("select id from documents where id = $1")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await;
+    match exists {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+    match enqueue_scans(&state, &[id]).await {
+        Ok(_) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+// Queues scans for the given documents, or for every live document never scanned or too large to scan (all of
+// them with `include_scanned`). Documents keep their current status until their scan completes.
+pub async fn rescan(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<RescanRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    if !state.scanner.enabled() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "virus scanning is not configured").into_response();
+    }
+
+    let ids = match &body.document_ids {
+        Some(ids) => {
+            sqlx::query_scalar::<_, Uuid>("select id from documents where id = any($1)")
+                .bind(ids)
+                .fetch_all(&state.pool)
+                .await
+        }
+        None => {
+            sqlx::query_scalar::<_, Uuid>(
+                "select id from documents where deleted_at is null and ($1 or scan_status = any($2)) order by created_at limit $3",
+            )
+            .bind(body.include_scanned)
+            .bind([UNSCANNED, TOO_LARGE])
+            .bind(RESCAN_LIMIT)
+            .fetch_all(&state.pool)
+            .await
+        }
+    };
+    let ids = match ids {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    match enqueue_scans(&state, &ids).await {
+        Ok(_) => (StatusCode::ACCEPTED, Json(serde_json::json!({ "queued": ids.len() }))).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
@@ -0,0 +1,680 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Extension, Form, Path as AxumPath, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{PgConnection, PgPool};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    client_ip, doc_editable, document_bytes, document_stream, file_response, hash_password, inline_response, scanner, verify_password, watermark,
+    AppState, AuthedUser, DocumentRow, DOCUMENT_COLUMNS,
+};
+
+const SHARE_LINK_TTL_HOURS_DEFAULT: i64 = 72;
+const SHARE_LINK_TTL_HOURS_MAX: i64 = 24 * 90;
+const SIGNATURE_HEX_LEN: usize = 32;
+const REQUEST_LINK_TTL_HOURS_DEFAULT: i32 = 72;
+const PUBLIC_BASE_URL_DEFAULT: &str = "http://127.0.0.1:8752";
+const MAX_FAILED_ATTEMPTS_DEFAULT: i64 = 5;
+const MAX_FAILED_ATTEMPTS_PER_IP_DEFAULT: i64 = 20;
+const LOCKOUT_MINUTES_DEFAULT: i32 = 15;
+const PASSWORD_HEADER: &str = "x-share-password";
+
+#[derive(Debug, sqlx::FromRow)]
+struct ShareLinkRow {
+    id: Uuid,
+    document_id: Uuid,
+    created_by: Option<Uuid>,
+    expires_at: DateTime<Utc>,
+    password_hash: Option<String>,
+    max_downloads: Option<i32>,
+    download_count: i32,
+    view_only: bool,
+    download_request_id: Option<Uuid>,
+    revoked_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLinkDto {
+    id: Uuid,
+    document_id: Uuid,
+    token: String,
+    url: String,
+    created_by: Option<Uuid>,
+    expires_at: DateTime<Utc>,
+    password_protected: bool,
+    max_downloads: Option<i32>,
+    download_count: i32,
+    view_only: bool,
+    revoked_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLinkAccessDto {
+    id: Uuid,
+    action: String,
+    outcome: String,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedDocumentDto {
+    name: String,
+    mime_type: String,
+    size: i64,
+    expires_at: DateTime<Utc>,
+    view_only: bool,
+    password_required: bool,
+    downloads_remaining: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareLinkRequest {
+    expires_in_hours: Option<i64>,
+    password: Option<String>,
+    max_downloads: Option<i32>,
+    view_only: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareAccessForm {
+    password: Option<String>,
+}
+
+const SHARE_LINK_COLUMNS: &str = "id, document_id, created_by, expires_at, password_hash, max_downloads, download_count, view_only, download_request_id, revoked_at, created_at";
+
+pub fn is_public_request(method: &Method, path: &str) -> bool {
+    (method == Method::GET || method == Method::HEAD || method == Method::POST) && path.starts_with("/s/")
+}
+
+fn signature(secret: &str, id: Uuid) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(b"share-link:");
+    mac.update(id.as_bytes());
+    mac
+}
+
+fn token_for(secret: &str, id: Uuid) -> String {
+    let sig = hex::encode(signature(secret, id).finalize().into_bytes());
+    format!("{}{}", id.simple(), &sig[..SIGNATURE_HEX_LEN])
+}
+
+fn verify_token(secret: &str, token: &str) -> Option<Uuid> {
+    if token.len() != 32 + SIGNATURE_HEX_LEN || !token.is_ascii() {
+        return None;
+    }
+    let (id, sig) = token.split_at(32);
+    let id = Uuid::parse_str(id).ok()?;
+    let sig = hex::decode(sig).ok()?;
+    signature(secret, id).verify_truncated_left(&sig).ok()?;
+    Some(id)
+}
+
+pub fn public_url(path: &str) -> String {
+    let base = std::env::var("PUBLIC_BASE_URL").unwrap_or_else(|_| PUBLIC_BASE_URL_DEFAULT.to_string());
+    format!("{}{path}", base.trim_end_matches('/'))
+}
+
+pub async fn create_for_request(conn: &mut PgConnection, request_id: Uuid) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"
+        insert into share_links (id, document_id, expires_at, max_downloads, download_request_id)
+        select $1, r.document_id, coalesce(r.expires_at, now() + make_interval(hours => $3)), coalesce(r.max_downloads, 1), r.id
+        from download_requests r
+        where r.id = $2 and r.requester_id is null
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(request_id)
+    .bind(REQUEST_LINK_TTL_HOURS_DEFAULT)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn request_link_id(pool: &PgPool, request_id: Uuid) -> sqlx::Result<Option<Uuid>> {
+    sqlx::query_scalar::<_, Uuid>(
+        "select id from share_links where download_request_id = $1 and revoked_at is null order by created_at desc limit 1",
+    )
+    .bind(request_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub fn download_url(secret: &str, id: Uuid) -> String {
+    public_url(&format!("/s/{}/download", token_for(secret, id)))
+}
+
+impl ShareLinkRow {
+    fn into_dto(self, secret: &str) -> ShareLinkDto {
+        let token = token_for(secret, self.id);
+        ShareLinkDto {
+            id: self.id,
+            document_id: self.document_id,
+            url: format!("/s/{token}"),
+            token,
+            created_by: self.created_by,
+            expires_at: self.expires_at,
+            password_protected: self.password_hash.is_some(),
+            max_downloads: self.max_downloads,
+            download_count: self.download_count,
+            view_only: self.view_only,
+            revoked_at: self.revoked_at,
+            created_at: self.created_at,
+        }
+    }
+
+    fn downloads_remaining(&self) -> Option<i32> {
+        self.max_downloads.map(|m| (m - self.download_count).max(0))
+    }
+}
+
+async fn load_document(state: &AppState, id: Uuid) -> Result<DocumentRow, axum::response::Response> {
+    let row = sqlx::query_as::<_, DocumentRow>(&format!(
+        "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1 and d.deleted_at is null"
+    ))
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match row {
+        Ok(Some(v)) => Ok(v),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "not found").into_response()),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()),
+    }
+}
+
+async fn load_editable_link(state: &AppState, authed: &AuthedUser, id: Uuid) -> Result<ShareLinkRow, axum::response::Response> {
+    let link = sqlx::query_as::<_, ShareLinkRow>(&format!("select {SHARE_LINK_COLUMNS} from share_links where id = $1"))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await;
+    let link = match link {
+        Ok(Some(v)) => v,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "not found").into_response()),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()),
+    };
+    let doc = load_document(state, link.document_id).await?;
+    if !doc_editable(&doc, authed) {
+        return Err((StatusCode::FORBIDDEN, "forbidden").into_response());
+    }
+    Ok(link)
+}
+
+pub async fn create_share_link(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<CreateShareLinkRequest>,
+) -> impl IntoResponse {
+    let doc = match load_document(&state, id).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    if !doc_editable(&doc, &authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let ttl_hours = body.expires_in_hours.unwrap_or(SHARE_LINK_TTL_HOURS_DEFAULT);
+    if !(1..=SHARE_LINK_TTL_HOURS_MAX).contains(&ttl_hours) {
+        return (StatusCode::BAD_REQUEST, "invalid expires_in_hours").into_response();
+    }
+    if body.max_downloads.is_some_and(|n| n < 1) {
+        return (StatusCode::BAD_REQUEST, "invalid max_downloads").into_response();
+    }
+    let password_hash = match body.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(p) => match hash_password(p) {
+            Ok(v) => Some(v),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "hash error").into_response(),
+        },
+        None => None,
+    };
+
+    let created = sqlx::query_as::<_, ShareLinkRow>(&format!(
+        r#"
+        insert into share_links (id, document_id, created_by, expires_at, password_hash, max_downloads, view_only)
+        values ($1, $2, $3, now() + make_interval(hours => $4), $5, $6, $7)
+        returning {SHARE_LINK_COLUMNS}
+        "#
+    ))
+    .bind(Uuid::new_v4())
+    .bind(doc.id)
+    .bind(authed.id)
+    .bind(ttl_hours as i32)
+    .bind(password_hash)
+    .bind(body.max_downloads)
+    .bind(body.view_only.unwrap_or(false))
+    .fetch_one(&state.pool)
+    .await;
+
+    match created {
+        Ok(link) => (StatusCode::CREATED, Json(link.into_dto(&state.share_secret))).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_share_links(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let doc = match load_document(&state, id).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    if !doc_editable(&doc, &authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, ShareLinkRow>(&format!(
+        "select {SHARE_LINK_COLUMNS} from share_links where document_id = $1 order by created_at desc"
+    ))
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => {
+            let out: Vec<ShareLinkDto> = v.into_iter().map(|l| l.into_dto(&state.share_secret)).collect();
+            (StatusCode::OK, Json(out)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn revoke_share_link(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if let Err(resp) = load_editable_link(&state, &authed, id).await {
+        return resp;
+    }
+
+    let res = sqlx::query("update share_links set revoked_at = coalesce(revoked_at, now()) where id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await;
+
+    match res {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_accesses(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if let Err(resp) = load_editable_link(&state, &authed, id).await {
+        return resp;
+    }
+
+    let rows = sqlx::query_as::<_, ShareLinkAccessDto>(
+        "select id, action, outcome, ip, user_agent, created_at from share_link_accesses where share_link_id = $1 order by created_at desc limit 500",
+    )
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+struct Visitor {
+    ip: String,
+    user_agent: Option<String>,
+}
+
+impl Visitor {
+    fn new(addr: SocketAddr, headers: &HeaderMap) -> Self {
+        Self {
+            ip: client_ip(addr, headers).to_string(),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.chars().take(512).collect()),
+        }
+    }
+}
+
+async fn record_access(state: &AppState, link_id: Uuid, action: &str, outcome: &str, visitor: &Visitor) {
+    let res = sqlx::query(
+        "insert into share_link_accesses (id, share_link_id, action, outcome, ip, user_agent) values ($1,$2,$3,$4,$5,$6)",
+    )
+    .bind(Uuid::new_v4())
+    .bind(link_id)
+    .bind(action)
+    .bind(outcome)
+    .bind(&visitor.ip)
+    .bind(&visitor.user_agent)
+    .execute(&state.pool)
+    .await;
+    if let Err(e) = res {
+        error!(?e, "record share link access failed");
+    }
+}
+
+async fn resolve_link(
+    state: &AppState,
+    token: &str,
+    action: &str,
+    visitor: &Visitor,
+) -> Result<(ShareLinkRow, DocumentRow), axum::response::Response> {
+    let not_found = || (StatusCode::NOT_FOUND, "not found").into_response();
+    let Some(id) = verify_token(&state.share_secret, token) else {
+        return Err(not_found());
+    };
+
+    let link = sqlx::query_as::<_, ShareLinkRow>(&format!("select {SHARE_LINK_COLUMNS} from share_links where id = $1"))
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await;
+    let link = match link {
+        Ok(Some(v)) => v,
+        Ok(None) => return Err(not_found()),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response()),
+    };
+
+    let denied = if link.revoked_at.is_some() {
+        Some((StatusCode::GONE, "revoked", "link revoked"))
+    } else if link.expires_at <= Utc::now() {
+        Some((StatusCode::GONE, "expired", "link expired"))
+    } else {
+        None
+    };
+    if let Some((status, outcome, msg)) = denied {
+        record_access(state, link.id, action, outcome, visitor).await;
+        return Err((status, msg).into_response());
+    }
+
+    let doc = match load_document(state, link.document_id).await {
+        Ok(v) => v,
+        Err(_) => return Err(not_found()),
+    };
+    Ok((link, doc))
+}
+
+fn env_limit<T: std::str::FromStr + Ord>(name: &str, default: T, min: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default).max(min)
+}
+
+struct LockoutLimits {
+    per_link: i64,
+    per_ip: i64,
+}
+
+impl LockoutLimits {
+    fn from_env() -> Self {
+        Self {
+            per_link: env_limit("SHARE_LINK_MAX_FAILED_ATTEMPTS", MAX_FAILED_ATTEMPTS_DEFAULT, 1),
+            per_ip: env_limit("SHARE_LINK_MAX_FAILED_ATTEMPTS_PER_IP", MAX_FAILED_ATTEMPTS_PER_IP_DEFAULT, 1),
+        }
+    }
+
+    fn exceeded(&self, per_link: i64, per_ip: i64) -> bool {
+        per_link >= self.per_link || per_ip >= self.per_ip
+    }
+}
+
+// Whether recent wrong passwords for this link, or from this address across all links, exceed the limits.
+async fn locked_out(state: &AppState, link_id: Uuid, ip: &str) -> sqlx::Result<bool> {
+    let (per_link, per_ip) = sqlx::query_as::<_, (i64, i64)>(
+        r#"
+        select count(*) filter (where share_link_id = $1), count(*) filter (where ip = $2)
+        from share_link_accesses
+        where outcome = 'invalid_password'
+          and created_at > now() - make_interval(mins => $3)
+          and (share_link_id = $1 or ip = $2)
+        "#,
+    )
+    .bind(link_id)
+    .bind(ip)
+    .bind(env_limit("SHARE_LINK_LOCKOUT_MINUTES", LOCKOUT_MINUTES_DEFAULT, 1))
+    .fetch_one(&state.pool)
+    .await?;
+    Ok(LockoutLimits::from_env().exceeded(per_link, per_ip))
+}
+
+async fn check_password(
+    state: &AppState,
+    link: &ShareLinkRow,
+    visitor: &Visitor,
+    supplied: Option<&str>,
+) -> Result<(), (StatusCode, &'static str, &'static str)> {
+    let Some(hash) = link.password_hash.as_deref() else {
+        return Ok(());
+    };
+    let Some(supplied) = supplied.filter(|p| !p.is_empty()) else {
+        return Err((StatusCode::UNAUTHORIZED, "password_required", "password required"));
+    };
+    match locked_out(state, link.id, &visitor.ip).await {
+        Ok(false) => {}
+        Ok(true) => return Err((StatusCode::TOO_MANY_REQUESTS, "locked_out", "too many failed attempts, try again later")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "error", "db error")),
+    }
+    if verify_password(supplied, hash).unwrap_or(false) {
+        Ok(())
+    } else {
+        Err((StatusCode::FORBIDDEN, "invalid_password", "invalid password"))
+    }
+}
+
+fn header_password(headers: &HeaderMap) -> Option<String> {
+    headers.get(PASSWORD_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string)
+}
+
+pub async fn share_info(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+) -> impl IntoResponse {
+    let visitor = Visitor::new(addr, &headers);
+    let (link, doc) = match resolve_link(&state, &token, "info", &visitor).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    record_access(&state, link.id, "info", "ok", &visitor).await;
+
+    let out = SharedDocumentDto {
+        name: doc.name,
+        mime_type: doc.mime_type,
+        size: doc.size,
+        expires_at: link.expires_at,
+        view_only: link.view_only,
+        password_required: link.password_hash.is_some(),
+        downloads_remaining: link.downloads_remaining(),
+    };
+    (StatusCode::OK, Json(out)).into_response()
+}
+
+// Passwords are taken from the x-share-password header or a POSTed form, never the query string, so they
+// stay out of access logs and browser history.
+pub async fn share_download(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+) -> impl IntoResponse {
+    let password = header_password(&headers);
+    serve(&state, Visitor::new(addr, &headers), password.as_deref(), &token, false).await
+}
+
+pub async fn share_download_form(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+    Form(form): Form<ShareAccessForm>,
+) -> impl IntoResponse {
+    let password = form.password.or_else(|| header_password(&headers));
+    serve(&state, Visitor::new(addr, &headers), password.as_deref(), &token, false).await
+}
+
+pub async fn share_view(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+) -> impl IntoResponse {
+    let password = header_password(&headers);
+    serve(&state, Visitor::new(addr, &headers), password.as_deref(), &token, true).await
+}
+
+pub async fn share_view_form(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    AxumPath(token): AxumPath<String>,
+    Form(form): Form<ShareAccessForm>,
+) -> impl IntoResponse {
+    let password = form.password.or_else(|| header_password(&headers));
+    serve(&state, Visitor::new(addr, &headers), password.as_deref(), &token, true).await
+}
+
+async fn serve(state: &AppState, visitor: Visitor, password: Option<&str>, token: &str, inline: bool) -> axum::response::Response {
+    let action = if inline { "view" } else { "download" };
+    let (link, doc) = match resolve_link(state, token, action, &visitor).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    if let Err((status, outcome, msg)) = check_password(state, &link, &visitor, password).await {
+        record_access(state, link.id, action, outcome, &visitor).await;
+        return (status, msg).into_response();
+    }
+    if !inline && link.view_only {
+        record_access(state, link.id, action, "view_only", &visitor).await;
+        return (StatusCode::FORBIDDEN, "view only").into_response();
+    }
+    // Approval links are metered per download, so they cannot be viewed inline, and a used-up link serves nothing.
+    if inline && link.download_request_id.is_some() {
+        record_access(state, link.id, action, "download_only", &visitor).await;
+        return (StatusCode::FORBIDDEN, "download only").into_response();
+    }
+    if inline && link.downloads_remaining() == Some(0) {
+        record_access(state, link.id, action, "limit_reached", &visitor).await;
+        return (StatusCode::GONE, "download limit reached").into_response();
+    }
+    if let Some((status, msg)) = scanner::blocked(&doc) {
+        record_access(state, link.id, action, "scan_blocked", &visitor).await;
+        return (status, msg).into_response();
+    }
+
+    let body = match link.download_request_id {
+        Some(request_id) if watermark::applies(&doc) => {
+            let data = match document_bytes(state, &doc).await {
+                Ok(v) => v,
+                Err(resp) => return resp,
+            };
+            match watermark::apply(state, &doc, request_id, data).await {
+                Ok(v) => axum::body::Body::from(v),
+                Err(e) => {
+                    error!(?e, document_id = %doc.id, %request_id, "watermark failed");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "watermark failed").into_response();
+                }
+            }
+        }
+        _ => match document_stream(state, &doc).await {
+            Ok(v) => v,
+            Err(resp) => return resp,
+        },
+    };
+
+    if !inline {
+        let consumed = sqlx::query(
+            r#"
+            update share_links
+            set download_count = download_count + 1
+            where id = $1
+              and revoked_at is null
+              and expires_at > now()
+              and (max_downloads is null or download_count < max_downloads)
+            "#,
+        )
+        .bind(link.id)
+        .execute(&state.pool)
+        .await;
+        match consumed {
+            Ok(r) if r.rows_affected() == 0 => {
+                record_access(state, link.id, action, "limit_reached", &visitor).await;
+                return (StatusCode::GONE, "download limit reached").into_response();
+            }
+            Ok(_) => {}
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        }
+    }
+    record_access(state, link.id, action, "ok", &visitor).await;
+
+    let mut resp = if inline {
+        inline_response(body, &doc.mime_type, &doc.name)
+    } else {
+        file_response(body, &doc.mime_type, &doc.name, "attachment")
+    };
+    resp.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    resp.headers_mut().insert("x-content-type-options", HeaderValue::from_static("nosniff"));
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-share-secret";
+
+    #[test]
+    fn tokens_round_trip() {
+        let id = Uuid::new_v4();
+        let token = token_for(SECRET, id);
+        assert_eq!(token.len(), 32 + SIGNATURE_HEX_LEN);
+        assert_eq!(verify_token(SECRET, &token), Some(id));
+        assert_eq!(verify_token("another-secret", &token), None);
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let id = Uuid::new_v4();
+        let token = token_for(SECRET, id);
+
+        let other = token_for(SECRET, Uuid::new_v4());
+        assert_eq!(verify_token(SECRET, &format!("{}{}", &other[..32], &token[32..])), None);
+
+        for pos in [0, 31, 32, token.len() - 1] {
+            let mut tampered = token.clone().into_bytes();
+            tampered[pos] = if tampered[pos] == b'0' { b'1' } else { b'0' };
+            assert_eq!(verify_token(SECRET, std::str::from_utf8(&tampered).unwrap()), None, "{pos}");
+        }
+        for bad in ["", &token[..token.len() - 1], &format!("{token}0"), &format!("{}\u{e9}", &token[..token.len() - 2])] {
+            assert_eq!(verify_token(SECRET, bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn locks_out_at_either_threshold() {
+        let limits = LockoutLimits { per_link: 5, per_ip: 20 };
+        assert!(!limits.exceeded(0, 0));
+        assert!(!limits.exceeded(4, 19));
+        assert!(limits.exceeded(5, 0));
+        assert!(limits.exceeded(0, 20));
+    }
+}
@@ -0,0 +1,1168 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{self, ready, Poll},
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{is_admin, jobs, previews, upload_sessions, watermark, AppState, AuthedUser, DocumentRow};
+
+const FILE_MAGIC: &[u8; 4] = b"XDE1";
+const NONCE_PREFIX_LEN: usize = 7;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const DATA_KEY_LEN: usize = 32;
+const ROTATE_BATCH: i64 = 200;
+const READDRESS_BATCH: i64 = 200;
+const BLOB_DIR: &str = "blobs";
+const STAGING_DIR: &str = ".staging";
+
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Arc<HashMap<String, LessSafeKey>>,
+    active: Option<String>,
+    address_key: Option<[u8; DATA_KEY_LEN]>,
+}
+
+pub struct DataKey([u8; DATA_KEY_LEN]);
+
+pub struct WrappedKey {
+    pub key_id: String,
+    pub wrapped: Vec<u8>,
+}
+
+fn parse_master_key(key_id: &str, hex_key: &str) -> anyhow::Result<(LessSafeKey, [u8; DATA_KEY_LEN])> {
+    let bytes = hex::decode(hex_key.trim()).with_context(|| format!("storage master key {key_id} is not valid hex"))?;
+    if bytes.len() != DATA_KEY_LEN {
+        anyhow::bail!("storage master key {key_id} must be {DATA_KEY_LEN} bytes");
+    }
+    let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| anyhow::anyhow!("invalid storage master key {key_id}"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&bytes).expect("hmac accepts any key length");
+    mac.update(b"blob-address");
+    Ok((LessSafeKey::new(key), mac.finalize().into_bytes().into()))
+}
+
+impl Keyring {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+        let mut address_keys = HashMap::new();
+        let mut last = None;
+
+        if let Some(path) = std::env::var("STORAGE_MASTER_KEY_FILE").ok().filter(|v| !v.trim().is_empty()) {
+            let text = std::fs::read_to_string(&path).with_context(|| format!("read STORAGE_MASTER_KEY_FILE {path}"))?;
+            for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                let (key_id, hex_key) = line
+                    .split_once(char::is_whitespace)
+                    .context("STORAGE_MASTER_KEY_FILE lines must be `<key_id> <hex key>`")?;
+                let (key, address_key) = parse_master_key(key_id, hex_key)?;
+                keys.insert(key_id.to_string(), key);
+                address_keys.insert(key_id.to_string(), address_key);
+                last = Some(key_id.to_string());
+            }
+        }
+
+        if let Some(v) = std::env::var("STORAGE_MASTER_KEY").ok().filter(|v| !v.trim().is_empty()) {
+            let (key_id, hex_key) = v
+                .trim()
+                .split_once(':')
+                .context("STORAGE_MASTER_KEY must be `<key_id>:<hex key>`")?;
+            let (key, address_key) = parse_master_key(key_id, hex_key)?;
+            keys.insert(key_id.to_string(), key);
+            address_keys.insert(key_id.to_string(), address_key);
+            last = Some(key_id.to_string());
+        }
+
+        let active = std::env::var("STORAGE_MASTER_KEY_ID").ok().filter(|v| !v.trim().is_empty()).or(last);
+        match &active {
+            Some(key_id) if !keys.contains_key(key_id) => {
+                anyhow::bail!("STORAGE_MASTER_KEY_ID {key_id} is not a configured key");
+            }
+            Some(key_id) => info!(%key_id, keys = keys.len(), "storage encryption enabled"),
+            None => warn!("no storage master key configured; document files are stored unencrypted"),
+        }
+
+        Ok(Self {
+            address_key: active.as_ref().and_then(|key_id| address_keys.remove(key_id)),
+            keys: Arc::new(keys),
+            active,
+        })
+    }
+
+    // Blobs are filed under an HMAC of their content hash, so file names do not reveal the plaintext SHA-256.
+    fn blob_rel_path(&self, sha256: &str) -> String {
+        let name = match &self.address_key {
+            Some(key) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+                mac.update(sha256.as_bytes());
+                hex::encode(mac.finalize().into_bytes())
+            }
+            None => sha256.to_string(),
+        };
+        format!("{BLOB_DIR}/{}/{name}", &name[..2])
+    }
+
+    pub fn generate(&self) -> anyhow::Result<Option<(DataKey, WrappedKey)>> {
+        let Some(active) = &self.active else {
+            return Ok(None);
+        };
+        let mut raw = [0u8; DATA_KEY_LEN];
+        OsRng.fill_bytes(&mut raw);
+        let key = DataKey(raw);
+        let wrapped = self.wrap(active, &key)?;
+        Ok(Some((key, wrapped)))
+    }
+
+    fn master(&self, key_id: &str) -> anyhow::Result<&LessSafeKey> {
+        self.keys
+            .get(key_id)
+            .with_context(|| format!("storage master key {key_id} is not configured"))
+    }
+
+    fn wrap(&self, key_id: &str, key: &DataKey) -> anyhow::Result<WrappedKey> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut sealed = key.0.to_vec();
+        self.master(key_id)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(key_id.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow::anyhow!("wrap data key failed"))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&sealed);
+        Ok(WrappedKey {
+            key_id: key_id.to_string(),
+            wrapped,
+        })
+    }
+
+    fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> anyhow::Result<DataKey> {
+        if wrapped.len() != NONCE_LEN + DATA_KEY_LEN + TAG_LEN {
+            anyhow::bail!("wrapped data key has invalid length");
+        }
+        let (nonce, sealed) = wrapped.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow::anyhow!("invalid nonce"))?;
+        let mut sealed = sealed.to_vec();
+        let plain = self
+            .master(key_id)?
+            .open_in_place(nonce, Aad::from(key_id.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow::anyhow!("unwrap data key failed"))?;
+
+        let mut raw = [0u8; DATA_KEY_LEN];
+        raw.copy_from_slice(plain);
+        Ok(DataKey(raw))
+    }
+
+    pub fn document_key(&self, doc: &DocumentRow) -> anyhow::Result<Option<DataKey>> {
+        self.data_key(doc.encryption_key_id.as_deref(), doc.wrapped_data_key.as_deref())
+    }
+
+    pub fn data_key(&self, key_id: Option<&str>, wrapped: Option<&[u8]>) -> anyhow::Result<Option<DataKey>> {
+        match (key_id, wrapped) {
+            (Some(key_id), Some(wrapped)) => self.unwrap(key_id, wrapped).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl DataKey {
+    fn aead(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("data key has AES-256 length"))
+    }
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Encrypts everything written to it in CHUNK_SIZE chunks. A full chunk is only sealed once more data
+// follows, so the final chunk can carry the `last` flag; `shutdown` seals it.
+pub struct EncryptingWriter<W> {
+    inner: W,
+    aead: LessSafeKey,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    plain: Vec<u8>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> EncryptingWriter<W> {
+    pub fn new(inner: W, key: &DataKey) -> Self {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        let mut pending = Vec::with_capacity(FILE_MAGIC.len() + NONCE_PREFIX_LEN + CHUNK_SIZE + TAG_LEN);
+        pending.extend_from_slice(FILE_MAGIC);
+        pending.extend_from_slice(&prefix);
+        Self {
+            inner,
+            aead: key.aead(),
+            prefix,
+            counter: 0,
+            plain: Vec::with_capacity(CHUNK_SIZE + 1),
+            pending,
+            pending_pos: 0,
+            finished: false,
+        }
+    }
+
+    fn seal(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let mut chunk: Vec<u8> = self.plain.drain(..len).collect();
+        self.aead
+            .seal_in_place_append_tag(chunk_nonce(&self.prefix, self.counter, last), Aad::empty(), &mut chunk)
+            .map_err(|_| invalid_data("encrypt chunk failed"))?;
+        self.counter = self.counter.checked_add(1).ok_or_else(|| invalid_data("file too large"))?;
+        self.pending.extend_from_slice(&chunk);
+        Ok(())
+    }
+
+    fn poll_drain(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if this.finished {
+            return Poll::Ready(Err(io::Error::other("write after shutdown")));
+        }
+        let n = data.len().min(CHUNK_SIZE + 1 - this.plain.len());
+        this.plain.extend_from_slice(&data[..n]);
+        if this.plain.len() > CHUNK_SIZE {
+            this.seal(CHUNK_SIZE, false)?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.finished {
+            ready!(this.poll_drain(cx))?;
+            this.seal(this.plain.len(), true)?;
+            this.finished = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+// Decrypts a file written by `EncryptingWriter`, holding at most one chunk in memory. A chunk is only
+// opened as the last one once the source is exhausted, so truncation fails authentication.
+pub struct DecryptingReader<R> {
+    inner: R,
+    aead: LessSafeKey,
+    prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    counter: u32,
+    cipher: Vec<u8>,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> DecryptingReader<R> {
+    pub fn new(inner: R, key: &DataKey) -> Self {
+        Self {
+            inner,
+            aead: key.aead(),
+            prefix: None,
+            counter: 0,
+            cipher: Vec::with_capacity(CHUNK_SIZE + TAG_LEN + 1),
+            plain: Vec::new(),
+            plain_pos: 0,
+            eof: false,
+            done: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let header = FILE_MAGIC.len() + NONCE_PREFIX_LEN;
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = out.remaining().min(this.plain.len() - this.plain_pos);
+                out.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+
+            let want = if this.prefix.is_none() { header } else { CHUNK_SIZE + TAG_LEN + 1 };
+            if !this.eof && this.cipher.len() < want {
+                let start = this.cipher.len();
+                this.cipher.resize(want, 0);
+                let mut buf = ReadBuf::new(&mut this.cipher[start..]);
+                let res = Pin::new(&mut this.inner).poll_read(cx, &mut buf);
+                let filled = buf.filled().len();
+                this.cipher.truncate(start + filled);
+                match res {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Ready(Ok(())) => this.eof = filled == 0,
+                }
+                continue;
+            }
+
+            let Some(prefix) = this.prefix else {
+                if this.cipher.len() < header || &this.cipher[..FILE_MAGIC.len()] != FILE_MAGIC {
+                    return Poll::Ready(Err(invalid_data("not an encrypted document file")));
+                }
+                let mut prefix = [0u8; NONCE_PREFIX_LEN];
+                prefix.copy_from_slice(&this.cipher[FILE_MAGIC.len()..header]);
+                this.prefix = Some(prefix);
+                this.cipher.drain(..header);
+                continue;
+            };
+
+            let last = this.eof && this.cipher.len() <= CHUNK_SIZE + TAG_LEN;
+            let len = if last { this.cipher.len() } else { CHUNK_SIZE + TAG_LEN };
+            if len < TAG_LEN {
+                return Poll::Ready(Err(invalid_data("document file failed authentication")));
+            }
+            let mut chunk: Vec<u8> = this.cipher.drain(..len).collect();
+            let plain_len = this
+                .aead
+                .open_in_place(chunk_nonce(&prefix, this.counter, last), Aad::empty(), &mut chunk)
+                .map_err(|_| invalid_data("document file failed authentication"))?
+                .len();
+            chunk.truncate(plain_len);
+            this.plain = chunk;
+            this.plain_pos = 0;
+            this.counter = this.counter.checked_add(1).ok_or_else(|| invalid_data("file too large"))?;
+            this.done = last;
+        }
+    }
+}
+
+// Fails the read at end of stream when the content does not hash to `expected`.
+pub struct VerifyingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    expected: String,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = out.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, out))?;
+        let read = &out.filled()[before..];
+        if !read.is_empty() {
+            this.hasher.update(read);
+        } else if hex::encode(std::mem::take(&mut this.hasher).finalize()) != this.expected {
+            return Poll::Ready(Err(invalid_data("checksum mismatch")));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub type BoxReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type BoxWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+pub async fn open(path: &Path, key: Option<&DataKey>) -> io::Result<BoxReader> {
+    let file = tokio::io::BufReader::with_capacity(CHUNK_SIZE, tokio::fs::File::open(path).await?);
+    Ok(match key {
+        Some(key) => Box::new(DecryptingReader::new(file, key)),
+        None => Box::new(file),
+    })
+}
+
+// The caller must `shutdown` the writer to seal the final chunk.
+pub async fn create(path: &Path, key: Option<&DataKey>) -> io::Result<BoxWriter> {
+    let file = tokio::io::BufWriter::with_capacity(CHUNK_SIZE, tokio::fs::File::create(path).await?);
+    Ok(match key {
+        Some(key) => Box::new(EncryptingWriter::new(file, key)),
+        None => Box::new(file),
+    })
+}
+
+// Streams the reader as a response body one chunk at a time; a read error aborts the response.
+pub fn body(reader: BoxReader) -> axum::body::Body {
+    body_or_else(reader, || {})
+}
+
+struct Unfinished<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> Drop for Unfinished<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            f();
+        }
+    }
+}
+
+// Like `body`, but calls `aborted` if the response ends before the reader is exhausted, whether the client
+// went away or the read failed.
+pub fn body_or_else<F>(reader: BoxReader, aborted: F) -> axum::body::Body
+where
+    F: FnOnce() + Send + 'static,
+{
+    let chunks = futures_util::stream::unfold((Some(reader), Unfinished(Some(aborted))), |(reader, mut unfinished)| async move {
+        let mut reader = reader?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        match reader.read(&mut buf).await {
+            Ok(0) => {
+                unfinished.0.take();
+                None
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(axum::body::Bytes::from(buf)), (Some(reader), unfinished)))
+            }
+            Err(e) => Some((Err(e), (None, unfinished))),
+        }
+    });
+    axum::body::Body::from_stream(chunks)
+}
+
+pub async fn write(path: &Path, key: Option<&DataKey>, data: &[u8]) -> io::Result<()> {
+    let mut file = create(path, key).await?;
+    file.write_all(data).await?;
+    file.shutdown().await
+}
+
+pub async fn read(path: &Path, key: Option<&DataKey>) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    open(path, key).await?.read_to_end(&mut out).await?;
+    Ok(out)
+}
+
+async fn lock_path(conn: &mut PgConnection, storage_rel_path: &str) -> sqlx::Result<()> {
+    sqlx::query("select pg_advisory_xact_lock(hashtext($1))")
+        .bind(storage_rel_path)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn open_document(state: &AppState, doc: &DocumentRow) -> io::Result<BoxReader> {
+    let key = state.keyring.document_key(doc).map_err(io::Error::other)?;
+    let reader = open(&state.storage_root.join(&doc.storage_rel_path), key.as_ref()).await?;
+    Ok(match &doc.sha256 {
+        Some(expected) => Box::new(VerifyingReader {
+            inner: reader,
+            hasher: Sha256::new(),
+            expected: expected.clone(),
+        }),
+        None => reader,
+    })
+}
+
+pub async fn read_document(state: &AppState, doc: &DocumentRow) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(doc.size.max(0) as usize);
+    open_document(state, doc).await?.read_to_end(&mut out).await?;
+    Ok(out)
+}
+
+pub struct Blob {
+    pub sha256: String,
+    pub storage_rel_path: String,
+    pub encryption_key_id: Option<String>,
+    pub wrapped_data_key: Option<Vec<u8>>,
+    pub created: bool,
+}
+
+// A blob written to a temporary file under its own data key, not yet known to the database.
+pub struct StagedBlob {
+    tmp_path: PathBuf,
+    sha256: String,
+    size: u64,
+    wrapped: Option<WrappedKey>,
+}
+
+impl StagedBlob {
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn discard(self) {
+        if let Err(e) = tokio::fs::remove_file(&self.tmp_path).await {
+            warn!(?e, path = %self.tmp_path.display(), "discard staged blob failed");
+        }
+    }
+}
+
+// Streams `source` to disk, encrypting and hashing it on the way, without holding it in memory.
+pub async fn stage_blob<R: AsyncRead + Unpin>(state: &AppState, mut source: R) -> anyhow::Result<StagedBlob> {
+    let (key, wrapped) = match state.keyring.generate()? {
+        Some((key, wrapped)) => (Some(key), Some(wrapped)),
+        None => (None, None),
+    };
+    let dir = state.storage_root.join(BLOB_DIR).join(STAGING_DIR);
+    tokio::fs::create_dir_all(&dir).await?;
+    let tmp_path = dir.join(Uuid::new_v4().to_string());
+
+    let copied = async {
+        let mut file = create(&tmp_path, key.as_ref()).await?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = source.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).await?;
+            size += n as u64;
+        }
+        file.shutdown().await?;
+        io::Result::Ok((hex::encode(hasher.finalize()), size))
+    }
+    .await;
+    match copied {
+        Ok((sha256, size)) => Ok(StagedBlob {
+            tmp_path,
+            sha256,
+            size,
+            wrapped,
+        }),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            Err(e.into())
+        }
+    }
+}
+
+// Moves a staged blob into place, or drops it in favour of an identical blob already stored.
+pub async fn commit_blob(conn: &mut PgConnection, state: &AppState, staged: StagedBlob) -> anyhow::Result<Blob> {
+    let storage_rel_path = state.keyring.blob_rel_path(&staged.sha256);
+    if let Err(e) = lock_path(&mut *conn, &storage_rel_path).await {
+        staged.discard().await;
+        return Err(e.into());
+    }
+
+    let existing = sqlx::query_as::<_, (String, Option<String>, Option<Vec<u8>>)>(
+        r#"
+        update blobs set ref_count = ref_count + 1
+        where sha256 = $1
+        returning storage_rel_path, encryption_key_id, wrapped_data_key
+        "#,
+    )
+    .bind(&staged.sha256)
+    .fetch_optional(&mut *conn)
+    .await;
+    let existing = match existing {
+        Ok(v) => v,
+        Err(e) => {
+            staged.discard().await;
+            return Err(e.into());
+        }
+    };
+    if let Some((storage_rel_path, encryption_key_id, wrapped_data_key)) = existing {
+        let sha256 = staged.sha256.clone();
+        staged.discard().await;
+        return Ok(Blob {
+            sha256,
+            storage_rel_path,
+            encryption_key_id,
+            wrapped_data_key,
+            created: false,
+        });
+    }
+
+    let abs_path = state.storage_root.join(&storage_rel_path);
+    let placed = async {
+        if let Some(parent) = abs_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&staged.tmp_path, &abs_path).await
+    }
+    .await;
+    if let Err(e) = placed {
+        staged.discard().await;
+        return Err(e.into());
+    }
+
+    let blob = Blob {
+        sha256: staged.sha256,
+        storage_rel_path,
+        encryption_key_id: staged.wrapped.as_ref().map(|w| w.key_id.clone()),
+        wrapped_data_key: staged.wrapped.map(|w| w.wrapped),
+        created: true,
+    };
+    let res = sqlx::query(
+        r#"
+        insert into blobs (sha256, storage_rel_path, size, ref_count, encryption_key_id, wrapped_data_key)
+        values ($1,$2,$3,1,$4,$5)
+        "#,
+    )
+    .bind(&blob.sha256)
+    .bind(&blob.storage_rel_path)
+    .bind(staged.size as i64)
+    .bind(&blob.encryption_key_id)
+    .bind(&blob.wrapped_data_key)
+    .execute(&mut *conn)
+    .await;
+    if let Err(e) = res {
+        discard(state, &blob).await;
+        return Err(e.into());
+    }
+    Ok(blob)
+}
+
+pub async fn discard(state: &AppState, blob: &Blob) {
+    if !blob.created {
+        return;
+    }
+    if let Err(e) = tokio::fs::remove_file(state.storage_root.join(&blob.storage_rel_path)).await {
+        warn!(?e, sha256 = %blob.sha256, "discard blob failed");
+    }
+}
+
+pub async fn release_blob(conn: &mut PgConnection, sha256: &str) -> sqlx::Result<()> {
+    let storage_rel_path = sqlx::query_scalar::<_, String>("select storage_rel_path from blobs where sha256 = $1")
+        .bind(sha256)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let Some(storage_rel_path) = storage_rel_path else {
+        return Ok(());
+    };
+    lock_path(&mut *conn, &storage_rel_path).await?;
+
+    let remaining = sqlx::query_as::<_, (i32, String)>(
+        "update blobs set ref_count = ref_count - 1 where sha256 = $1 returning ref_count, storage_rel_path",
+    )
+    .bind(sha256)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some((ref_count, storage_rel_path)) = remaining else {
+        return Ok(());
+    };
+    if ref_count > 0 {
+        return Ok(());
+    }
+
+    sqlx::query("delete from blobs where sha256 = $1")
+        .bind(sha256)
+        .execute(&mut *conn)
+        .await?;
+    let payload = serde_json::json!(jobs::RemoveFilePayload { storage_rel_path });
+    jobs::enqueue(&mut *conn, jobs::REMOVE_FILE, payload).await?;
+    Ok(())
+}
+
+pub async fn remove_file(state: &AppState, storage_rel_path: &str) -> anyhow::Result<()> {
+    let mut tx = state.pool.begin().await?;
+    lock_path(&mut tx, storage_rel_path).await?;
+
+    let referenced = sqlx::query_scalar::<_, bool>(
+        r#"
+        select exists(select 1 from blobs where storage_rel_path = $1)
+            or exists(select 1 from documents where storage_rel_path = $1)
+        "#,
+    )
+    .bind(storage_rel_path)
+    .fetch_one(&mut *tx)
+    .await?;
+    if !referenced {
+        match tokio::fs::remove_file(state.storage_root.join(storage_rel_path)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        previews::purge(&state.storage_root, storage_rel_path).await;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+fn rewrap(keyring: &Keyring, active: &str, key_id: &str, wrapped: &[u8]) -> anyhow::Result<WrappedKey> {
+    let key = keyring.unwrap(key_id, wrapped)?;
+    keyring.wrap(active, &key)
+}
+
+pub async fn rotate_keys(pool: &PgPool, keyring: &Keyring) -> anyhow::Result<()> {
+    let active = keyring
+        .active
+        .clone()
+        .context("a storage master key is required to rotate keys")?;
+
+    let mut rotated = 0u64;
+    loop {
+        let rows = sqlx::query_as::<_, (String, String, Vec<u8>)>(
+            r#"
+            select sha256, encryption_key_id, wrapped_data_key
+            from blobs
+            where encryption_key_id <> $1 and wrapped_data_key is not null
+            order by sha256
+            limit $2
+            "#,
+        )
+        .bind(&active)
+        .bind(ROTATE_BATCH)
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+
+        for (sha256, key_id, wrapped) in rows {
+            let rewrapped =
+                rewrap(keyring, &active, &key_id, &wrapped).with_context(|| format!("rewrap data key for blob {sha256}"))?;
+            let mut tx = pool.begin().await?;
+            sqlx::query("update blobs set encryption_key_id = $2, wrapped_data_key = $3 where sha256 = $1")
+                .bind(&sha256)
+                .bind(&rewrapped.key_id)
+                .bind(&rewrapped.wrapped)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("update documents set encryption_key_id = $2, wrapped_data_key = $3 where sha256 = $1")
+                .bind(&sha256)
+                .bind(&rewrapped.key_id)
+                .bind(&rewrapped.wrapped)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            rotated += 1;
+        }
+    }
+
+    loop {
+        let rows = sqlx::query_as::<_, (Uuid, String, Vec<u8>)>(
+            r#"
+            select id, encryption_key_id, wrapped_data_key
+            from documents
+            where sha256 is null and encryption_key_id <> $1 and wrapped_data_key is not null
+            order by id
+            limit $2
+            "#,
+        )
+        .bind(&active)
+        .bind(ROTATE_BATCH)
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+
+        for (id, key_id, wrapped) in rows {
+            let rewrapped =
+                rewrap(keyring, &active, &key_id, &wrapped).with_context(|| format!("rewrap data key for document {id}"))?;
+            sqlx::query(
+                "update documents set encryption_key_id = $2, wrapped_data_key = $3 where id = $1 and encryption_key_id = $4",
+            )
+            .bind(id)
+            .bind(&rewrapped.key_id)
+            .bind(&rewrapped.wrapped)
+            .bind(&key_id)
+            .execute(pool)
+            .await?;
+            rotated += 1;
+        }
+    }
+
+    info!(rotated, key_id = %active, "storage data keys rewrapped");
+    Ok(())
+}
+
+// Moves blobs stored under their plaintext SHA-256 to their keyed address, along with their previews.
+pub async fn readdress_blobs(state: &AppState) -> anyhow::Result<()> {
+    if state.keyring.address_key.is_none() {
+        return Ok(());
+    }
+
+    let mut moved = 0u64;
+    let mut after = String::new();
+    loop {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            r#"
+            select sha256, storage_rel_path
+            from blobs
+            where sha256 > $1 and storage_rel_path = 'blobs/' || left(sha256, 2) || '/' || sha256
+            order by sha256
+            limit $2
+            "#,
+        )
+        .bind(&after)
+        .bind(READDRESS_BATCH)
+        .fetch_all(&state.pool)
+        .await?;
+        let Some((last, _)) = rows.last() else {
+            break;
+        };
+        after = last.clone();
+
+        for (sha256, from) in rows {
+            let to = state.keyring.blob_rel_path(&sha256);
+            let mut tx = state.pool.begin().await?;
+            lock_path(&mut tx, &from).await?;
+            lock_path(&mut tx, &to).await?;
+            let current = sqlx::query_scalar::<_, String>("select storage_rel_path from blobs where sha256 = $1")
+                .bind(&sha256)
+                .fetch_optional(&mut *tx)
+                .await?;
+            if current.as_deref() != Some(from.as_str()) {
+                continue;
+            }
+
+            let (from_abs, to_abs) = (state.storage_root.join(&from), state.storage_root.join(&to));
+            if let Some(parent) = to_abs.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            match tokio::fs::rename(&from_abs, &to_abs).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    warn!(%sha256, path = %from, "blob file missing; leaving it for fsck");
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let updated = async {
+                sqlx::query("update blobs set storage_rel_path = $2 where sha256 = $1")
+                    .bind(&sha256)
+                    .bind(&to)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("update documents set storage_rel_path = $2 where sha256 = $1")
+                    .bind(&sha256)
+                    .bind(&to)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await
+            }
+            .await;
+            if let Err(e) = updated {
+                let _ = tokio::fs::rename(&to_abs, &from_abs).await;
+                return Err(e.into());
+            }
+            previews::relocate(&state.storage_root, &from, &to).await;
+            moved += 1;
+        }
+    }
+
+    info!(moved, "blobs moved to keyed addresses");
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FsckQuery {
+    verify: Option<bool>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct StoredFileRow {
+    storage_rel_path: String,
+    sha256: Option<String>,
+    encryption_key_id: Option<String>,
+    wrapped_data_key: Option<Vec<u8>>,
+    ref_count: Option<i32>,
+    document_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckEntry {
+    storage_rel_path: String,
+    sha256: Option<String>,
+    document_ids: Vec<Uuid>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefCountMismatch {
+    sha256: String,
+    ref_count: i32,
+    documents: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckReport {
+    checked: usize,
+    verified: bool,
+    missing: Vec<FsckEntry>,
+    corrupted: Vec<FsckEntry>,
+    orphaned: Vec<String>,
+    ref_count_mismatches: Vec<RefCountMismatch>,
+}
+
+impl StoredFileRow {
+    fn entry(&self, error: Option<String>) -> FsckEntry {
+        FsckEntry {
+            storage_rel_path: self.storage_rel_path.clone(),
+            sha256: self.sha256.clone(),
+            document_ids: self.document_ids.clone(),
+            error,
+        }
+    }
+}
+
+async fn check_file(state: &AppState, row: &StoredFileRow) -> io::Result<()> {
+    let key = match (&row.encryption_key_id, &row.wrapped_data_key) {
+        (Some(key_id), Some(wrapped)) => Some(state.keyring.unwrap(key_id, wrapped).map_err(io::Error::other)?),
+        _ => None,
+    };
+    let mut reader = open(&state.storage_root.join(&row.storage_rel_path), key.as_ref()).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    match &row.sha256 {
+        Some(expected) if *expected != hex::encode(hasher.finalize()) => Err(invalid_data("checksum mismatch")),
+        _ => Ok(()),
+    }
+}
+
+async fn stored_files(root: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                let skipped = [
+                    root.join(watermark::CACHE_DIR),
+                    root.join(upload_sessions::STAGING_DIR),
+                    root.join(BLOB_DIR).join(STAGING_DIR),
+                ];
+                if !skipped.contains(&path) {
+                    dirs.push(path);
+                }
+            } else if let Ok(rel) = path.strip_prefix(root) {
+                let rel: Vec<_> = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+                files.push(rel.join("/"));
+            }
+        }
+    }
+    Ok(files)
+}
+
+pub async fn fsck(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Query(q): Query<FsckQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, StoredFileRow>(
+        r#"
+        select
+            b.storage_rel_path, b.sha256, b.encryption_key_id, b.wrapped_data_key, b.ref_count,
+            coalesce(array_agg(d.id) filter (where d.id is not null), '{}') as document_ids
+        from blobs b
+        left join documents d on d.sha256 = b.sha256
+        group by b.sha256
+        union all
+        select d.storage_rel_path, null, d.encryption_key_id, d.wrapped_data_key, null, array[d.id]
+        from documents d
+        where d.sha256 is null
+        "#,
+    )
+    .fetch_all(&state.pool)
+    .await;
+    let rows = match rows {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let verify = q.verify.unwrap_or(false);
+    let mut report = FsckReport {
+        checked: rows.len(),
+        verified: verify,
+        ..Default::default()
+    };
+
+    for row in &rows {
+        if let (Some(sha256), Some(ref_count)) = (&row.sha256, row.ref_count) {
+            if ref_count as usize != row.document_ids.len() {
+                report.ref_count_mismatches.push(RefCountMismatch {
+                    sha256: sha256.clone(),
+                    ref_count,
+                    documents: row.document_ids.len(),
+                });
+            }
+        }
+
+        let result = if verify {
+            check_file(&state, row).await
+        } else {
+            tokio::fs::metadata(state.storage_root.join(&row.storage_rel_path)).await.map(|_| ())
+        };
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => report.missing.push(row.entry(None)),
+            Err(e) => report.corrupted.push(row.entry(Some(e.to_string()))),
+        }
+    }
+
+    let referenced: HashSet<&str> = rows.iter().map(|r| r.storage_rel_path.as_str()).collect();
+    match stored_files(&state.storage_root).await {
+        Ok(files) => {
+            report.orphaned = files
+                .into_iter()
+                .filter(|f| !referenced.contains(f.as_str()))
+                .filter(|f| !previews::source_rel_path(f).is_some_and(|src| referenced.contains(src)))
+                .collect();
+            report.orphaned.sort();
+        }
+        Err(e) => {
+            error!(?e, "scan storage root failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+        }
+    }
+
+    (StatusCode::OK, Json(report)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: usize = FILE_MAGIC.len() + NONCE_PREFIX_LEN;
+    const SEALED_CHUNK: usize = CHUNK_SIZE + TAG_LEN;
+
+    fn key(byte: u8) -> DataKey {
+        DataKey([byte; DATA_KEY_LEN])
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn encrypt(key: &DataKey, data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), key);
+        writer.write_all(data).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.inner
+    }
+
+    async fn decrypt(key: &DataKey, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        DecryptingReader::new(data, key).read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    fn keyring(active: &str) -> Keyring {
+        let master = |id: &str, byte: &str| (id.to_string(), parse_master_key(id, &byte.repeat(DATA_KEY_LEN)).unwrap().0);
+        let keys = HashMap::from([master("k1", "11"), master("k2", "22")]);
+        Keyring {
+            keys: Arc::new(keys),
+            active: Some(active.to_string()),
+            address_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_at_chunk_boundaries() {
+        let key = key(7);
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let data = content(len);
+            let sealed = encrypt(&key, &data).await;
+            let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(sealed.len(), HEADER + chunks * TAG_LEN + len, "{len}");
+            assert_eq!(decrypt(&key, &sealed).await.unwrap(), data, "{len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_files() {
+        let key = key(7);
+        let sealed = encrypt(&key, &content(2 * CHUNK_SIZE + 10)).await;
+        for len in [0, HEADER - 1, HEADER, HEADER + SEALED_CHUNK, HEADER + 2 * SEALED_CHUNK, sealed.len() - 1] {
+            let err = decrypt(&key, &sealed[..len]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_reordered_and_tampered_chunks() {
+        let key = key(7);
+        let sealed = encrypt(&key, &content(3 * CHUNK_SIZE)).await;
+
+        let mut swapped = sealed.clone();
+        let (first, rest) = swapped[HEADER..].split_at_mut(SEALED_CHUNK);
+        first.swap_with_slice(&mut rest[..SEALED_CHUNK]);
+        assert!(decrypt(&key, &swapped).await.is_err());
+
+        for pos in [0, FILE_MAGIC.len(), HEADER, HEADER + SEALED_CHUNK - 1, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[pos] ^= 1;
+            assert!(decrypt(&key, &tampered).await.is_err(), "{pos}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_key() {
+        let sealed = encrypt(&key(7), &content(100)).await;
+        assert!(decrypt(&key(8), &sealed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rewrapped_keys_still_decrypt() {
+        let old = keyring("k1");
+        let (key, wrapped) = old.generate().unwrap().unwrap();
+        assert_eq!(wrapped.key_id, "k1");
+        let data = content(CHUNK_SIZE + 1);
+        let sealed = encrypt(&key, &data).await;
+
+        let new = keyring("k2");
+        let rewrapped = rewrap(&new, "k2", &wrapped.key_id, &wrapped.wrapped).unwrap();
+        assert_eq!(rewrapped.key_id, "k2");
+        assert!(new.data_key(Some("k1"), Some(&rewrapped.wrapped)).is_err());
+        let key = new.data_key(Some("k2"), Some(&rewrapped.wrapped)).unwrap().unwrap();
+        assert_eq!(decrypt(&key, &sealed).await.unwrap(), data);
+    }
+}
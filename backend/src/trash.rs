@@ -0,0 +1,153 @@
+use axum::{
+    extract::{Extension, Path as AxumPath, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    document_api_dto, document_api_dtos, events, is_admin, jobs, publish_event, quotas, retention::{self, EFFECTIVE_POLICY_JOIN}, storage, watermark, AppState, AuthedUser,
+    DocumentRow, DOCUMENT_COLUMNS,
+};
+
+const TRASH_RETENTION_DAYS_DEFAULT: i32 = 30;
+const PURGE_BATCH: i64 = 100;
+
+pub async fn list_trash(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let rows = sqlx::query_as::<_, DocumentRow>(&format!(
+        r#"
+        select {DOCUMENT_COLUMNS}
+        from documents d
+        join users u on u.id = d.owner_id
+        where d.deleted_at is not null and ($1 or d.owner_id = $2)
+        order by d.deleted_at desc
+        "#
+    ))
+    .bind(is_admin(&authed))
+    .bind(authed.id)
+    .fetch_all(&state.pool)
+    .await;
+
+    let rows = match rows {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    match document_api_dtos(&state.pool, rows).await {
+        Ok(docs) => (StatusCode::OK, Json(docs)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn restore_document(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let owner = sqlx::query_scalar::<_, Uuid>("select owner_id from documents where id = $1 and deleted_at is not null")
+        .bind(id)
+        .fetch_optional(&state.pool)
+        .await;
+    match owner {
+        Ok(Some(owner_id)) if is_admin(&authed) || owner_id == authed.id => {}
+        Ok(Some(_)) => return (StatusCode::FORBIDDEN, "forbidden").into_response(),
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let restored = sqlx::query_as::<_, DocumentRow>(&format!(
+        r#"
+        with d as (
+            update documents
+            set deleted_at = null, deleted_by = null, updated_at = now()
+            where id = $1 and deleted_at is not null
+            returning *
+        )
+        select {DOCUMENT_COLUMNS} from d join users u on u.id = d.owner_id
+        "#
+    ))
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match restored {
+        Ok(Some(doc)) => {
+            publish_event(&state.pool, events::Event::DocumentRestored { document_id: doc.id }).await;
+            match document_api_dto(&state.pool, doc).await {
+                Ok(api) => (StatusCode::OK, Json(api)).into_response(),
+                Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+            }
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn purge_document(state: &AppState, id: Uuid) -> anyhow::Result<bool> {
+    let mut tx = state.pool.begin().await?;
+    let row = sqlx::query_as::<_, (String, Option<String>, Uuid, i64)>(
+        "delete from documents where id = $1 and deleted_at is not null and not legal_hold returning storage_rel_path, sha256, owner_id, size",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((storage_rel_path, sha256, owner_id, size)) = row else {
+        return Ok(false);
+    };
+    quotas::release(&mut tx, owner_id, size).await?;
+    let details = serde_json::json!({ "ownerId": owner_id, "size": size, "sha256": sha256 });
+    retention::audit(&mut *tx, Some(id), None, "document.purged", details).await?;
+
+    match sha256 {
+        Some(sha256) => storage::release_blob(&mut tx, &sha256).await?,
+        None => {
+            let payload = serde_json::json!(jobs::RemoveFilePayload { storage_rel_path });
+            jobs::enqueue(&mut *tx, jobs::REMOVE_FILE, payload).await?;
+        }
+    }
+    tx.commit().await?;
+
+    watermark::purge(&state.storage_root, id).await;
+    Ok(true)
+}
+
+pub async fn purge_expired(state: &AppState) -> anyhow::Result<()> {
+    let retention_days: i32 = std::env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(TRASH_RETENTION_DAYS_DEFAULT)
+        .max(0);
+
+    let mut purged = 0u64;
+    loop {
+        let ids = sqlx::query_scalar::<_, Uuid>(&format!(
+            r#"
+            select d.id from documents d
+            {EFFECTIVE_POLICY_JOIN}
+            where d.deleted_at is not null and d.deleted_at < now() - make_interval(days => $1)
+                and not d.legal_hold
+                and (p.min_retention_days is null or d.created_at + make_interval(days => p.min_retention_days) <= now())
+            order by d.deleted_at
+            limit $2
+            "#
+        ))
+        .bind(retention_days)
+        .bind(PURGE_BATCH)
+        .fetch_all(&state.pool)
+        .await?;
+        if ids.is_empty() {
+            break;
+        }
+        for id in ids {
+            if purge_document(state, id).await? {
+                purged += 1;
+            }
+        }
+    }
+
+    if purged > 0 {
+        info!(purged, retention_days, "purged trashed documents");
+    }
+    Ok(())
+}
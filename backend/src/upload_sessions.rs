@@ -0,0 +1,557 @@
+use std::path::{Path, PathBuf};
+
+use axum::{
+    body::Bytes,
+    extract::{Extension, Path as AxumPath, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    announce_document, content_types, insert_document, normalize_folder, quotas, storage, AppState, AuthedUser, DocumentApiDto, DocumentDto,
+    UploadSettings, UploadedFile,
+};
+
+pub const STAGING_DIR: &str = ".uploads";
+
+const ASSEMBLE_BUFFER: usize = 256 * 1024;
+const UPLOAD_MAX_BYTES_DEFAULT: i64 = 2 * 1024 * 1024 * 1024;
+const UPLOAD_SESSION_TTL_HOURS_DEFAULT: i64 = 24;
+const UPLOAD_SESSIONS_PER_USER: i64 = 20;
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+
+const SESSION_COLUMNS: &str = r#"
+    id, owner_id, file_name, mime_type, folder, notes, permission, allowed_users, is_generated, requestable, watermark,
+    view_requires_approval, upload_length, upload_offset, sha256, encryption_key_id, wrapped_data_key, created_at, expires_at
+"#;
+
+#[derive(Debug, sqlx::FromRow)]
+struct SessionRow {
+    id: Uuid,
+    owner_id: Uuid,
+    file_name: String,
+    mime_type: String,
+    folder: String,
+    notes: String,
+    permission: String,
+    allowed_users: Vec<Uuid>,
+    is_generated: bool,
+    requestable: bool,
+    watermark: bool,
+    view_requires_approval: bool,
+    upload_length: i64,
+    upload_offset: i64,
+    sha256: Option<String>,
+    encryption_key_id: Option<String>,
+    wrapped_data_key: Option<Vec<u8>>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSessionDto {
+    id: Uuid,
+    owner_id: Uuid,
+    file_name: String,
+    mime_type: String,
+    folder: String,
+    upload_length: i64,
+    upload_offset: i64,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl From<&SessionRow> for UploadSessionDto {
+    fn from(r: &SessionRow) -> Self {
+        Self {
+            id: r.id,
+            owner_id: r.owner_id,
+            file_name: r.file_name.clone(),
+            mime_type: r.mime_type.clone(),
+            folder: r.folder.clone(),
+            upload_length: r.upload_length,
+            upload_offset: r.upload_offset,
+            created_at: r.created_at,
+            expires_at: r.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadRequest {
+    file_name: String,
+    mime_type: Option<String>,
+    upload_length: i64,
+    sha256: Option<String>,
+    folder: Option<String>,
+    notes: Option<String>,
+    permission: Option<String>,
+    allowed_users: Option<Vec<Uuid>>,
+    is_generated: Option<bool>,
+    requestable: Option<bool>,
+    watermark: Option<bool>,
+    view_requires_approval: Option<bool>,
+}
+
+fn max_upload_bytes() -> i64 {
+    std::env::var("UPLOAD_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(UPLOAD_MAX_BYTES_DEFAULT)
+}
+
+fn session_ttl_hours() -> i64 {
+    std::env::var("UPLOAD_SESSION_TTL_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(UPLOAD_SESSION_TTL_HOURS_DEFAULT)
+        .max(1)
+}
+
+fn staging_dir(storage_root: &Path, id: Uuid) -> PathBuf {
+    storage_root.join(STAGING_DIR).join(id.to_string())
+}
+
+// Chunks are kept as one file per accepted PATCH, named by the offset they start at.
+fn segment_path(storage_root: &Path, id: Uuid, offset: i64) -> PathBuf {
+    staging_dir(storage_root, id).join(format!("{offset:020}"))
+}
+
+fn offset_headers(session: &SessionRow) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_OFFSET, HeaderValue::from(session.upload_offset));
+    headers.insert(UPLOAD_LENGTH, HeaderValue::from(session.upload_length));
+    if let Ok(v) = HeaderValue::from_str(&session.expires_at.to_rfc3339()) {
+        headers.insert(UPLOAD_EXPIRES, v);
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers
+}
+
+async fn fetch_session<'e>(exec: impl sqlx::PgExecutor<'e>, id: Uuid, owner_id: Uuid, lock: bool) -> sqlx::Result<Option<SessionRow>> {
+    sqlx::query_as::<_, SessionRow>(&format!(
+        "select {SESSION_COLUMNS} from upload_sessions where id = $1 and owner_id = $2 and expires_at > now(){}",
+        if lock { " for update" } else { "" }
+    ))
+    .bind(id)
+    .bind(owner_id)
+    .fetch_optional(exec)
+    .await
+}
+
+pub async fn create_upload(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(req): Json<CreateUploadRequest>,
+) -> impl IntoResponse {
+    let file_name = req.file_name.trim().to_string();
+    if file_name.is_empty() || file_name.contains(['/', '\\']) {
+        return (StatusCode::BAD_REQUEST, "invalid file name").into_response();
+    }
+    if let Err((status, msg)) = content_types::check_name(&file_name) {
+        return (status, msg).into_response();
+    }
+    if req.upload_length < 0 {
+        return (StatusCode::BAD_REQUEST, "invalid upload length").into_response();
+    }
+    if req.upload_length > max_upload_bytes() {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "upload too large").into_response();
+    }
+    let sha256 = req.sha256.map(|v| v.trim().to_ascii_lowercase());
+    if sha256.as_ref().is_some_and(|v| v.len() != 64 || !v.bytes().all(|b| b.is_ascii_hexdigit())) {
+        return (StatusCode::BAD_REQUEST, "invalid sha256").into_response();
+    }
+    let Some(folder) = normalize_folder(req.folder.as_deref().unwrap_or("")) else {
+        return (StatusCode::BAD_REQUEST, "invalid folder").into_response();
+    };
+    let permission = req.permission.unwrap_or_else(|| "public".to_string());
+    if permission != "public" && permission != "private" && permission != "specific" {
+        return (StatusCode::BAD_REQUEST, "invalid permission").into_response();
+    }
+    let allowed_users = if permission == "specific" { req.allowed_users.unwrap_or_default() } else { vec![] };
+    let mime_type = req
+        .mime_type
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| crate::uploads::guess_mime(&file_name));
+
+    let active = sqlx::query_scalar::<_, i64>("select count(*) from upload_sessions where owner_id = $1 and expires_at > now()")
+        .bind(authed.id)
+        .fetch_one(&state.pool)
+        .await;
+    match active {
+        Ok(n) if n >= UPLOAD_SESSIONS_PER_USER => {
+            return (StatusCode::TOO_MANY_REQUESTS, "too many active uploads").into_response();
+        }
+        Ok(_) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    // Checked again when the upload is finalized; this only spares the client a doomed transfer.
+    if let Err(msg) = quotas::check_free_space(&state.storage_root, req.upload_length as u64) {
+        return (StatusCode::INSUFFICIENT_STORAGE, msg).into_response();
+    }
+    let quota = async {
+        let mut tx = state.pool.begin().await?;
+        quotas::reserve(&mut tx, authed.id, req.upload_length).await
+    }
+    .await;
+    match quota {
+        Ok(Ok(())) => {}
+        Ok(Err(msg)) => return (StatusCode::PAYLOAD_TOO_LARGE, msg).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let wrapped = match state.keyring.generate() {
+        Ok(v) => v.map(|(_, w)| w),
+        Err(e) => {
+            error!(?e, "generate upload key failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+        }
+    };
+
+    let created = sqlx::query_as::<_, SessionRow>(&format!(
+        r#"
+        insert into upload_sessions
+            (id, owner_id, file_name, mime_type, folder, notes, permission, allowed_users, is_generated, requestable, watermark,
+             view_requires_approval, upload_length, sha256, encryption_key_id, wrapped_data_key, expires_at)
+        values
+            ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16, now() + make_interval(hours => $17))
+        returning {SESSION_COLUMNS}
+        "#
+    ))
+    .bind(Uuid::new_v4())
+    .bind(authed.id)
+    .bind(&file_name)
+    .bind(&mime_type)
+    .bind(&folder)
+    .bind(req.notes.unwrap_or_default())
+    .bind(&permission)
+    .bind(&allowed_users)
+    .bind(req.is_generated.unwrap_or(false))
+    .bind(req.requestable.unwrap_or(false))
+    .bind(req.watermark.unwrap_or(false))
+    .bind(req.view_requires_approval.unwrap_or(false))
+    .bind(req.upload_length)
+    .bind(&sha256)
+    .bind(wrapped.as_ref().map(|w| w.key_id.clone()))
+    .bind(wrapped.as_ref().map(|w| w.wrapped.clone()))
+    .bind(session_ttl_hours() as i32)
+    .fetch_one(&state.pool)
+    .await;
+
+    match created {
+        Ok(session) => {
+            let mut headers = offset_headers(&session);
+            if let Ok(v) = HeaderValue::from_str(&format!("/uploads/{}", session.id)) {
+                headers.insert(header::LOCATION, v);
+            }
+            (StatusCode::CREATED, headers, Json(UploadSessionDto::from(&session))).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_uploads(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    let rows = sqlx::query_as::<_, SessionRow>(&format!(
+        "select {SESSION_COLUMNS} from upload_sessions where owner_id = $1 and expires_at > now() order by created_at desc"
+    ))
+    .bind(authed.id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v.iter().map(UploadSessionDto::from).collect::<Vec<_>>())).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn get_upload(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    match fetch_session(&state.pool, id, authed.id, false).await {
+        Ok(Some(session)) => (StatusCode::OK, offset_headers(&session), Json(UploadSessionDto::from(&session))).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+// Appends one chunk. The session row stays locked while the chunk is written, so concurrent PATCHes for the
+// same offset are serialized and only the first one is accepted.
+pub async fn patch_upload(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    if !content_type.trim().eq_ignore_ascii_case(OFFSET_CONTENT_TYPE) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "content type must be application/offset+octet-stream").into_response();
+    }
+    let Some(offset) = headers
+        .get(&UPLOAD_OFFSET)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<i64>().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "Upload-Offset header is required").into_response();
+    };
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let session = match fetch_session(&mut *tx, id, authed.id, true).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    if offset != session.upload_offset {
+        return (StatusCode::CONFLICT, offset_headers(&session), "offset mismatch").into_response();
+    }
+    if offset + body.len() as i64 > session.upload_length {
+        return (StatusCode::PAYLOAD_TOO_LARGE, offset_headers(&session), "chunk exceeds upload length").into_response();
+    }
+
+    if !body.is_empty() {
+        if let Err(msg) = quotas::check_free_space(&state.storage_root, body.len() as u64) {
+            return (StatusCode::INSUFFICIENT_STORAGE, offset_headers(&session), msg).into_response();
+        }
+        let key = match state
+            .keyring
+            .data_key(session.encryption_key_id.as_deref(), session.wrapped_data_key.as_deref())
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!(?e, %id, "unwrap upload key failed");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+            }
+        };
+        let path = segment_path(&state.storage_root, id, offset);
+        let tmp = path.with_extension("part");
+        let written = async {
+            tokio::fs::create_dir_all(staging_dir(&state.storage_root, id)).await?;
+            storage::write(&tmp, key.as_ref(), &body).await?;
+            tokio::fs::rename(&tmp, &path).await
+        }
+        .await;
+        if let Err(e) = written {
+            error!(?e, %id, "write upload chunk failed");
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+        }
+    }
+
+    let updated = sqlx::query_as::<_, SessionRow>(&format!(
+        r#"
+        update upload_sessions
+        set upload_offset = upload_offset + $2, expires_at = now() + make_interval(hours => $3)
+        where id = $1
+        returning {SESSION_COLUMNS}
+        "#
+    ))
+    .bind(id)
+    .bind(body.len() as i64)
+    .bind(session_ttl_hours() as i32)
+    .fetch_one(&mut *tx)
+    .await;
+
+    match updated {
+        Ok(session) => match tx.commit().await {
+            Ok(_) => (StatusCode::NO_CONTENT, offset_headers(&session)).into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+        },
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+// Writes the decrypted segments to `sink` in order, checking they add up to exactly the upload length.
+async fn assemble<W: AsyncWrite + Unpin>(state: &AppState, session: &SessionRow, mut sink: W) -> anyhow::Result<()> {
+    let key = state
+        .keyring
+        .data_key(session.encryption_key_id.as_deref(), session.wrapped_data_key.as_deref())?;
+    let mut offset = 0i64;
+    while offset < session.upload_length {
+        let mut segment = storage::open(&segment_path(&state.storage_root, session.id, offset), key.as_ref()).await?;
+        let copied = tokio::io::copy(&mut segment, &mut sink).await?;
+        if copied == 0 {
+            anyhow::bail!("empty upload segment at offset {offset}");
+        }
+        offset += copied as i64;
+    }
+    if offset != session.upload_length {
+        anyhow::bail!("upload segments exceed upload length");
+    }
+    sink.shutdown().await?;
+    Ok(())
+}
+
+// Stages the assembled upload as a blob, keeping its first bytes for content inspection.
+async fn stage<R: AsyncRead + Unpin>(state: &AppState, mut source: R) -> anyhow::Result<(Vec<u8>, storage::StagedBlob)> {
+    let mut head = Vec::with_capacity(content_types::INSPECT_LEN);
+    (&mut source).take(content_types::INSPECT_LEN as u64).read_to_end(&mut head).await?;
+    let staged = storage::stage_blob(state, head.as_slice().chain(source)).await?;
+    Ok((head, staged))
+}
+
+// Turns a fully received upload into a document. The document is inserted and the session deleted in the
+// transaction that holds the session's lock, so a retried finalize cannot create the document twice.
+pub async fn finalize_upload(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    let session = match fetch_session(&mut *tx, id, authed.id, true).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+    if session.upload_offset != session.upload_length {
+        return (StatusCode::CONFLICT, offset_headers(&session), "upload incomplete").into_response();
+    }
+    if let Err(msg) = quotas::check_free_space(&state.storage_root, session.upload_length as u64) {
+        return (StatusCode::INSUFFICIENT_STORAGE, msg).into_response();
+    }
+
+    // Segments are decrypted and re-encrypted under the blob's key one chunk at a time.
+    let (sink, source) = tokio::io::duplex(ASSEMBLE_BUFFER);
+    let (assembled, staged) = tokio::join!(assemble(&state, &session, sink), stage(&state, source));
+    let (head, staged) = match (assembled, staged) {
+        (Ok(()), Ok(v)) => v,
+        (assembled, staged) => {
+            if let Err(e) = assembled {
+                error!(?e, %id, "assemble upload failed");
+            }
+            match staged {
+                Ok((_, staged)) => staged.discard().await,
+                Err(e) => error!(?e, %id, "stage upload failed"),
+            }
+            return (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response();
+        }
+    };
+    if session.sha256.as_deref().is_some_and(|expected| expected != staged.sha256()) {
+        staged.discard().await;
+        return (StatusCode::UNPROCESSABLE_ENTITY, "checksum mismatch").into_response();
+    }
+    let mime_type = match content_types::inspect(&session.file_name, &head, staged.size()) {
+        Ok(v) => v,
+        Err((status, msg)) => {
+            staged.discard().await;
+            return (status, msg).into_response();
+        }
+    };
+
+    if sqlx::query("delete from upload_sessions where id = $1").bind(id).execute(&mut *tx).await.is_err() {
+        staged.discard().await;
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    let settings = UploadSettings {
+        notes: session.notes.clone(),
+        permission: session.permission.clone(),
+        allowed_users: session.allowed_users.clone(),
+        is_generated: session.is_generated,
+        requestable: session.requestable,
+        watermark: session.watermark,
+        view_requires_approval: session.view_requires_approval,
+    };
+    let file = UploadedFile {
+        folder: &session.folder,
+        name: &session.file_name,
+        mime_type: &mime_type,
+    };
+    let (doc, blob) = match insert_document(&mut tx, &state, &authed, &settings, &file, staged).await {
+        Ok(v) => v,
+        Err((status, msg)) => return (status, msg).into_response(),
+    };
+    if let Err(e) = tx.commit().await {
+        error!(?e, %id, "finalize upload failed");
+        storage::discard(&state, &blob).await;
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+    announce_document(&state, &doc).await;
+    remove_staging(&state.storage_root, id).await;
+
+    (StatusCode::CREATED, Json(DocumentApiDto::from(DocumentDto::from(doc)))).into_response()
+}
+
+pub async fn delete_upload(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    let deleted = sqlx::query("delete from upload_sessions where id = $1 and owner_id = $2")
+        .bind(id)
+        .bind(authed.id)
+        .execute(&state.pool)
+        .await;
+    match deleted {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => {
+            remove_staging(&state.storage_root, id).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+async fn remove_staging(storage_root: &Path, id: Uuid) {
+    match tokio::fs::remove_dir_all(staging_dir(storage_root, id)).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(?e, %id, "remove upload staging dir failed"),
+    }
+}
+
+// Drops expired sessions, then any staging directory that no longer has a session (for example after the
+// owner was deleted). Directories are listed before the lookup so a session created meanwhile is never hit.
+pub async fn expire_sessions(state: &AppState) -> anyhow::Result<()> {
+    let expired = sqlx::query_scalar::<_, Uuid>("delete from upload_sessions where expires_at <= now() returning id")
+        .fetch_all(&state.pool)
+        .await?;
+    for id in &expired {
+        remove_staging(&state.storage_root, *id).await;
+    }
+
+    let mut staged = vec![];
+    match tokio::fs::read_dir(state.storage_root.join(STAGING_DIR)).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await? {
+                if let Some(id) = entry.file_name().to_str().and_then(|n| Uuid::parse_str(n).ok()) {
+                    staged.push(id);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let live = sqlx::query_scalar::<_, Uuid>("select id from upload_sessions where id = any($1)")
+        .bind(&staged)
+        .fetch_all(&state.pool)
+        .await?;
+    let orphaned: Vec<Uuid> = staged.into_iter().filter(|id| !live.contains(id)).collect();
+    for id in &orphaned {
+        remove_staging(&state.storage_root, *id).await;
+    }
+
+    if !expired.is_empty() || !orphaned.is_empty() {
+        info!(expired = expired.len(), orphaned = orphaned.len(), "cleaned up upload sessions");
+    }
+    Ok(())
+}
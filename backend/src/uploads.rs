@@ -0,0 +1,275 @@
+use std::io::{Cursor, Read};
+
+use serde::Serialize;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{normalize_folder, DocumentApiDto};
+
+const ZIP_MAX_ENTRIES_DEFAULT: usize = 1000;
+const ZIP_MAX_UNCOMPRESSED_BYTES_DEFAULT: u64 = 512 * 1024 * 1024;
+const ZIP_MAX_RATIO_DEFAULT: u64 = 100;
+// Small entries compress absurdly well without being dangerous.
+const ZIP_RATIO_MIN_BYTES: u64 = 1024 * 1024;
+const ZIP_CONTENT_TYPES: &[&str] = &["application/zip", "application/x-zip-compressed"];
+
+pub struct UploadItem {
+    pub source: String,
+    pub folder: String,
+    pub name: String,
+    pub data: Result<Vec<u8>, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadResult {
+    pub source: String,
+    pub folder: String,
+    pub name: String,
+    pub status: &'static str,
+    pub document: Option<DocumentApiDto>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkUploadResponse {
+    pub created: usize,
+    pub failed: usize,
+    pub results: Vec<UploadResult>,
+}
+
+struct ZipLimits {
+    max_entries: usize,
+    max_bytes: u64,
+    max_ratio: u64,
+}
+
+impl ZipLimits {
+    fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|v| v.parse::<T>().ok()).unwrap_or(default)
+        }
+        Self {
+            max_entries: var("ZIP_MAX_ENTRIES", ZIP_MAX_ENTRIES_DEFAULT),
+            max_bytes: var("ZIP_MAX_UNCOMPRESSED_BYTES", ZIP_MAX_UNCOMPRESSED_BYTES_DEFAULT),
+            max_ratio: var("ZIP_MAX_RATIO", ZIP_MAX_RATIO_DEFAULT).max(1),
+        }
+    }
+}
+
+// Only plain .zip archives are unpacked; docx, xlsx, odt, epub and friends are zip containers too but are
+// documents in their own right.
+pub fn is_zip_archive(name: &str, content_type: Option<&str>, data: &[u8]) -> bool {
+    let magic = data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06");
+    let declared = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.eq_ignore_ascii_case("zip"),
+        _ => content_type
+            .and_then(|t| t.split(';').next())
+            .is_some_and(|t| ZIP_CONTENT_TYPES.iter().any(|z| t.trim().eq_ignore_ascii_case(z))),
+    };
+    magic && declared
+}
+
+pub fn guess_mime(name: &str) -> String {
+    mime_guess::from_path(name).first_or_octet_stream().essence_str().to_string()
+}
+
+fn failed(source: &str, folder: &str, error: impl Into<String>) -> UploadItem {
+    UploadItem {
+        source: source.to_string(),
+        folder: folder.to_string(),
+        name: source.rsplit('/').next().unwrap_or(source).to_string(),
+        data: Err(error.into()),
+    }
+}
+
+// Expands an uploaded archive, handing `emit` one item per file as soon as it has been read. Entries that
+// escape the archive root, or whose declared or actual size breaks the limits, come back as failed items
+// instead of being extracted.
+pub fn unpack_zip(archive_name: &str, data: &[u8], base_folder: &str, mut emit: impl FnMut(UploadItem)) {
+    let limits = ZipLimits::from_env();
+    let mut zip = match ZipArchive::new(Cursor::new(data)) {
+        Ok(v) => v,
+        Err(_) => return emit(failed(archive_name, base_folder, "invalid zip archive")),
+    };
+    if zip.len() > limits.max_entries {
+        return emit(failed(archive_name, base_folder, "zip archive has too many entries"));
+    }
+
+    let mut declared = 0u64;
+    for i in 0..zip.len() {
+        match zip.by_index_raw(i) {
+            Ok(entry) => declared = declared.saturating_add(entry.size()),
+            Err(_) => return emit(failed(archive_name, base_folder, "invalid zip archive")),
+        }
+    }
+    if declared > limits.max_bytes {
+        return emit(failed(archive_name, base_folder, "zip archive is too large when unpacked"));
+    }
+
+    let mut extracted = 0u64;
+    for i in 0..zip.len() {
+        let mut entry = match zip.by_index(i) {
+            Ok(v) => v,
+            Err(ZipError::UnsupportedArchive(msg)) => {
+                emit(failed(&format!("{archive_name}#{i}"), base_folder, format!("unsupported entry: {msg}")));
+                continue;
+            }
+            Err(_) => {
+                emit(failed(&format!("{archive_name}#{i}"), base_folder, "invalid zip entry"));
+                continue;
+            }
+        };
+        let source = format!("{archive_name}/{}", entry.name());
+        if entry.is_dir() {
+            continue;
+        }
+
+        let raw = entry.name();
+        let absolute = raw.starts_with(['/', '\\']) || raw.as_bytes().get(1) == Some(&b':');
+        let Some(path) = entry.enclosed_name().filter(|_| !absolute) else {
+            emit(failed(&source, base_folder, "unsafe path in zip entry"));
+            continue;
+        };
+        let parts: Vec<String> = path.iter().map(|p| p.to_string_lossy().into_owned()).collect();
+        let Some((name, dirs)) = parts.split_last() else {
+            continue;
+        };
+        if dirs.first().is_some_and(|d| d == "__MACOSX") || name == ".DS_Store" {
+            continue;
+        }
+        let folder = match normalize_folder(&format!("{base_folder}/{}", dirs.join("/"))) {
+            Some(v) => v,
+            None => {
+                emit(failed(&source, base_folder, "unsafe path in zip entry"));
+                continue;
+            }
+        };
+
+        let size = entry.size();
+        if size >= ZIP_RATIO_MIN_BYTES && size / entry.compressed_size().max(1) > limits.max_ratio {
+            emit(failed(&source, &folder, "zip entry compression ratio too high"));
+            continue;
+        }
+
+        let mut out = Vec::with_capacity(size.min(limits.max_bytes) as usize);
+        let budget = limits.max_bytes.saturating_sub(extracted).min(size);
+        let read = (&mut entry).take(budget + 1).read_to_end(&mut out);
+        let data = match read {
+            Ok(_) if out.len() as u64 > budget => Err("zip entry is larger than declared".to_string()),
+            Ok(_) => {
+                extracted += out.len() as u64;
+                Ok(out)
+            }
+            Err(_) => Err("corrupt zip entry".to_string()),
+        };
+        emit(UploadItem {
+            source,
+            folder,
+            name: name.clone(),
+            data,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    fn archive(entries: &[(&str, &[u8])], method: CompressionMethod) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            zip.start_file(*name, SimpleFileOptions::default().compression_method(method)).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    // Rewrites the uncompressed size recorded in every local and central directory header.
+    fn declare_size(data: &mut [u8], size: u32) {
+        for i in 0..data.len().saturating_sub(30) {
+            let offset = match &data[i..i + 4] {
+                b"PK\x03\x04" => 22,
+                b"PK\x01\x02" => 24,
+                _ => continue,
+            };
+            data[i + offset..i + offset + 4].copy_from_slice(&size.to_le_bytes());
+        }
+    }
+
+    fn unpack(data: &[u8]) -> Vec<UploadItem> {
+        let mut items = vec![];
+        unpack_zip("upload.zip", data, "base", |item| items.push(item));
+        items
+    }
+
+    fn error(item: &UploadItem) -> &str {
+        item.data.as_ref().err().map(String::as_str).unwrap_or("")
+    }
+
+    #[test]
+    fn unpacks_entries_into_folders() {
+        let data = archive(
+            &[("docs/q3/report.txt", b"numbers"), ("__MACOSX/docs/._report.txt", b"junk"), ("top.txt", b"hi")],
+            CompressionMethod::Deflated,
+        );
+        let items = unpack(&data);
+        assert_eq!(items.len(), 2);
+        assert_eq!((items[0].folder.as_str(), items[0].name.as_str()), ("base/docs/q3", "report.txt"));
+        assert_eq!(items[0].data.as_deref().unwrap(), b"numbers");
+        assert_eq!((items[1].folder.as_str(), items[1].name.as_str()), ("base", "top.txt"));
+    }
+
+    #[test]
+    fn rejects_paths_outside_the_archive() {
+        for name in ["../evil.txt", "docs/../../evil.txt", "/etc/passwd", "\\evil.txt", "C:/Windows/evil.txt", "C:\\evil.txt"] {
+            let items = unpack(&archive(&[(name, b"x")], CompressionMethod::Stored));
+            assert_eq!(items.len(), 1, "{name}");
+            assert_eq!(error(&items[0]), "unsafe path in zip entry", "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_high_compression_ratio() {
+        let zeros = vec![0u8; 4 * 1024 * 1024];
+        let items = unpack(&archive(&[("zeros.bin", &zeros)], CompressionMethod::Deflated));
+        assert_eq!(items.len(), 1);
+        assert_eq!(error(&items[0]), "zip entry compression ratio too high");
+    }
+
+    #[test]
+    fn rejects_entry_larger_than_declared() {
+        let mut data = archive(&[("notes.txt", b"considerably more than five bytes")], CompressionMethod::Stored);
+        declare_size(&mut data, 5);
+        let items = unpack(&data);
+        assert_eq!(items.len(), 1);
+        assert_eq!(error(&items[0]), "zip entry is larger than declared");
+    }
+
+    #[test]
+    fn rejects_archive_declared_too_large() {
+        let mut data = archive(&[("a.txt", b"a"), ("b.txt", b"b")], CompressionMethod::Stored);
+        declare_size(&mut data, u32::MAX - 1);
+        let items = unpack(&data);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].source, "upload.zip");
+        assert_eq!(error(&items[0]), "zip archive is too large when unpacked");
+    }
+
+    #[test]
+    fn only_plain_zip_archives_are_unpacked() {
+        let data = archive(&[("word/document.xml", b"<w:document/>")], CompressionMethod::Deflated);
+        assert!(is_zip_archive("bundle.zip", None, &data));
+        assert!(is_zip_archive("BUNDLE.ZIP", Some("application/octet-stream"), &data));
+        assert!(is_zip_archive("bundle", Some("application/zip"), &data));
+        assert!(!is_zip_archive("report.docx", Some("application/zip"), &data));
+        assert!(!is_zip_archive("sheet.xlsx", None, &data));
+        assert!(!is_zip_archive("book.epub", None, &data));
+        assert!(!is_zip_archive("bundle", None, &data));
+        assert!(!is_zip_archive("fake.zip", Some("application/zip"), b"not a zip"));
+    }
+}
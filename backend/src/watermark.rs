@@ -0,0 +1,294 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use font8x8::UnicodeFonts;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat, Rgba, RgbaImage};
+use lopdf::{dictionary, Dictionary, Object, ObjectId, Stream};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    storage::{self, DataKey},
+    AppState, DocumentRow,
+};
+
+pub const CACHE_DIR: &str = ".watermarks";
+const PDF_FONT_NAME: &str = "XdocsWatermark";
+const PDF_FONT_SIZE: f32 = 7.0;
+const PDF_MARGIN: f32 = 18.0;
+const IMAGE_BAND_ALPHA: f32 = 0.55;
+const JPEG_QUALITY: u8 = 90;
+const INHERIT_DEPTH_MAX: usize = 32;
+
+#[derive(Debug, sqlx::FromRow)]
+struct StampRow {
+    applicant_name: String,
+    applicant_company: String,
+    approved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Pdf,
+    Image(ImageFormat),
+}
+
+fn detect(data: &[u8]) -> Option<Kind> {
+    if data.starts_with(b"%PDF-") {
+        return Some(Kind::Pdf);
+    }
+    match image::guess_format(data) {
+        Ok(f @ (ImageFormat::Png | ImageFormat::Jpeg)) => Some(Kind::Image(f)),
+        _ => None,
+    }
+}
+
+fn label(row: &StampRow, request_id: Uuid) -> String {
+    let mut issued_to = row.applicant_name.trim().to_string();
+    if !row.applicant_company.trim().is_empty() {
+        issued_to = format!("{issued_to}, {}", row.applicant_company.trim());
+    }
+    let approved_at = row
+        .approved_at
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| "-".to_string());
+    format!("Issued to {issued_to} | approved {approved_at} | ref {request_id}")
+}
+
+fn cache_path(storage_root: &Path, document_id: Uuid, request_id: Uuid) -> PathBuf {
+    storage_root.join(CACHE_DIR).join(document_id.to_string()).join(request_id.to_string())
+}
+
+// Whether `apply` would stamp the document, so other content can be streamed untouched.
+pub fn applies(doc: &DocumentRow) -> bool {
+    doc.watermark && matches!(doc.mime_type.as_str(), "application/pdf" | "image/png" | "image/jpeg")
+}
+
+pub async fn apply(state: &AppState, doc: &DocumentRow, request_id: Uuid, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if !doc.watermark {
+        return Ok(data);
+    }
+    stamp(state, doc, request_id, data, || async {
+        let row = sqlx::query_as::<_, StampRow>(
+            "select applicant_name, applicant_company, approved_at from download_requests where id = $1",
+        )
+        .bind(request_id)
+        .fetch_one(&state.pool)
+        .await?;
+        Ok(label(&row, request_id))
+    })
+    .await
+}
+
+// Stamps the viewer's identity for inline views that no approved request covers.
+pub async fn apply_for_viewer(state: &AppState, doc: &DocumentRow, user_id: Uuid, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if !doc.watermark {
+        return Ok(data);
+    }
+    stamp(state, doc, user_id, data, || async {
+        let username = sqlx::query_scalar::<_, String>("select username from users where id = $1")
+            .bind(user_id)
+            .fetch_one(&state.pool)
+            .await?;
+        Ok(format!("Viewed by {username} | ref {user_id}"))
+    })
+    .await
+}
+
+async fn stamp<F, Fut>(state: &AppState, doc: &DocumentRow, cache_id: Uuid, data: Vec<u8>, text: F) -> anyhow::Result<Vec<u8>>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<String>>,
+{
+    let Some(kind) = detect(&data) else {
+        return Ok(data);
+    };
+
+    let key = state.keyring.document_key(doc)?;
+    let path = cache_path(&state.storage_root, doc.id, cache_id);
+    if let Ok(cached) = storage::read(&path, key.as_ref()).await {
+        return Ok(cached);
+    }
+
+    let text = text().await?;
+    let stamped = tokio::task::spawn_blocking(move || match kind {
+        Kind::Pdf => stamp_pdf(&data, &text),
+        Kind::Image(format) => stamp_image(&data, format, &text),
+    })
+    .await??;
+
+    if let Err(e) = write_cache(&path, key.as_ref(), &stamped).await {
+        warn!(?e, document_id = %doc.id, %cache_id, "watermark cache write failed");
+    }
+    Ok(stamped)
+}
+
+async fn write_cache(path: &Path, key: Option<&DataKey>, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    storage::write(&tmp, key, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+pub async fn purge(storage_root: &Path, document_id: Uuid) {
+    let dir = storage_root.join(CACHE_DIR).join(document_id.to_string());
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(?e, %document_id, "watermark cache purge failed"),
+    }
+}
+
+pub fn inherited<'a>(pdf: &'a lopdf::Document, page_id: ObjectId, key: &[u8]) -> Option<&'a Object> {
+    let mut node = pdf.get_dictionary(page_id).ok()?;
+    for _ in 0..INHERIT_DEPTH_MAX {
+        if let Ok(v) = node.get(key) {
+            return pdf.dereference(v).ok().map(|(_, v)| v);
+        }
+        let parent = node.get(b"Parent").and_then(Object::as_reference).ok()?;
+        node = pdf.get_dictionary(parent).ok()?;
+    }
+    None
+}
+
+fn media_origin(pdf: &lopdf::Document, page_id: ObjectId) -> (f32, f32) {
+    let corner = inherited(pdf, page_id, b"MediaBox")
+        .and_then(|v| v.as_array().ok())
+        .and_then(|a| Some((a.first()?.as_float().ok()?, a.get(1)?.as_float().ok()?)));
+    corner.unwrap_or((0.0, 0.0))
+}
+
+fn pdf_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '(' | ')' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            '\u{a0}'..='\u{ff}' => out.push_str(&format!("\\{:03o}", c as u32)),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+fn stamp_pdf(data: &[u8], text: &str) -> anyhow::Result<Vec<u8>> {
+    let mut pdf = lopdf::Document::load_mem(data).context("parse pdf")?;
+    if pdf.is_encrypted() {
+        anyhow::bail!("encrypted pdf");
+    }
+
+    let font_id = pdf.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let save_id = pdf.add_object(Stream::new(Dictionary::new(), b"q\n".to_vec()));
+    let escaped = pdf_text(text);
+
+    for page_id in pdf.get_pages().into_values() {
+        let (x0, y0) = media_origin(&pdf, page_id);
+        let content = format!(
+            "Q q 0.45 g BT /{PDF_FONT_NAME} {PDF_FONT_SIZE} Tf {} {} Td ({escaped}) Tj ET Q\n",
+            x0 + PDF_MARGIN,
+            y0 + PDF_MARGIN * 0.66,
+        );
+        let stamp_id = pdf.add_object(Stream::new(Dictionary::new(), content.into_bytes()));
+
+        let mut resources = match inherited(&pdf, page_id, b"Resources") {
+            Some(Object::Dictionary(d)) => d.clone(),
+            _ => Dictionary::new(),
+        };
+        let mut fonts = match resources.get(b"Font").ok().and_then(|v| pdf.dereference(v).ok()) {
+            Some((_, Object::Dictionary(d))) => d.clone(),
+            _ => Dictionary::new(),
+        };
+        fonts.set(PDF_FONT_NAME, Object::Reference(font_id));
+        resources.set("Font", fonts);
+
+        let mut contents = vec![Object::Reference(save_id)];
+        contents.extend(pdf.get_page_contents(page_id).into_iter().map(Object::Reference));
+        contents.push(Object::Reference(stamp_id));
+
+        let page = pdf.get_dictionary_mut(page_id)?;
+        page.set("Resources", resources);
+        page.set("Contents", contents);
+    }
+
+    let mut out = Vec::with_capacity(data.len() + 4096);
+    pdf.save_to(&mut out)?;
+    Ok(out)
+}
+
+fn glyph(c: char) -> [u8; 8] {
+    font8x8::BASIC_FONTS
+        .get(c)
+        .or_else(|| font8x8::LATIN_FONTS.get(c))
+        .or_else(|| font8x8::BASIC_FONTS.get('?'))
+        .unwrap_or([0; 8])
+}
+
+fn blend(px: &mut Rgba<u8>, color: [u8; 3], alpha: f32) {
+    for i in 0..3 {
+        px[i] = (px[i] as f32 * (1.0 - alpha) + color[i] as f32 * alpha).round() as u8;
+    }
+}
+
+fn stamp_image(data: &[u8], format: ImageFormat, text: &str) -> anyhow::Result<Vec<u8>> {
+    let img = image::load_from_memory_with_format(data, format).context("decode image")?;
+    let mut canvas: RgbaImage = img.to_rgba8();
+    let (width, height) = canvas.dimensions();
+
+    let scale = (width / 800).clamp(1, 4);
+    let cell = 8 * scale;
+    let pad = 2 * scale;
+    let per_line = ((width.saturating_sub(2 * pad)) / cell).max(1) as usize;
+    let glyphs: Vec<[u8; 8]> = text.chars().map(glyph).collect();
+    let lines: Vec<&[[u8; 8]]> = glyphs.chunks(per_line).collect();
+    let band = (lines.len() as u32 * (cell + pad) + pad).min(height);
+    let top = height - band;
+
+    for y in top..height {
+        for x in 0..width {
+            blend(canvas.get_pixel_mut(x, y), [0, 0, 0], IMAGE_BAND_ALPHA);
+        }
+    }
+
+    for (row, line) in lines.iter().enumerate() {
+        let line_top = top + pad + row as u32 * (cell + pad);
+        for (col, bitmap) in line.iter().enumerate() {
+            let left = pad + col as u32 * cell;
+            for (gy, bits) in bitmap.iter().enumerate() {
+                for gx in 0..8u32 {
+                    if bits & (1 << gx) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            let (x, y) = (left + gx * scale + dx, line_top + gy as u32 * scale + dy);
+                            if x < width && y < height {
+                                blend(canvas.get_pixel_mut(x, y), [255, 255, 255], 1.0);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let rgb = DynamicImage::ImageRgba8(canvas).to_rgb8();
+            rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+        }
+        _ => canvas.write_to(&mut std::io::Cursor::new(&mut out), format)?,
+    }
+    Ok(out)
+}
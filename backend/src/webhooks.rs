@@ -0,0 +1,676 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Path as AxumPath, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, header, Request, Uri};
+use hyper_util::rt::TokioIo;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName},
+    TlsConnector,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    document_api_dto,
+    events::{self, Event},
+    is_admin, jobs, AppState, AuthedUser, DocumentRow, DownloadRequestDto,
+    DOCUMENT_COLUMNS, DOWNLOAD_REQUEST_SELECT,
+};
+
+const WEBHOOK_TIMEOUT_SECS_DEFAULT: u64 = 10;
+const RESPONSE_BODY_MAX: usize = 2048;
+const TEST_EVENT_TYPE: &str = "webhook.test";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDto {
+    id: Uuid,
+    name: String,
+    url: String,
+    event_types: Vec<String>,
+    enabled: bool,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedWebhookDto {
+    #[serde(flatten)]
+    webhook: WebhookDto,
+    secret: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDeliveryDto {
+    id: Uuid,
+    webhook_id: Uuid,
+    event_type: String,
+    payload: serde_json::Value,
+    status: String,
+    attempts: i32,
+    response_status: Option<i32>,
+    response_body: Option<String>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    name: String,
+    url: String,
+    secret: Option<String>,
+    event_types: Option<Vec<String>>,
+    enabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateWebhookRequest {
+    name: Option<String>,
+    url: Option<String>,
+    secret: Option<String>,
+    event_types: Option<Vec<String>>,
+    enabled: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListDeliveriesQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeliverPayload {
+    pub delivery_id: Uuid,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope<'a> {
+    event: &'a str,
+    occurred_at: DateTime<Utc>,
+    data: serde_json::Value,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DeliveryTarget {
+    event_type: String,
+    payload: serde_json::Value,
+    status: String,
+    attempts: i32,
+    url: String,
+    secret: String,
+    enabled: bool,
+}
+
+const WEBHOOK_SELECT: &str = "select id, name, url, event_types, enabled, created_at, updated_at from webhooks";
+
+const DELIVERY_SELECT: &str = r#"
+    select
+        id, webhook_id, event_type, payload, status, attempts, response_status, response_body, last_error,
+        created_at, updated_at, delivered_at
+    from webhook_deliveries
+"#;
+
+fn generate_secret() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+fn valid_url(url: &str) -> bool {
+    match url.parse::<Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some(),
+        Err(_) => false,
+    }
+}
+
+fn valid_event_types(types: &[String]) -> bool {
+    types.iter().all(|t| events::EVENT_TYPES.contains(&t.as_str()))
+}
+
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn event_data(pool: &PgPool, event: &Event) -> anyhow::Result<Option<serde_json::Value>> {
+    let data = match event {
+        Event::UserRegistered { user_id } | Event::UserApproved { user_id } | Event::UserDisabled { user_id } => {
+            let Some((username, email, role, status)) = sqlx::query_as::<_, (String, Option<String>, String, String)>(
+                "select username, email, role, status from users where id = $1",
+            )
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            else {
+                return Ok(None);
+            };
+            serde_json::json!({ "id": user_id, "username": username, "email": email, "role": role, "status": status })
+        }
+        Event::DocumentCreated { document_id }
+        | Event::DocumentRestored { document_id }
+        | Event::DocumentUpdated { document_id, .. }
+        | Event::DocumentShared { document_id, .. }
+        | Event::DocumentInfected { document_id, .. }
+        | Event::DocumentUnscannable { document_id, .. } => {
+            let Some(doc) = sqlx::query_as::<_, DocumentRow>(&format!(
+                "select {DOCUMENT_COLUMNS} from documents d join users u on u.id = d.owner_id where d.id = $1"
+            ))
+            .bind(document_id)
+            .fetch_optional(pool)
+            .await?
+            else {
+                return Ok(None);
+            };
+            serde_json::to_value(document_api_dto(pool, doc).await?)?
+        }
+        Event::DocumentDeleted {
+            document_id, owner_id, ..
+        } => serde_json::json!({ "id": document_id, "ownerId": owner_id }),
+        Event::DownloadRequestCreated { request_id }
+        | Event::DownloadRequestStageAdvanced { request_id }
+        | Event::DownloadRequestApproved { request_id }
+        | Event::DownloadRequestRejected { request_id }
+        | Event::DownloadRequestCancelled { request_id }
+        | Event::DownloadRequestExpired { request_id }
+        | Event::DownloadRequestExpiringSoon { request_id } => {
+            let Some(r) = sqlx::query_as::<_, DownloadRequestDto>(&format!("{DOWNLOAD_REQUEST_SELECT} where r.id = $1"))
+                .bind(request_id)
+                .fetch_optional(pool)
+                .await?
+            else {
+                return Ok(None);
+            };
+            serde_json::to_value(r)?
+        }
+    };
+    Ok(Some(data))
+}
+
+pub async fn enqueue_deliveries(pool: &PgPool, conn: &mut sqlx::PgConnection, event: &Event) -> anyhow::Result<()> {
+    let event_type = event.event_type();
+    let hooks = sqlx::query_scalar::<_, Uuid>(
+        "select id from webhooks where enabled and (cardinality(event_types) = 0 or $1 = any(event_types))",
+    )
+    .bind(event_type)
+    .fetch_all(pool)
+    .await?;
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let Some(data) = event_data(pool, event).await? else {
+        return Ok(());
+    };
+    let payload = serde_json::to_value(Envelope {
+        event: event_type,
+        occurred_at: Utc::now(),
+        data,
+    })?;
+
+    for webhook_id in hooks {
+        create_delivery(&mut *conn, webhook_id, event_type, &payload).await?;
+    }
+    Ok(())
+}
+
+async fn create_delivery(
+    conn: &mut sqlx::PgConnection,
+    webhook_id: Uuid,
+    event_type: &str,
+    payload: &serde_json::Value,
+) -> sqlx::Result<Uuid> {
+    let id = Uuid::new_v4();
+    sqlx::query("insert into webhook_deliveries (id, webhook_id, event_type, payload) values ($1,$2,$3,$4)")
+        .bind(id)
+        .bind(webhook_id)
+        .bind(event_type)
+        .bind(payload)
+        .execute(&mut *conn)
+        .await?;
+    let job = serde_json::to_value(DeliverPayload { delivery_id: id }).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    jobs::enqueue(&mut *conn, jobs::DELIVER_WEBHOOK, job).await?;
+    Ok(id)
+}
+
+pub async fn deliver(pool: &PgPool, delivery_id: Uuid) -> anyhow::Result<()> {
+    let Some(target) = sqlx::query_as::<_, DeliveryTarget>(
+        r#"
+        select wd.event_type, wd.payload, wd.status, wd.attempts, w.url, w.secret, w.enabled
+        from webhook_deliveries wd
+        join webhooks w on w.id = wd.webhook_id
+        where wd.id = $1
+        "#,
+    )
+    .bind(delivery_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(());
+    };
+
+    if target.status != "pending" {
+        return Ok(());
+    }
+    if !target.enabled {
+        sqlx::query(
+            "update webhook_deliveries set status = 'failed', last_error = 'webhook disabled', updated_at = now() where id = $1",
+        )
+        .bind(delivery_id)
+        .execute(pool)
+        .await?;
+        return Ok(());
+    }
+
+    let body = serde_json::to_vec(&target.payload)?;
+    let timestamp = Utc::now().timestamp();
+    let headers = [
+        ("x-xdocs-event", target.event_type.clone()),
+        ("x-xdocs-delivery", delivery_id.to_string()),
+        ("x-xdocs-timestamp", timestamp.to_string()),
+        ("x-xdocs-signature", sign(&target.secret, timestamp, &body)),
+    ];
+
+    let timeout_secs: u64 = std::env::var("WEBHOOK_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(WEBHOOK_TIMEOUT_SECS_DEFAULT);
+    let result = match tokio::time::timeout(Duration::from_secs(timeout_secs), post(&target.url, &headers, body)).await {
+        Ok(v) => v,
+        Err(_) => Err(anyhow::anyhow!("timed out after {timeout_secs}s")),
+    };
+
+    let attempts = target.attempts + 1;
+    match result {
+        Ok((status, response_body)) if (200..300).contains(&status) => {
+            sqlx::query(
+                r#"
+                update webhook_deliveries
+                set status = 'succeeded', attempts = $2, response_status = $3, response_body = $4, last_error = null,
+                    delivered_at = now(), updated_at = now()
+                where id = $1
+                "#,
+            )
+            .bind(delivery_id)
+            .bind(attempts)
+            .bind(status as i32)
+            .bind(response_body)
+            .execute(pool)
+            .await?;
+            Ok(())
+        }
+        other => {
+            let (status, response_body, err) = match other {
+                Ok((status, body)) => (Some(status as i32), Some(body), format!("receiver responded with {status}")),
+                Err(e) => (None, None, format!("{e:#}")),
+            };
+            let final_status = if attempts >= jobs::JOB_MAX_ATTEMPTS_DEFAULT { "failed" } else { "pending" };
+            sqlx::query(
+                r#"
+                update webhook_deliveries
+                set status = $2, attempts = $3, response_status = $4, response_body = $5, last_error = $6, updated_at = now()
+                where id = $1
+                "#,
+            )
+            .bind(delivery_id)
+            .bind(final_status)
+            .bind(attempts)
+            .bind(status)
+            .bind(response_body)
+            .bind(&err)
+            .execute(pool)
+            .await?;
+            Err(anyhow::anyhow!(err))
+        }
+    }
+}
+
+async fn post(url: &str, headers: &[(&str, String)], body: Vec<u8>) -> anyhow::Result<(u16, String)> {
+    let uri: Uri = url.parse().context("invalid url")?;
+    let host = uri.host().context("missing host")?.to_string();
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => anyhow::bail!("unsupported scheme"),
+    };
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let authority = uri.authority().map(|a| a.as_str().to_string()).unwrap_or_else(|| host.clone());
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    let mut req = Request::post(path)
+        .header(header::HOST, authority)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, "xdocs-webhooks");
+    for (name, value) in headers {
+        req = req.header(*name, value);
+    }
+    let req = req.body(Full::new(Bytes::from(body)))?;
+
+    let tcp = TcpStream::connect((host.as_str(), port)).await.context("connect")?;
+    if https {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(host).context("invalid server name")?;
+        let tls = TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await.context("tls handshake")?;
+        send(TokioIo::new(tls), req).await
+    } else {
+        send(TokioIo::new(tcp), req).await
+    }
+}
+
+async fn send<T>(io: T, req: Request<Full<Bytes>>) -> anyhow::Result<(u16, String)>
+where
+    T: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            warn!(?e, "webhook connection error");
+        }
+    });
+    let res = sender.send_request(req).await?;
+    let status = res.status().as_u16();
+    let body = res.into_body().collect().await?.to_bytes();
+    let body = String::from_utf8_lossy(&body[..body.len().min(RESPONSE_BODY_MAX)]).into_owned();
+    Ok((status, body))
+}
+
+pub async fn list_webhooks(State(state): State<AppState>, Extension(authed): Extension<AuthedUser>) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, WebhookDto>(&format!("{WEBHOOK_SELECT} order by created_at desc"))
+        .fetch_all(&state.pool)
+        .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    Json(body): Json<CreateWebhookRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    if !valid_url(&body.url) {
+        return (StatusCode::BAD_REQUEST, "invalid url").into_response();
+    }
+    let event_types = body.event_types.unwrap_or_default();
+    if !valid_event_types(&event_types) {
+        return (StatusCode::BAD_REQUEST, "invalid event_types").into_response();
+    }
+    let secret = body.secret.filter(|s| !s.is_empty()).unwrap_or_else(generate_secret);
+
+    let created = sqlx::query_as::<_, WebhookDto>(
+        r#"
+        insert into webhooks (id, name, url, secret, event_types, enabled, created_by)
+        values ($1,$2,$3,$4,$5,$6,$7)
+        returning id, name, url, event_types, enabled, created_at, updated_at
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(name)
+    .bind(&body.url)
+    .bind(&secret)
+    .bind(&event_types)
+    .bind(body.enabled.unwrap_or(true))
+    .bind(authed.id)
+    .fetch_one(&state.pool)
+    .await;
+
+    match created {
+        Ok(webhook) => (StatusCode::CREATED, Json(CreatedWebhookDto { webhook, secret })).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn update_webhook(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Json(body): Json<UpdateWebhookRequest>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let name = body.name.as_deref().map(str::trim);
+    if name == Some("") {
+        return (StatusCode::BAD_REQUEST, "missing fields").into_response();
+    }
+    if body.url.as_deref().is_some_and(|u| !valid_url(u)) {
+        return (StatusCode::BAD_REQUEST, "invalid url").into_response();
+    }
+    if body.event_types.as_deref().is_some_and(|t| !valid_event_types(t)) {
+        return (StatusCode::BAD_REQUEST, "invalid event_types").into_response();
+    }
+    let secret = body.secret.filter(|s| !s.is_empty());
+
+    let updated = sqlx::query_as::<_, WebhookDto>(
+        r#"
+        update webhooks
+        set name = coalesce($2, name),
+            url = coalesce($3, url),
+            secret = coalesce($4, secret),
+            event_types = coalesce($5, event_types),
+            enabled = coalesce($6, enabled),
+            updated_at = now()
+        where id = $1
+        returning id, name, url, event_types, enabled, created_at, updated_at
+        "#,
+    )
+    .bind(id)
+    .bind(name)
+    .bind(&body.url)
+    .bind(&secret)
+    .bind(&body.event_types)
+    .bind(body.enabled)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match updated {
+        Ok(Some(v)) => (StatusCode::OK, Json(v)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let res = sqlx::query("delete from webhooks where id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await;
+
+    match res {
+        Ok(r) if r.rows_affected() == 0 => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn list_deliveries(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+    Query(q): Query<ListDeliveriesQuery>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let rows = sqlx::query_as::<_, WebhookDeliveryDto>(&format!(
+        "{DELIVERY_SELECT} where webhook_id = $1 and ($2::text is null or status = $2) order by created_at desc limit $3"
+    ))
+    .bind(id)
+    .bind(q.status)
+    .bind(q.limit.unwrap_or(100).clamp(1, 1000))
+    .fetch_all(&state.pool)
+    .await;
+
+    match rows {
+        Ok(v) => (StatusCode::OK, Json(v)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn redeliver(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let reset = sqlx::query_as::<_, WebhookDeliveryDto>(
+        r#"
+        update webhook_deliveries
+        set status = 'pending', attempts = 0, last_error = null, updated_at = now()
+        where id = $1 and status <> 'pending'
+        returning
+            id, webhook_id, event_type, payload, status, attempts, response_status, response_body, last_error,
+            created_at, updated_at, delivered_at
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await;
+
+    let delivery = match reset {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            let exists = sqlx::query_scalar::<_, bool>("select exists(select 1 from webhook_deliveries where id = $1)")
+                .bind(id)
+                .fetch_one(&mut *tx)
+                .await;
+            return match exists {
+                Ok(true) => (StatusCode::CONFLICT, "delivery pending").into_response(),
+                Ok(false) => (StatusCode::NOT_FOUND, "not found").into_response(),
+                Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+            };
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let job = serde_json::json!(DeliverPayload { delivery_id: id });
+    if jobs::enqueue(&mut *tx, jobs::DELIVER_WEBHOOK, job).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response();
+    }
+
+    match tx.commit().await {
+        Ok(()) => (StatusCode::ACCEPTED, Json(delivery)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+pub async fn test_webhook(
+    State(state): State<AppState>,
+    Extension(authed): Extension<AuthedUser>,
+    AxumPath(id): AxumPath<Uuid>,
+) -> impl IntoResponse {
+    if !is_admin(&authed) {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+
+    let mut tx = match state.pool.begin().await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    let exists = sqlx::query_scalar::<_, bool>("select exists(select 1 from webhooks where id = $1)")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await;
+    match exists {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+
+    let payload = serde_json::json!(Envelope {
+        event: TEST_EVENT_TYPE,
+        occurred_at: Utc::now(),
+        data: serde_json::json!({ "webhookId": id }),
+    });
+    let delivery_id = match create_delivery(&mut tx, id, TEST_EVENT_TYPE, &payload).await {
+        Ok(v) => v,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    };
+
+    match tx.commit().await {
+        Ok(()) => (StatusCode::ACCEPTED, Json(serde_json::json!({ "deliveryId": delivery_id }))).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "db error").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"type":"user.registered"}"#;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000, BODY),
+            "sha256=c9a7e486ce0fec71da4ac6573ff7a653d91a394d2ab292cb205cd323afdf36d1"
+        );
+    }
+
+    #[test]
+    fn signature_covers_every_input() {
+        let signed = sign("whsec_test", 1_700_000_000, BODY);
+        assert_ne!(sign("whsec_other", 1_700_000_000, BODY), signed);
+        assert_ne!(sign("whsec_test", 1_700_000_001, BODY), signed);
+        assert_ne!(sign("whsec_test", 1_700_000_000, br#"{"type":"user.approved"}"#), signed);
+    }
+}